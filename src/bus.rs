@@ -1,4 +1,4 @@
-use crate::{types::UsbSpeed, HostDriver};
+use crate::{errors::UsbHostError, types::UsbSpeed, HostDriver, RESET_RECOVERY, RESUME_RECOVERY};
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Event {
@@ -11,9 +11,32 @@ pub enum Event {
 // not Send anyways
 #[allow(async_fn_in_trait)]
 pub trait Bus {
+    /// Drives reset on the root port, for at least 50ms (TDRSTR)
     async fn reset(&mut self);
     async fn poll(&mut self) -> Event;
+    /// Speed the controller detected for the root device, `None` if no
+    /// device is connected. Split transactions and packet sizes depend on it.
     async fn speed(&mut self) -> Option<UsbSpeed>;
+
+    /// Stops sending SOFs so the devices enter suspend. Controllers that can't
+    /// suspend the bus keep the default.
+    async fn suspend(&mut self) -> Result<(), UsbHostError> {
+        Err(UsbHostError::Unsupported)
+    }
+
+    /// Drives resume signaling on the root port (at least 20ms, TDRSMDN) and
+    /// restarts SOFs
+    async fn resume(&mut self) -> Result<(), UsbHostError> {
+        Err(UsbHostError::Unsupported)
+    }
+
+    /// Current (micro)frame number in the layout of EHCI's FRINDEX: the 11 bit
+    /// frame number of the last SOF in bits 3 to 13 and the microframe in bits
+    /// 0 to 2 (always 0 at full and low speed). Controllers without access to
+    /// their frame counter keep the default.
+    async fn frame_number(&mut self) -> Result<u16, UsbHostError> {
+        Err(UsbHostError::Unsupported)
+    }
 }
 
 pub(crate) struct BusWrap<D: HostDriver>(D::Bus);
@@ -23,14 +46,35 @@ impl<D: HostDriver> BusWrap<D> {
         Self(bus)
     }
 
+    /// Resets the root port and waits for the reset recovery time
+    pub async fn reset(&mut self) {
+        self.0.reset().await;
+        embassy_time::Timer::after(RESET_RECOVERY).await;
+    }
+
+    pub async fn suspend(&mut self) -> Result<(), UsbHostError> {
+        self.0.suspend().await
+    }
+
+    /// Resumes the bus and waits for the resume recovery time
+    pub async fn resume(&mut self) -> Result<(), UsbHostError> {
+        self.0.resume().await?;
+        embassy_time::Timer::after(RESUME_RECOVERY).await;
+        Ok(())
+    }
+
     pub async fn speed(&mut self) -> Option<UsbSpeed> {
         self.0.speed().await
     }
 
+    pub async fn frame_number(&mut self) -> Result<u16, UsbHostError> {
+        self.0.frame_number().await
+    }
+
     pub async fn poll(&mut self) -> Event {
         match self.0.poll().await {
             Event::DeviceAttach => {
-                self.0.reset().await;
+                self.reset().await;
                 //TODO: why this wait????
                 embassy_time::Timer::after_millis(500).await;
 
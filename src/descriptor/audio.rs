@@ -0,0 +1,198 @@
+use crate::types::Bcd16;
+
+/// Audio interface class
+pub const AUDIO_CLASS: u8 = 0x01;
+/// Audio control interface subclass
+pub const AUDIO_SUBCLASS_CONTROL: u8 = 0x01;
+/// CS_INTERFACE class specific descriptor type
+pub const CS_INTERFACE: u8 = 0x24;
+
+const AC_HEADER: u8 = 0x01;
+const AC_INPUT_TERMINAL: u8 = 0x02;
+const AC_OUTPUT_TERMINAL: u8 = 0x03;
+const AC_FEATURE_UNIT: u8 = 0x06;
+
+/// bcdADC of Audio Class 2.0, its descriptors have a different layout
+const ADC_2_0: u16 = 0x0200;
+
+/// Class specific descriptor of an audio control interface (UAC 1.0 and 2.0)
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum AudioControlDescriptor<'d> {
+    Header(AcHeader<'d>),
+    InputTerminal(InputTerminal),
+    OutputTerminal(OutputTerminal),
+    FeatureUnit(FeatureUnit<'d>),
+    /// Units not parsed (mixer, selector, ...), `data` is the whole descriptor
+    Other {
+        subtype: u8,
+        data: &'d [u8],
+    },
+}
+
+/// Class specific AC interface header, the first class specific descriptor
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct AcHeader<'d> {
+    /// bcdADC, audio class release the device implements
+    pub adc_version: Bcd16,
+    /// wTotalLength of the class specific AC descriptors
+    pub total_length: u16,
+    /// baInterfaceNr, the audio streaming interfaces of the function. Empty
+    /// for UAC 2.0, which groups them with an interface association
+    pub streaming_interfaces: &'d [u8],
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct InputTerminal {
+    pub terminal_id: u8,
+    /// wTerminalType, e.g. 0x0101 USB streaming, 0x0201 microphone
+    pub terminal_type: u16,
+    pub assoc_terminal: u8,
+    pub nr_channels: u8,
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct OutputTerminal {
+    pub terminal_id: u8,
+    /// wTerminalType, e.g. 0x0101 USB streaming, 0x0301 speaker
+    pub terminal_type: u16,
+    pub assoc_terminal: u8,
+    /// Unit or terminal connected to the terminal's input
+    pub source_id: u8,
+}
+
+/// Feature unit, the unit with the volume and mute controls
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct FeatureUnit<'d> {
+    pub unit_id: u8,
+    pub source_id: u8,
+    /// Bytes of the control bitmap of each channel
+    control_size: u8,
+    /// bmaControls, master channel first
+    controls: &'d [u8],
+    /// UAC 2.0 uses 2 bits per control (present, writable), UAC 1.0 one bit
+    uac2: bool,
+}
+
+impl FeatureUnit<'_> {
+    /// Logical channels plus the master channel 0
+    pub fn channels(&self) -> usize {
+        match self.control_size {
+            0 => 0,
+            size => self.controls.len() / size as usize,
+        }
+    }
+
+    /// Control bitmap of `channel`, 0 is the master channel
+    pub fn controls(&self, channel: usize) -> Option<u32> {
+        let size = self.control_size as usize;
+        let bytes = self.controls.get(channel * size..(channel + 1) * size)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0u32, |bitmap, byte| (bitmap << 8) | *byte as u32),
+        )
+    }
+
+    /// Whether `channel` has the control with index `control`, 0 is mute and
+    /// 1 is volume
+    fn has_control(&self, channel: usize, control: u32) -> bool {
+        let mask = if self.uac2 {
+            0b11 << (control * 2)
+        } else {
+            1 << control
+        };
+        self.controls(channel)
+            .is_some_and(|bitmap| bitmap & mask != 0)
+    }
+
+    pub fn has_mute(&self, channel: usize) -> bool {
+        self.has_control(channel, 0)
+    }
+
+    pub fn has_volume(&self, channel: usize) -> bool {
+        self.has_control(channel, 1)
+    }
+}
+
+impl<'d> AudioControlDescriptor<'d> {
+    /// Parses a CS_INTERFACE descriptor of an audio control interface, `data`
+    /// is the whole descriptor. `adc_version` is the bcdADC of the header seen
+    /// before, the header itself is parsed with any version.
+    pub fn parse(data: &'d [u8], adc_version: u16) -> Option<Self> {
+        let &[length, _, subtype, ..] = data else {
+            return None;
+        };
+        let data = data.get(..length as usize)?;
+        let uac2 = adc_version >= ADC_2_0;
+        let u16_at = |i: usize| Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]));
+        Some(match subtype {
+            AC_HEADER => {
+                let version = u16_at(3)?;
+                if version >= ADC_2_0 {
+                    // bCategory, wTotalLength, bmControls
+                    AudioControlDescriptor::Header(AcHeader {
+                        adc_version: Bcd16(version),
+                        total_length: u16_at(6)?,
+                        streaming_interfaces: &[],
+                    })
+                } else {
+                    // wTotalLength, bInCollection, baInterfaceNr
+                    let count = *data.get(7)? as usize;
+                    AudioControlDescriptor::Header(AcHeader {
+                        adc_version: Bcd16(version),
+                        total_length: u16_at(5)?,
+                        streaming_interfaces: data.get(8..8 + count)?,
+                    })
+                }
+            }
+            AC_INPUT_TERMINAL => AudioControlDescriptor::InputTerminal(InputTerminal {
+                terminal_id: *data.get(3)?,
+                terminal_type: u16_at(4)?,
+                assoc_terminal: *data.get(6)?,
+                // UAC 2.0 has bCSourceID before bNrChannels
+                nr_channels: *data.get(if uac2 { 8 } else { 7 })?,
+            }),
+            AC_OUTPUT_TERMINAL => AudioControlDescriptor::OutputTerminal(OutputTerminal {
+                terminal_id: *data.get(3)?,
+                terminal_type: u16_at(4)?,
+                assoc_terminal: *data.get(6)?,
+                source_id: *data.get(7)?,
+            }),
+            AC_FEATURE_UNIT => {
+                // UAC 1.0 has bControlSize, UAC 2.0 4 byte bitmaps. iFeature is last.
+                let (control_size, controls_start) = if uac2 { (4, 5) } else { (*data.get(5)?, 6) };
+                AudioControlDescriptor::FeatureUnit(FeatureUnit {
+                    unit_id: *data.get(3)?,
+                    source_id: *data.get(4)?,
+                    control_size,
+                    controls: data.get(controls_start..data.len().checked_sub(1)?)?,
+                    uac2,
+                })
+            }
+            subtype => AudioControlDescriptor::Other { subtype, data },
+        })
+    }
+
+    /// Terminal or unit ID, `None` for the header
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            AudioControlDescriptor::Header(_) => None,
+            AudioControlDescriptor::InputTerminal(terminal) => Some(terminal.terminal_id),
+            AudioControlDescriptor::OutputTerminal(terminal) => Some(terminal.terminal_id),
+            AudioControlDescriptor::FeatureUnit(unit) => Some(unit.unit_id),
+            // All units start with bUnitID
+            AudioControlDescriptor::Other { data, .. } => data.get(3).copied(),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{errors::UsbHostError, types::Bcd16};
+
+use super::ParsingError;
+
+/// Size of the BOS header
+pub const BOS_HEADER_SIZE: usize = 5;
+/// DEVICE CAPABILITY descriptor type
+pub const DEVICE_CAPABILITY: u8 = 0x10;
+
+const CAPABILITY_USB_2_0_EXTENSION: u8 = 0x02;
+const CAPABILITY_SUPERSPEED_USB: u8 = 0x03;
+const CAPABILITY_CONTAINER_ID: u8 = 0x04;
+const CAPABILITY_PLATFORM: u8 = 0x05;
+const CAPABILITY_BILLBOARD: u8 = 0x0D;
+
+/// Offset of the alternate modes in the billboard capability
+const BILLBOARD_ALTERNATE_MODES: usize = 44;
+
+/// Binary Object Store, the header and the device capability descriptors
+/// following it
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct Bos<'d> {
+    /// wTotalLength of the header and all device capabilities
+    pub total_length: u16,
+    /// bNumDeviceCaps
+    pub num_device_caps: u8,
+    capabilities: &'d [u8],
+}
+
+impl<'d> Bos<'d> {
+    /// Parses the BOS starting at `buf[0]`, `buf` has to hold all
+    /// `wTotalLength` bytes. Returns the BOS and its total length.
+    pub(crate) fn parse(buf: &'d [u8]) -> Result<(Self, usize), UsbHostError> {
+        let &[length, _, total_0, total_1, num_device_caps, ..] = buf else {
+            return Err(ParsingError::Incomplete.into());
+        };
+        let total_length = u16::from_le_bytes([total_0, total_1]);
+        if length as usize != BOS_HEADER_SIZE || (total_length as usize) < BOS_HEADER_SIZE {
+            return Err(ParsingError::InvalidLength.into());
+        }
+        let capabilities = buf
+            .get(BOS_HEADER_SIZE..total_length as usize)
+            .ok_or(ParsingError::Incomplete)?;
+        Ok((
+            Bos {
+                total_length,
+                num_device_caps,
+                capabilities,
+            },
+            total_length as usize,
+        ))
+    }
+
+    /// Device capability descriptors of the BOS
+    pub fn capabilities(&self) -> DeviceCapabilityIterator<'d> {
+        DeviceCapabilityIterator {
+            buf: self.capabilities,
+        }
+    }
+
+    /// The USB 2.0 extension capability, `None` if the device has none
+    pub fn usb_2_0_extension(&self) -> Option<Usb2Extension> {
+        self.capabilities().find_map(|capability| match capability {
+            Ok(DeviceCapability::Usb2Extension(extension)) => Some(extension),
+            _ => None,
+        })
+    }
+
+    /// The billboard capability of a Type-C device that failed to enter an
+    /// alternate mode, `None` for other devices
+    pub fn billboard(&self) -> Option<Billboard<'d>> {
+        self.capabilities().find_map(|capability| match capability {
+            Ok(DeviceCapability::Billboard(billboard)) => Some(billboard),
+            _ => None,
+        })
+    }
+}
+
+pub struct DeviceCapabilityIterator<'d> {
+    buf: &'d [u8],
+}
+
+impl<'d> Iterator for DeviceCapabilityIterator<'d> {
+    type Item = Result<DeviceCapability<'d>, UsbHostError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let &[length, descriptor_type, ..] = self.buf else {
+            if self.buf.is_empty() {
+                return None;
+            }
+            self.buf = &[];
+            return Some(Err(ParsingError::Incomplete.into()));
+        };
+        let result = if length < 3 {
+            Err(ParsingError::InvalidLength.into())
+        } else if descriptor_type != DEVICE_CAPABILITY {
+            Err(ParsingError::UnknownType {
+                length,
+                descriptor_type,
+            }
+            .into())
+        } else {
+            match self.buf.get(..length as usize) {
+                Some(data) => {
+                    self.buf = &self.buf[length as usize..];
+                    return Some(Ok(DeviceCapability::parse(data)));
+                }
+                None => Err(ParsingError::Incomplete.into()),
+            }
+        };
+        self.buf = &[];
+        Some(result)
+    }
+}
+
+/// Device capability descriptor of the BOS
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum DeviceCapability<'d> {
+    Usb2Extension(Usb2Extension),
+    SuperSpeed(SuperSpeed),
+    /// UUID identifying the device across all its speeds
+    ContainerId([u8; 16]),
+    /// Vendor or platform specific capability, e.g. WebUSB or Microsoft OS 2.0
+    Platform {
+        uuid: [u8; 16],
+        data: &'d [u8],
+    },
+    Billboard(Billboard<'d>),
+    /// Capabilities not parsed or too short for their type, `data` is the
+    /// whole descriptor
+    Other {
+        capability_type: u8,
+        data: &'d [u8],
+    },
+}
+
+impl<'d> DeviceCapability<'d> {
+    /// `data` is the whole descriptor, at least 3 bytes
+    fn parse(data: &'d [u8]) -> Self {
+        let capability_type = data[2];
+        let uuid = |data: &[u8]| data.get(4..20)?.try_into().ok();
+        let capability = match capability_type {
+            CAPABILITY_USB_2_0_EXTENSION => data.get(3..7).map(|attributes| {
+                DeviceCapability::Usb2Extension(Usb2Extension {
+                    attributes: u32::from_le_bytes([
+                        attributes[0],
+                        attributes[1],
+                        attributes[2],
+                        attributes[3],
+                    ]),
+                })
+            }),
+            CAPABILITY_SUPERSPEED_USB => data.get(3..10).map(|fields| {
+                DeviceCapability::SuperSpeed(SuperSpeed {
+                    attributes: fields[0],
+                    speeds_supported: u16::from_le_bytes([fields[1], fields[2]]),
+                    functionality_support: fields[3],
+                    u1_exit_latency: fields[4],
+                    u2_exit_latency: u16::from_le_bytes([fields[5], fields[6]]),
+                })
+            }),
+            CAPABILITY_CONTAINER_ID => uuid(data).map(DeviceCapability::ContainerId),
+            CAPABILITY_PLATFORM => uuid(data).map(|uuid| DeviceCapability::Platform {
+                uuid,
+                data: &data[20..],
+            }),
+            CAPABILITY_BILLBOARD => match *data {
+                [_, _, _, additional_info_url, num_alternate_modes, preferred_alternate_mode, ..]
+                    if data.len() >= BILLBOARD_ALTERNATE_MODES =>
+                {
+                    Some(DeviceCapability::Billboard(Billboard {
+                        additional_info_url,
+                        num_alternate_modes,
+                        preferred_alternate_mode,
+                        version: Bcd16::from_le_bytes([data[40], data[41]]),
+                        configured: &data[8..40],
+                        alternate_modes: &data[BILLBOARD_ALTERNATE_MODES..],
+                    }))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        capability.unwrap_or(DeviceCapability::Other {
+            capability_type,
+            data,
+        })
+    }
+}
+
+/// USB 2.0 extension capability, present on devices supporting LPM
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct Usb2Extension {
+    /// bmAttributes
+    pub attributes: u32,
+}
+
+impl Usb2Extension {
+    /// Link Power Management supported
+    pub fn lpm(&self) -> bool {
+        self.attributes & (1 << 1) != 0
+    }
+
+    /// Best Effort Service Latency is used for LPM
+    pub fn besl(&self) -> bool {
+        self.attributes & (1 << 2) != 0
+    }
+}
+
+/// SuperSpeed USB capability
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct SuperSpeed {
+    /// bmAttributes, bit 1 is Latency Tolerance Messages support
+    pub attributes: u8,
+    /// wSpeedsSupported, bit 0 low, 1 full, 2 high and 3 SuperSpeed
+    pub speeds_supported: u16,
+    /// bFunctionalitySupport, lowest speed with all functionality
+    pub functionality_support: u8,
+    /// bU1DevExitLat in µs
+    pub u1_exit_latency: u8,
+    /// wU2DevExitLat in µs
+    pub u2_exit_latency: u16,
+}
+
+/// Billboard capability, exposed by Type-C devices that could not enter the
+/// alternate mode(s) they support
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct Billboard<'d> {
+    /// iAddtionalInfoURL, string descriptor index
+    pub additional_info_url: u8,
+    /// bNumberOfAlternateModes
+    pub num_alternate_modes: u8,
+    /// bPreferredAlternateMode, index of the preferred alternate mode
+    pub preferred_alternate_mode: u8,
+    /// bcdVersion of the billboard specification
+    pub version: Bcd16,
+    /// bmConfigured, 2 bits per alternate mode
+    configured: &'d [u8],
+    /// wSVID, bAlternateMode and iAlternateModeString of each alternate mode
+    alternate_modes: &'d [u8],
+}
+
+/// Alternate mode listed by a [`Billboard`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct AlternateMode {
+    /// Standard or vendor ID, e.g. 0xFF01 DisplayPort
+    pub svid: u16,
+    /// Index of the mode within the SVID
+    pub mode: u8,
+    /// String descriptor index of the mode's description
+    pub string_index: u8,
+}
+
+/// bmConfigured state of an alternate mode
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum AlternateModeState {
+    Unspecified,
+    NotAttempted,
+    Unsuccessful,
+    Configured,
+}
+
+impl Billboard<'_> {
+    /// Alternate mode `index`, up to `num_alternate_modes`
+    pub fn alternate_mode(&self, index: u8) -> Option<AlternateMode> {
+        if index >= self.num_alternate_modes {
+            return None;
+        }
+        let start = index as usize * 4;
+        let &[svid_0, svid_1, mode, string_index] = self.alternate_modes.get(start..start + 4)?
+        else {
+            return None;
+        };
+        Some(AlternateMode {
+            svid: u16::from_le_bytes([svid_0, svid_1]),
+            mode,
+            string_index,
+        })
+    }
+
+    /// Whether the device entered alternate mode `index`
+    pub fn alternate_mode_state(&self, index: u8) -> Option<AlternateModeState> {
+        if index >= self.num_alternate_modes {
+            return None;
+        }
+        let byte = self.configured.get(index as usize / 4)?;
+        Some(match (byte >> ((index % 4) * 2)) & 0b11 {
+            0 => AlternateModeState::Unspecified,
+            1 => AlternateModeState::NotAttempted,
+            2 => AlternateModeState::Unsuccessful,
+            _ => AlternateModeState::Configured,
+        })
+    }
+}
@@ -1,4 +1,6 @@
-use crate::types::Bcd16;
+use arrayvec::ArrayVec;
+
+use crate::{descriptor::ParsingError, types::Bcd16};
 
 #[repr(C, packed)]
 pub struct HIDDescriptor {
@@ -12,6 +14,18 @@ pub struct HIDDescriptor {
 
 // HID descriptor type constant
 pub const HID_DESCRIPTOR_TYPE: u8 = 0x21;
+/// Report descriptor type, read with GET_DESCRIPTOR on the interface
+pub const HID_REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+/// Usage page of consumer controls (media keys)
+pub const USAGE_PAGE_CONSUMER: u16 = 0x0C;
+/// Usage page of pointers, joysticks, gamepads and their axes
+pub const USAGE_PAGE_GENERIC_DESKTOP: u16 = 0x01;
+/// Usage page of buttons, usage n is button n
+pub const USAGE_PAGE_BUTTON: u16 = 0x09;
+/// Joystick application collection on the generic desktop page
+pub const USAGE_JOYSTICK: u16 = 0x04;
+/// Gamepad application collection on the generic desktop page
+pub const USAGE_GAMEPAD: u16 = 0x05;
 
 impl HIDDescriptor {
     pub fn parse(data: &[u8]) -> Option<Self> {
@@ -30,6 +44,17 @@ impl HIDDescriptor {
         
         Some(descriptor)
     }
+
+    /// wDescriptorLength of the report descriptor, the first class descriptor
+    /// listed in the raw HID descriptor `data`
+    pub fn report_descriptor_length(data: &[u8]) -> Option<u16> {
+        match data {
+            [_, _, _, _, _, _, HID_REPORT_DESCRIPTOR_TYPE, lo, hi, ..] => {
+                Some(u16::from_le_bytes([*lo, *hi]))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[cfg(feature = "defmt")]
@@ -54,3 +79,532 @@ impl defmt::Format for HIDDescriptor {
         );
     }
 }
+
+#[cfg(not(feature = "defmt"))]
+impl core::fmt::Debug for HIDDescriptor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HIDDescriptor")
+            .field("total_length", &self.total_length)
+            .field("descriptor_type", &self.descriptor_type)
+            .field("bcd_hid", &{ self.bcd_hid })
+            .field("country_code", &self.country_code)
+            .field("flags", &self.flags)
+            .field("num_descriptors", &self.num_descriptors)
+            .finish()
+    }
+}
+
+/// Maximum number of Usage items tracked per main item, further usages are ignored
+const MAX_LOCAL_USAGES: usize = 16;
+/// Maximum depth of the Push/Pop global item stack
+const MAX_GLOBAL_STACK: usize = 4;
+/// Maximum number of distinct report IDs tracked in a report descriptor
+const MAX_REPORT_IDS: usize = 16;
+/// Longest report in bits, a descriptor declaring a longer one is rejected
+const MAX_REPORT_BITS: u32 = 8 * 4096;
+
+/// Main item a [`ReportField`] belongs to
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum ReportKind {
+    Input = 0,
+    Output = 1,
+    Feature = 2,
+}
+
+/// A single data field of a HID report, as described by the report descriptor
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct ReportField {
+    /// Report ID prefixing the report, 0 if the device does not use report IDs
+    pub report_id: u8,
+    pub kind: ReportKind,
+    pub usage_page: u16,
+    /// Usage of a variable field.
+    ///
+    /// For array fields this is the first usage of the range, the field value is
+    /// an index relative to `logical_minimum` into the usages starting there.
+    pub usage: u16,
+    /// Offset from the start of the report in bits, not counting the report ID byte
+    pub bit_offset: u32,
+    pub bit_size: u32,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    /// Raw flags of the main item (Constant, Variable, Relative, ...)
+    pub flags: u32,
+    /// Usage page of the enclosing application collection
+    pub application_usage_page: u16,
+    /// Usage of the enclosing application collection
+    pub application_usage: u16,
+}
+
+impl ReportField {
+    pub fn is_input(&self) -> bool {
+        self.kind == ReportKind::Input
+    }
+
+    pub fn is_output(&self) -> bool {
+        self.kind == ReportKind::Output
+    }
+
+    pub fn is_feature(&self) -> bool {
+        self.kind == ReportKind::Feature
+    }
+
+    /// Variable field (one usage per field) as opposed to an array field
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x2 != 0
+    }
+
+    /// Field value is relative to the previous report
+    pub fn is_relative(&self) -> bool {
+        self.flags & 0x4 != 0
+    }
+
+    /// Usage an array field's `value` selects, an index relative to
+    /// `logical_minimum` into the usages starting at `usage`. `None` if `value`
+    /// is outside the logical range.
+    pub fn array_usage(&self, value: i32) -> Option<u16> {
+        if value < self.logical_minimum || value > self.logical_maximum {
+            return None;
+        }
+        let index = u16::try_from(value.checked_sub(self.logical_minimum)?).ok()?;
+        self.usage.checked_add(index)
+    }
+
+    /// Extracts the raw value of this field from `report`.
+    ///
+    /// `report` must not include the report ID byte.
+    /// Returns `None` if the field lies outside `report` or is wider than 32 bits.
+    pub fn extract(&self, report: &[u8]) -> Option<u32> {
+        if self.bit_size == 0 || self.bit_size > 32 {
+            return None;
+        }
+        let end = self.bit_offset as usize + self.bit_size as usize;
+        if end > report.len() * 8 {
+            return None;
+        }
+
+        let mut value = 0u32;
+        for i in 0..self.bit_size as usize {
+            let bit = self.bit_offset as usize + i;
+            if report[bit / 8] & (1 << (bit % 8)) != 0 {
+                value |= 1 << i;
+            }
+        }
+        Some(value)
+    }
+
+    /// Extracts the value of this field, sign extended if the logical range is signed
+    pub fn extract_signed(&self, report: &[u8]) -> Option<i32> {
+        let value = self.extract(report)?;
+        if self.logical_minimum < 0 && self.bit_size < 32 {
+            let shift = 32 - self.bit_size;
+            Some(((value << shift) as i32) >> shift)
+        } else {
+            Some(value as i32)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct GlobalState {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    /// Logical Maximum reinterpreted as unsigned, see [`GlobalState::logical_maximum`]
+    logical_maximum_unsigned: u32,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+impl GlobalState {
+    /// Many descriptors encode e.g. a maximum of 255 as the single byte 0xFF, which
+    /// is -1 when read as the signed value the spec mandates. Treat the maximum as
+    /// unsigned if the minimum is not negative.
+    fn logical_maximum(&self) -> i32 {
+        if self.logical_minimum >= 0 && self.logical_maximum < 0 {
+            self.logical_maximum_unsigned as i32
+        } else {
+            self.logical_maximum
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct LocalState {
+    /// Usages, extended (page << 16 | id) if the item was 4 bytes long
+    usages: ArrayVec<u32, MAX_LOCAL_USAGES>,
+    usage_minimum: Option<u32>,
+    usage_maximum: Option<u32>,
+}
+
+impl LocalState {
+    /// Usage of the `index`-th field of a variable main item.
+    ///
+    /// Explicit usages come first, then the usage range. If both are exhausted
+    /// the last usage applies to all remaining fields.
+    fn usage(&self, index: u32) -> Option<u32> {
+        if let Some(usage) = self.usages.get(index as usize) {
+            return Some(*usage);
+        }
+        if let (Some(min), Some(max)) = (self.usage_minimum, self.usage_maximum) {
+            let index = index - self.usages.len() as u32;
+            return Some(core::cmp::min(min.saturating_add(index), max));
+        }
+        self.usages.last().copied()
+    }
+
+    /// First usage of an array main item
+    fn array_usage(&self) -> Option<u32> {
+        self.usage_minimum.or(self.usages.first().copied())
+    }
+}
+
+/// Splits a possibly extended usage into (usage page, usage id)
+fn resolve_usage(usage: u32, usage_page: u16) -> (u16, u16) {
+    if usage > 0xFFFF {
+        ((usage >> 16) as u16, usage as u16)
+    } else {
+        (usage_page, usage as u16)
+    }
+}
+
+struct PendingMain {
+    kind: ReportKind,
+    flags: u32,
+    global: GlobalState,
+    local: LocalState,
+    bit_offset: u32,
+    index: u32,
+}
+
+/// Walks a HID report descriptor and yields the data fields it declares.
+///
+/// Each Input/Output/Feature main item yields one [`ReportField`] per report count.
+/// Constant (padding) fields advance the bit offset but are not yielded.
+/// Long items are skipped.
+pub struct ReportDescriptorParser<'a> {
+    data: &'a [u8],
+    offset: usize,
+    global: GlobalState,
+    global_stack: ArrayVec<GlobalState, MAX_GLOBAL_STACK>,
+    local: LocalState,
+    application: (u16, u16),
+    /// Next bit offset for each (report ID, [input, output, feature])
+    report_offsets: ArrayVec<(u8, [u32; 3]), MAX_REPORT_IDS>,
+    pending: Option<PendingMain>,
+}
+
+impl<'a> ReportDescriptorParser<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            global: GlobalState::default(),
+            global_stack: ArrayVec::new(),
+            local: LocalState::default(),
+            application: (0, 0),
+            report_offsets: ArrayVec::new(),
+            pending: None,
+        }
+    }
+
+    fn next_pending_field(&mut self) -> Option<ReportField> {
+        let pending = self.pending.as_mut()?;
+        let variable = pending.flags & 0x2 != 0;
+        let constant = pending.flags & 0x1 != 0;
+
+        while pending.index < pending.global.report_count {
+            let index = pending.index;
+            pending.index += 1;
+            if constant {
+                continue;
+            }
+
+            let usage = if variable {
+                pending.local.usage(index)
+            } else {
+                pending.local.array_usage()
+            };
+            let (usage_page, usage) = resolve_usage(usage.unwrap_or(0), pending.global.usage_page);
+
+            return Some(ReportField {
+                report_id: pending.global.report_id,
+                kind: pending.kind,
+                usage_page,
+                usage,
+                bit_offset: pending.bit_offset + index * pending.global.report_size,
+                bit_size: pending.global.report_size,
+                logical_minimum: pending.global.logical_minimum,
+                logical_maximum: pending.global.logical_maximum(),
+                flags: pending.flags,
+                application_usage_page: self.application.0,
+                application_usage: self.application.1,
+            });
+        }
+
+        self.pending = None;
+        None
+    }
+
+    fn on_main_data(&mut self, kind: ReportKind, flags: u32) -> Result<(), ParsingError> {
+        let report_id = self.global.report_id;
+        let slot = match self
+            .report_offsets
+            .iter()
+            .position(|(id, _)| *id == report_id)
+        {
+            Some(slot) => slot,
+            None => {
+                self.report_offsets
+                    .try_push((report_id, [0; 3]))
+                    .map_err(|_| ParsingError::Unsupported)?;
+                self.report_offsets.len() - 1
+            }
+        };
+        let next_offset = &mut self.report_offsets[slot].1[kind as usize];
+        let bit_offset = *next_offset;
+        *next_offset = self
+            .global
+            .report_size
+            .checked_mul(self.global.report_count)
+            .and_then(|bits| bit_offset.checked_add(bits))
+            .filter(|end| *end <= MAX_REPORT_BITS)
+            .ok_or(ParsingError::InvalidLength)?;
+
+        self.pending = Some(PendingMain {
+            kind,
+            flags,
+            global: self.global,
+            local: core::mem::take(&mut self.local),
+            bit_offset,
+            index: 0,
+        });
+        Ok(())
+    }
+
+    /// Parses one item, updating the parser state
+    fn parse_item(&mut self) -> Result<(), ParsingError> {
+        let buf = &self.data[self.offset..];
+        let prefix = buf[0];
+
+        // Long item: prefix, bDataSize, bLongItemTag, data
+        if prefix == 0xFE {
+            if buf.len() < 3 || buf.len() < 3 + buf[1] as usize {
+                return Err(ParsingError::Incomplete);
+            }
+            self.offset += 3 + buf[1] as usize;
+            return Ok(());
+        }
+
+        let size = match prefix & 0x3 {
+            3 => 4,
+            size => size as usize,
+        };
+        if buf.len() < 1 + size {
+            return Err(ParsingError::Incomplete);
+        }
+        let mut bytes = [0u8; 4];
+        bytes[..size].copy_from_slice(&buf[1..1 + size]);
+        let unsigned = u32::from_le_bytes(bytes);
+        let signed = match size {
+            1 => unsigned as u8 as i8 as i32,
+            2 => unsigned as u16 as i16 as i32,
+            _ => unsigned as i32,
+        };
+        self.offset += 1 + size;
+
+        let item_type = (prefix >> 2) & 0x3;
+        let tag = prefix >> 4;
+        match (item_type, tag) {
+            // Main items
+            (0, 0x8) => self.on_main_data(ReportKind::Input, unsigned)?,
+            (0, 0x9) => self.on_main_data(ReportKind::Output, unsigned)?,
+            (0, 0xB) => self.on_main_data(ReportKind::Feature, unsigned)?,
+            (0, 0xA) => {
+                // Application collection
+                if unsigned == 0x01 {
+                    let usage = self.local.usages.first().copied().unwrap_or(0);
+                    self.application = resolve_usage(usage, self.global.usage_page);
+                }
+                self.local = LocalState::default();
+            }
+            (0, _) => self.local = LocalState::default(),
+
+            // Global items
+            (1, 0x0) => self.global.usage_page = unsigned as u16,
+            (1, 0x1) => self.global.logical_minimum = signed,
+            (1, 0x2) => {
+                self.global.logical_maximum = signed;
+                self.global.logical_maximum_unsigned = unsigned;
+            }
+            (1, 0x7) => self.global.report_size = unsigned,
+            (1, 0x8) => self.global.report_id = unsigned as u8,
+            (1, 0x9) => self.global.report_count = unsigned,
+            (1, 0xA) => self
+                .global_stack
+                .try_push(self.global)
+                .map_err(|_| ParsingError::Unsupported)?,
+            (1, 0xB) => {
+                self.global = self.global_stack.pop().ok_or(ParsingError::InvalidLength)?;
+            }
+
+            // Local items
+            (2, 0x0) => {
+                let usage = if size == 4 {
+                    unsigned
+                } else {
+                    unsigned & 0xFFFF
+                };
+                // Usages beyond capacity are dropped, the last one keeps applying
+                let _ = self.local.usages.try_push(usage);
+            }
+            (2, 0x1) => self.local.usage_minimum = Some(unsigned),
+            (2, 0x2) => self.local.usage_maximum = Some(unsigned),
+
+            // Physical extents, units, designators, strings, delimiters, reserved
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ReportDescriptorParser<'a> {
+    type Item = Result<ReportField, ParsingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(field) = self.next_pending_field() {
+                return Some(Ok(field));
+            }
+            if self.offset >= self.data.len() {
+                return None;
+            }
+            if let Err(e) = self.parse_item() {
+                self.offset = self.data.len();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Boot keyboard report descriptor, HID 1.11 appendix B.1
+    #[rustfmt::skip]
+    const BOOT_KEYBOARD: [u8; 63] = [
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01,
+        // Modifiers
+        0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08,
+        0x81, 0x02,
+        // Reserved byte
+        0x95, 0x01, 0x75, 0x08, 0x81, 0x01,
+        // LEDs and their padding
+        0x95, 0x05, 0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02,
+        0x95, 0x01, 0x75, 0x03, 0x91, 0x01,
+        // Key array
+        0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65,
+        0x81, 0x00,
+        0xC0,
+    ];
+
+    #[test]
+    fn boot_keyboard_fields() {
+        let fields: Vec<ReportField> = ReportDescriptorParser::new(&BOOT_KEYBOARD)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let (inputs, outputs): (Vec<&ReportField>, Vec<_>) =
+            fields.iter().partition(|f| f.is_input());
+        assert_eq!(inputs.len(), 8 + 6);
+        assert_eq!(outputs.len(), 5);
+        assert!(fields
+            .iter()
+            .all(|f| f.application_usage_page == 0x01 && f.application_usage == 0x06));
+
+        for (i, modifier) in inputs[..8].iter().enumerate() {
+            assert!(modifier.is_variable());
+            assert_eq!(modifier.usage_page, 0x07);
+            assert_eq!(modifier.usage, 0xE0 + i as u16);
+            assert_eq!((modifier.bit_offset, modifier.bit_size), (i as u32, 1));
+        }
+        // The reserved byte is skipped, the key array starts at byte 2
+        for (i, key) in inputs[8..].iter().enumerate() {
+            assert!(!key.is_variable());
+            assert_eq!((key.usage_page, key.usage), (0x07, 0));
+            assert_eq!((key.bit_offset, key.bit_size), (16 + 8 * i as u32, 8));
+            assert_eq!((key.logical_minimum, key.logical_maximum), (0, 0x65));
+        }
+        for (i, led) in outputs.iter().enumerate() {
+            assert_eq!((led.usage_page, led.usage), (0x08, 1 + i as u16));
+            assert_eq!((led.bit_offset, led.bit_size), (i as u32, 1));
+        }
+    }
+
+    #[test]
+    fn array_usage_of_extreme_logical_range() {
+        let key = ReportDescriptorParser::new(&BOOT_KEYBOARD)
+            .filter_map(Result::ok)
+            .find(|f| f.is_input() && !f.is_variable())
+            .unwrap();
+        assert_eq!(key.array_usage(0x04), Some(0x04));
+        assert_eq!(key.array_usage(0x66), None);
+        assert_eq!(key.array_usage(-1), None);
+        let full_range = ReportField {
+            logical_minimum: i32::MIN,
+            logical_maximum: i32::MAX,
+            ..key
+        };
+        assert_eq!(full_range.array_usage(i32::MIN + 5), Some(5));
+        assert_eq!(full_range.array_usage(i32::MAX), None);
+    }
+
+    fn parse_error(descriptor: &[u8]) -> Option<ParsingError> {
+        ReportDescriptorParser::new(descriptor).find_map(Result::err)
+    }
+
+    #[test]
+    fn oversized_report_is_rejected() {
+        // Report Size and Report Count of 0xFFFFFFFF overflow the bit offset
+        #[rustfmt::skip]
+        let overflowing = [
+            0x77, 0xFF, 0xFF, 0xFF, 0xFF, 0x97, 0xFF, 0xFF, 0xFF, 0xFF, 0x81, 0x02,
+        ];
+        assert!(matches!(
+            parse_error(&overflowing),
+            Some(ParsingError::InvalidLength)
+        ));
+        // 1 bit short of the limit, then one field past it
+        let mut too_long = Vec::from([0x75, 0x01, 0x97]);
+        too_long.extend_from_slice(&(MAX_REPORT_BITS - 1).to_le_bytes());
+        too_long.extend_from_slice(&[0x81, 0x01, 0x95, 0x02, 0x81, 0x02]);
+        assert!(matches!(
+            parse_error(&too_long),
+            Some(ParsingError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn malformed_items_are_rejected() {
+        // Usage Page missing its data byte
+        assert!(matches!(
+            parse_error(&[0x05, 0x01, 0x05]),
+            Some(ParsingError::Incomplete)
+        ));
+        // Pop without Push
+        assert!(matches!(
+            parse_error(&[0xB4]),
+            Some(ParsingError::InvalidLength)
+        ));
+        // Parsing stops at the first error
+        let mut parser = ReportDescriptorParser::new(&[0xB4, 0x75, 0x01, 0x95, 0x01, 0x81, 0x02]);
+        assert!(matches!(parser.next(), Some(Err(_))));
+        assert!(parser.next().is_none());
+    }
+}
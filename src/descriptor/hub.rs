@@ -1,5 +1,14 @@
+use bitvec::BitArr;
+
 use crate::types::UsbSpeed;
 
+/// Fixed part of the hub descriptor plus the largest `DeviceRemovable` and
+/// `PortPwrCtrlMask` bitmaps (255 ports and the reserved bit 0, 32 bytes each)
+pub const MAX_HUB_DESCRIPTOR_SIZE: usize = core::mem::size_of::<HubDescriptor>() + 32 + 32;
+
+/// `DeviceRemovable` bitmap, bit n set means the device on port n is not removable
+pub type DeviceRemovable = BitArr!(for 256, in u8);
+
 #[cfg_attr(target_endian = "little", repr(C, packed))]
 #[derive(Default, Clone)]
 pub struct HubDescriptor {
@@ -16,6 +25,107 @@ pub struct HubDescriptor {
     pub power_on_to_power_good_time: u8,
 
     pub hub_controller_current: u8,
+    // Followed by the variable length DeviceRemovable and PortPwrCtrlMask bitmaps
+}
+
+impl HubDescriptor {
+    pub fn characteristics(&self) -> HubCharacteristics {
+        HubCharacteristics(self.hub_characteristics)
+    }
+
+    /// Number of bytes of the `DeviceRemovable` bitmap following the fixed part,
+    /// one bit per port plus the reserved bit 0, rounded up to a byte
+    pub fn device_removable_len(&self) -> usize {
+        self.number_of_ports as usize / 8 + 1
+    }
+}
+
+/// Logical power switching mode, `wHubCharacteristics` bits 1..0
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum PowerSwitchingMode {
+    /// All ports are powered at once
+    Ganged,
+    Individual,
+    /// Ports are always powered, only USB 1.0 hubs
+    None,
+}
+
+/// Over-current protection mode, `wHubCharacteristics` bits 4..3
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum OverCurrentProtectionMode {
+    /// Over-current is reported for all ports together in the hub status
+    Global,
+    Individual,
+    None,
+}
+
+/// Decoded `wHubCharacteristics` of the hub descriptor
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub struct HubCharacteristics(pub u16);
+
+impl HubCharacteristics {
+    pub fn power_switching_mode(&self) -> PowerSwitchingMode {
+        match self.0 & 0b11 {
+            0b00 => PowerSwitchingMode::Ganged,
+            0b01 => PowerSwitchingMode::Individual,
+            _ => PowerSwitchingMode::None,
+        }
+    }
+
+    /// The hub is part of a compound device
+    pub fn is_compound_device(&self) -> bool {
+        self.0 & 0x4 != 0
+    }
+
+    pub fn over_current_protection_mode(&self) -> OverCurrentProtectionMode {
+        match (self.0 >> 3) & 0b11 {
+            0b00 => OverCurrentProtectionMode::Global,
+            0b01 => OverCurrentProtectionMode::Individual,
+            _ => OverCurrentProtectionMode::None,
+        }
+    }
+
+    /// TT think time in full-speed bit times (8, 16, 24 or 32), only valid
+    /// for high-speed hubs
+    pub fn tt_think_time(&self) -> u8 {
+        (((self.0 >> 5) & 0b11) as u8 + 1) * 8
+    }
+
+    pub fn port_indicators_supported(&self) -> bool {
+        self.0 & 0x80 != 0
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for HubCharacteristics {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "HubCharacteristics {{ power_switching: {}, compound: {}, over_current: {}, tt_think_time: {}, indicators: {} }}",
+            self.power_switching_mode(),
+            self.is_compound_device(),
+            self.over_current_protection_mode(),
+            self.tt_think_time(),
+            self.port_indicators_supported()
+        )
+    }
+}
+
+#[cfg(not(feature = "defmt"))]
+impl core::fmt::Debug for HubCharacteristics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HubCharacteristics")
+            .field("power_switching", &self.power_switching_mode())
+            .field("compound", &self.is_compound_device())
+            .field("over_current", &self.over_current_protection_mode())
+            .field("tt_think_time", &self.tt_think_time())
+            .field("indicators", &self.port_indicators_supported())
+            .finish()
+    }
 }
 
 #[cfg(feature = "defmt")]
@@ -34,6 +144,23 @@ impl defmt::Format for HubDescriptor {
     }
 }
 
+#[cfg(not(feature = "defmt"))]
+impl core::fmt::Debug for HubDescriptor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HubDescriptor")
+            .field("length", &self.length)
+            .field("type", &self.descriptor_type)
+            .field("ports", &self.number_of_ports)
+            .field("chars", &self.characteristics())
+            .field(
+                "power_time_ms",
+                &(self.power_on_to_power_good_time as u16 * 2),
+            )
+            .field("current_ma", &self.hub_controller_current)
+            .finish()
+    }
+}
+
 #[derive(Default, Clone, Copy)]
 pub struct HubPortStatus(u16);
 
@@ -114,6 +241,24 @@ impl defmt::Format for HubPortStatus {
     }
 }
 
+#[cfg(not(feature = "defmt"))]
+impl core::fmt::Debug for HubPortStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HubPortStatus")
+            .field("connected", &self.connected())
+            .field("enabled", &self.enabled())
+            .field("suspended", &self.suspended())
+            .field("over_current", &self.over_current())
+            .field("reset", &self.reset())
+            .field("power", &self.power())
+            .field("low_speed", &self.low_speed())
+            .field("high_speed", &self.high_speed())
+            .field("test_mode", &self.test_mode())
+            .field("indicator", &self.indicator())
+            .finish()
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct HubPortStatusChange(u16);
 
@@ -154,6 +299,19 @@ impl defmt::Format for HubPortStatusChange {
     }
 }
 
+#[cfg(not(feature = "defmt"))]
+impl core::fmt::Debug for HubPortStatusChange {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HubPortStatusChange")
+            .field("connection", &self.connection())
+            .field("enable", &self.enable())
+            .field("suspend", &self.suspend())
+            .field("over_current", &self.over_current())
+            .field("reset", &self.reset())
+            .finish()
+    }
+}
+
 impl From<u16> for HubPortStatusChange {
     fn from(val: u16) -> Self {
         HubPortStatusChange(val)
@@ -1,5 +1,16 @@
-use crate::{errors::UsbHostError, types::Bcd16};
+use embassy_time::Duration;
 
+use crate::{
+    consts::UsbBaseClass,
+    errors::UsbHostError,
+    types::{Bcd16, EndpointAddress, EndpointDirection, EndpointType, UsbSpeed},
+};
+
+use audio::AudioControlDescriptor;
+use bos::Bos;
+
+pub mod audio;
+pub mod bos;
 pub mod hid;
 pub mod hub;
 
@@ -13,8 +24,13 @@ pub enum DescriptorType {
     String = 3,
     Interface = 4,
     Endpoint = 5,
+    DeviceQualifier = 6,
+    InterfaceAssociation = 11,
+    Bos = 15,
+    DeviceCapability = 16,
 }
 
+/// bDescriptorType, `Err` for types the parser doesn't know
 impl TryFrom<u8> for DescriptorType {
     type Error = ();
 
@@ -25,6 +41,10 @@ impl TryFrom<u8> for DescriptorType {
             3 => Ok(Self::String),
             4 => Ok(Self::Interface),
             5 => Ok(Self::Endpoint),
+            6 => Ok(Self::DeviceQualifier),
+            11 => Ok(Self::InterfaceAssociation),
+            15 => Ok(Self::Bos),
+            16 => Ok(Self::DeviceCapability),
             _ => Err(()),
         }
     }
@@ -38,6 +58,12 @@ pub enum Descriptor<'d> {
     Configuration(&'d ConfigurationDescriptor),
     Endpoint(&'d EndpointDescriptor),
     Interface(&'d InterfaceDescriptor),
+    InterfaceAssociation(&'d InterfaceAssociationDescriptor),
+    DeviceQualifier(&'d DeviceQualifier),
+    /// Binary Object Store, includes its device capabilities
+    Bos(Bos<'d>),
+    /// Class specific descriptor of an audio control interface
+    AudioControl(AudioControlDescriptor<'d>),
     UnknownDescriptor {
         descriptor_type: u8,
         length: u8,
@@ -70,16 +96,198 @@ impl<'a> Descriptor<'a> {
             _ => None,
         }
     }
+    pub fn interface_association(self) -> Option<&'a InterfaceAssociationDescriptor> {
+        match self {
+            Descriptor::InterfaceAssociation(iad) => Some(iad),
+            _ => None,
+        }
+    }
+    pub fn device_qualifier(self) -> Option<&'a DeviceQualifier> {
+        match self {
+            Descriptor::DeviceQualifier(qualifier) => Some(qualifier),
+            _ => None,
+        }
+    }
+    pub fn bos(self) -> Option<Bos<'a>> {
+        match self {
+            Descriptor::Bos(bos) => Some(bos),
+            _ => None,
+        }
+    }
+    pub fn audio_control(self) -> Option<AudioControlDescriptor<'a>> {
+        match self {
+            Descriptor::AudioControl(ac) => Some(ac),
+            _ => None,
+        }
+    }
 }
 
 pub struct DescriptorIterator<'a> {
     buf: &'a [u8],
     offset: usize,
+    /// Inside an audio control interface, its class specific descriptors are
+    /// parsed as [`Descriptor::AudioControl`]
+    audio_control: bool,
+    /// bcdADC of the last audio control header
+    adc_version: u16,
+    /// Descriptor counts checked, see [`DescriptorIterator::validating`]
+    validation: Option<Validation>,
+}
+
+/// Counts declared by the configuration and interface descriptors seen so far
+#[derive(Default)]
+struct Validation {
+    /// wTotalLength of the configuration, `None` before the configuration descriptor
+    total_length: Option<usize>,
+    num_interfaces: u8,
+    /// Interface descriptors with alternate setting 0
+    interfaces: u8,
+    /// bNumEndpoints of the last interface descriptor
+    num_endpoints: u8,
+    /// Endpoint descriptors following the last interface descriptor
+    endpoints: u8,
+    done: bool,
+}
+
+impl Validation {
+    /// Checks the endpoints of the last interface
+    fn end_interface(&self) -> Result<(), ParsingError> {
+        if self.endpoints != self.num_endpoints {
+            return Err(ParsingError::CountMismatch {
+                expected: self.num_endpoints,
+                found: self.endpoints,
+            });
+        }
+        Ok(())
+    }
+
+    /// Updates the counts with the descriptor ending at `offset`
+    fn check(&mut self, descriptor: &Descriptor, offset: usize) -> Result<(), ParsingError> {
+        match descriptor {
+            Descriptor::Configuration(configuration) => {
+                *self = Validation {
+                    total_length: Some(configuration.total_length as usize),
+                    num_interfaces: configuration.num_interfaces,
+                    ..Default::default()
+                };
+            }
+            Descriptor::Interface(interface) => {
+                self.end_interface()?;
+                if interface.b_alternate_setting == 0 {
+                    self.interfaces += 1;
+                }
+                self.num_endpoints = interface.b_num_endpoints;
+                self.endpoints = 0;
+            }
+            Descriptor::Endpoint(_) => {
+                self.endpoints += 1;
+                if self.endpoints > self.num_endpoints {
+                    return Err(ParsingError::CountMismatch {
+                        expected: self.num_endpoints,
+                        found: self.endpoints,
+                    });
+                }
+            }
+            _ => {}
+        }
+        match self.total_length {
+            Some(total_length) if offset > total_length => Err(ParsingError::InvalidLength),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the counts once all descriptors up to `offset` were seen
+    fn finish(&mut self, offset: usize) -> Result<(), ParsingError> {
+        self.done = true;
+        if self
+            .total_length
+            .is_some_and(|total_length| offset < total_length)
+        {
+            return Err(ParsingError::Incomplete);
+        }
+        self.end_interface()?;
+        if self.interfaces != self.num_interfaces {
+            return Err(ParsingError::CountMismatch {
+                expected: self.num_interfaces,
+                found: self.interfaces,
+            });
+        }
+        Ok(())
+    }
 }
 
 impl<'a> DescriptorIterator<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
-        Self { buf, offset: 0 }
+        Self {
+            buf,
+            offset: 0,
+            audio_control: false,
+            adc_version: 0,
+            validation: None,
+        }
+    }
+
+    /// Iterates the configuration starting at `buf[0]` up to its `wTotalLength`,
+    /// bytes past it (e.g. the unused tail of the buffer it was read into) are
+    /// ignored. `buf` not starting with a configuration descriptor is walked
+    /// as with [`DescriptorIterator::new`].
+    pub fn with_config_length(buf: &'a [u8]) -> Self {
+        let buf = match *buf {
+            [_, descriptor_type, total_0, total_1, ..]
+                if descriptor_type == DescriptorType::Configuration as u8 =>
+            {
+                let total_length = u16::from_le_bytes([total_0, total_1]) as usize;
+                &buf[..total_length.min(buf.len())]
+            }
+            _ => buf,
+        };
+        Self::new(buf)
+    }
+
+    /// Cross-checks the descriptors against the configuration descriptor.
+    ///
+    /// Yields [`ParsingError::CountMismatch`] if the number of interfaces or
+    /// the number of endpoints of an interface differ from `bNumInterfaces` and
+    /// `bNumEndpoints`, and [`ParsingError::InvalidLength`] /
+    /// [`ParsingError::Incomplete`] if the descriptors exceed or fall short of
+    /// `wTotalLength`. The iterator stops after the first error.
+    pub fn validating(mut self) -> Self {
+        self.validation = Some(Validation::default());
+        self
+    }
+
+    /// Yields the raw bytes of each descriptor next to the parsed descriptor,
+    /// e.g. to log it or read fields the typed descriptor doesn't cover
+    pub fn with_raw(self) -> WithRaw<'a> {
+        WithRaw { iter: self }
+    }
+
+    /// Class specific descriptors need the interface they belong to
+    fn class_specific(&mut self, descriptor: Descriptor<'a>) -> Descriptor<'a> {
+        match descriptor {
+            Descriptor::Interface(interface) => {
+                self.audio_control = interface.b_interface_class == audio::AUDIO_CLASS
+                    && interface.b_interface_sub_class == audio::AUDIO_SUBCLASS_CONTROL;
+                descriptor
+            }
+            Descriptor::UnknownDescriptor {
+                descriptor_type: audio::CS_INTERFACE,
+                data,
+                ..
+            } if self.audio_control => {
+                match AudioControlDescriptor::parse(data, self.adc_version) {
+                    Some(ac) => {
+                        if let AudioControlDescriptor::Header(header) = ac {
+                            self.adc_version = header.adc_version.0;
+                        }
+                        Descriptor::AudioControl(ac)
+                    }
+                    // Truncated, left to the caller
+                    None => descriptor,
+                }
+            }
+            descriptor => descriptor,
+        }
     }
 }
 
@@ -88,41 +296,167 @@ impl<'a> Iterator for DescriptorIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.offset >= self.buf.len() {
-            return None;
+            let validation = self.validation.as_mut().filter(|v| !v.done)?;
+            return validation.finish(self.offset).err().map(|e| Err(e.into()));
         }
 
         let desc = parse_descriptor(&self.buf[self.offset..]);
 
         match desc {
+            // Never advancing would loop forever, parse_descriptor already
+            // rejects a bLength below the header size
+            Ok((_, 0)) => {
+                self.offset = self.buf.len();
+                if let Some(validation) = self.validation.as_mut() {
+                    validation.done = true;
+                }
+                Some(Err(ParsingError::InvalidLength.into()))
+            }
             Ok((descriptor, length)) => {
                 self.offset += length;
-                Some(Ok(descriptor))
+                if let Some(validation) = self.validation.as_mut() {
+                    if let Err(e) = validation.check(&descriptor, self.offset) {
+                        validation.done = true;
+                        self.offset = self.buf.len();
+                        return Some(Err(e.into()));
+                    }
+                }
+                Some(Ok(self.class_specific(descriptor)))
             }
             Err(e) => {
                 self.offset = self.buf.len();
+                if let Some(validation) = self.validation.as_mut() {
+                    validation.done = true;
+                }
                 Some(Err(e))
             }
         }
     }
 }
 
-#[derive(Clone, Copy)]
+/// Descriptors with the bytes they were parsed from, `bLength` bytes starting
+/// with the descriptor's header (`wTotalLength` for the BOS), see
+/// [`DescriptorIterator::with_raw`]
+pub struct WithRaw<'a> {
+    iter: DescriptorIterator<'a>,
+}
+
+impl<'a> Iterator for WithRaw<'a> {
+    type Item = Result<(Descriptor<'a>, &'a [u8]), UsbHostError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.iter.offset;
+        let desc = match self.iter.next()? {
+            Ok(desc) => desc,
+            Err(e) => return Some(Err(e)),
+        };
+        let buf = self.iter.buf;
+        let raw = buf.get(start..self.iter.offset).unwrap_or(&buf[start..]);
+        Some(Ok((desc, raw)))
+    }
+}
+
+/// Interface number and alternate setting identifying one interface descriptor
+#[derive(Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct InterfaceId {
+    pub number: u8,
+    pub alternate_setting: u8,
+}
+
+/// Tags every descriptor with the interface it belongs to, i.e. the last
+/// interface descriptor before it. Descriptors before the first interface and
+/// interface association descriptors are tagged with `None`.
+pub struct WithInterface<I> {
+    iter: I,
+    current: Option<InterfaceId>,
+}
+
+impl<I> WithInterface<I> {
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter,
+            current: None,
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = Result<Descriptor<'a>, UsbHostError>>> Iterator for WithInterface<I> {
+    type Item = Result<(Option<InterfaceId>, Descriptor<'a>), UsbHostError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let desc = match self.iter.next()? {
+            Ok(desc) => desc,
+            Err(e) => return Some(Err(e)),
+        };
+        match desc {
+            Descriptor::Interface(interface) => self.current = Some(interface.id()),
+            Descriptor::Configuration(_) | Descriptor::InterfaceAssociation(_) => {
+                self.current = None
+            }
+            _ => {}
+        }
+        Some(Ok((self.current, desc)))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ParsingError {
     IncompleteDeviceDescriptor { max_packet_size: u8 },
     Incomplete,
     InvalidLength,
     UnknownType { length: u8, descriptor_type: u8 },
+    /// Number of interface or endpoint descriptors differs from the count
+    /// declared by the configuration or interface descriptor
+    CountMismatch { expected: u8, found: u8 },
+    /// Input is valid but exceeds the limits of the parser
+    Unsupported,
+}
+
+impl From<ParsingError> for UsbHostError {
+    fn from(e: ParsingError) -> Self {
+        UsbHostError::ParsingError(e)
+    }
 }
 
-impl Into<UsbHostError> for ParsingError {
-    fn into(self) -> UsbHostError {
-        UsbHostError::ParsingError(self)
+impl core::fmt::Display for ParsingError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParsingError::IncompleteDeviceDescriptor { max_packet_size } => write!(
+                f,
+                "incomplete device descriptor (max packet size {})",
+                max_packet_size
+            ),
+            ParsingError::Incomplete => f.write_str("descriptor is truncated"),
+            ParsingError::InvalidLength => f.write_str("descriptor has an invalid length"),
+            ParsingError::UnknownType {
+                length,
+                descriptor_type,
+            } => write!(
+                f,
+                "unknown descriptor type {:#04x} (length {})",
+                descriptor_type, length
+            ),
+            ParsingError::CountMismatch { expected, found } => write!(
+                f,
+                "expected {} interface or endpoint descriptors, found {}",
+                expected, found
+            ),
+            ParsingError::Unsupported => f.write_str("descriptor exceeds parser limits"),
+        }
     }
 }
 
-fn parse_descriptor<'a>(buf: &'a [u8]) -> Result<(Descriptor<'a>, usize), UsbHostError> {
+impl core::error::Error for ParsingError {}
+
+/// Parses the descriptor starting at `buf[0]`, e.g. one read with a vendor
+/// request. Returns the descriptor and its length, the offset of the next
+/// descriptor in `buf`. Types without a parser are returned as
+/// [`Descriptor::UnknownDescriptor`], see [`DescriptorIterator`] to walk a
+/// whole configuration.
+pub fn parse_descriptor<'a>(buf: &'a [u8]) -> Result<(Descriptor<'a>, usize), UsbHostError> {
     #[cfg(not(target_endian = "little"))]
     compile_error!("This function only works for little endian architechture");
 
@@ -132,18 +466,26 @@ fn parse_descriptor<'a>(buf: &'a [u8]) -> Result<(Descriptor<'a>, usize), UsbHos
     // SAFETY: [`DescriptorHeader`] is packed, does not require alignment,
     // size is checked above
     let header: &'a DescriptorHeader = unsafe { core::mem::transmute(buf.as_ptr()) };
+    // A descriptor shorter than its header would never advance the iterator
+    if (header.length as usize) < core::mem::size_of::<DescriptorHeader>() {
+        return Err(ParsingError::InvalidLength.into());
+    }
+    let unknown = || {
+        let data = buf
+            .get(..header.length as usize)
+            .ok_or(ParsingError::Incomplete)?;
+        Ok((
+            Descriptor::UnknownDescriptor {
+                descriptor_type: header.descriptor_type,
+                length: header.length,
+                data,
+            },
+            header.length as usize,
+        ))
+    };
     let desc_type = match DescriptorType::try_from(header.descriptor_type) {
         Ok(desc_type) => desc_type,
-        Err(_) => {
-            return Ok((
-                Descriptor::UnknownDescriptor {
-                    descriptor_type: header.descriptor_type,
-                    length: header.length,
-                    data: &buf[..header.length as usize],
-                },
-                header.length as usize,
-            ));
-        }
+        Err(_) => return unknown(),
     };
     match desc_type {
         DescriptorType::Device => {
@@ -182,7 +524,15 @@ fn parse_descriptor<'a>(buf: &'a [u8]) -> Result<(Descriptor<'a>, usize), UsbHos
                 ))
             }
         }
-        DescriptorType::String => panic!(),
+        // String descriptors are not expected in a configuration, the payload is
+        // passed on as is
+        DescriptorType::String => unknown(),
+        DescriptorType::Bos => {
+            let (bos, length) = Bos::parse(buf)?;
+            Ok((Descriptor::Bos(bos), length))
+        }
+        // Only valid within a BOS, which parses its device capabilities
+        DescriptorType::DeviceCapability => unknown(),
         DescriptorType::Interface => {
             if buf.len() < core::mem::size_of::<InterfaceDescriptor>() {
                 Err(ParsingError::Incomplete.into())
@@ -203,6 +553,32 @@ fn parse_descriptor<'a>(buf: &'a [u8]) -> Result<(Descriptor<'a>, usize), UsbHos
                 ))
             }
         }
+        DescriptorType::InterfaceAssociation => {
+            if buf.len() < core::mem::size_of::<InterfaceAssociationDescriptor>() {
+                Err(ParsingError::Incomplete.into())
+            } else {
+                // SAFETY: packed, size is checked above
+                let iad = unsafe { &*(buf.as_ptr() as *const InterfaceAssociationDescriptor) };
+                Ok((
+                    Descriptor::InterfaceAssociation(iad),
+                    header.length as usize,
+                ))
+            }
+        }
+        DescriptorType::DeviceQualifier => {
+            if header.length as usize != core::mem::size_of::<DeviceQualifier>() {
+                Err(ParsingError::InvalidLength.into())
+            } else if buf.len() < core::mem::size_of::<DeviceQualifier>() {
+                Err(ParsingError::Incomplete.into())
+            } else {
+                // SAFETY: packed, size is checked above
+                let qualifier = unsafe { &*(buf.as_ptr() as *const DeviceQualifier) };
+                Ok((
+                    Descriptor::DeviceQualifier(qualifier),
+                    header.length as usize,
+                ))
+            }
+        }
     }
 }
 
@@ -318,6 +694,67 @@ impl defmt::Format for DeviceDescriptor {
     }
 }
 
+impl DeviceDescriptor {
+    /// bDeviceClass, `None` for codes not assigned by the USB-IF
+    pub fn base_class(&self) -> Option<UsbBaseClass> {
+        UsbBaseClass::try_from(self.device_class).ok()
+    }
+}
+
+/// Device qualifier, the fields of the device descriptor that would change if the
+/// device operated at the other speed (full speed for a high-speed device and
+/// vice versa). Only high-speed capable devices have one, others STALL the request.
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+#[cfg_attr(target_endian = "little", repr(C, packed))]
+#[derive(Clone)]
+pub struct DeviceQualifier {
+    pub length: u8,
+    pub descriptor_type: DescriptorType,
+    /// USB Specification Release Number in Binary-Coded Decimal, at least 2.00
+    pub usb_release: Bcd16,
+
+    /// Class code at the other speed
+    pub device_class: u8,
+
+    /// Subclass code at the other speed
+    pub device_sub_class: u8,
+
+    /// Protocol code at the other speed
+    pub device_protocol: u8,
+
+    /// Maximum packet size for endpoint zero at the other speed
+    pub max_packet_size: u8,
+
+    /// Number of configurations at the other speed
+    pub num_configurations: u8,
+
+    /// Reserved, must be zero
+    pub reserved: u8,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DeviceQualifier {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "DeviceQualifier {{
+\tusb_release: {},
+\tdevice_class: {=u8:#x},
+\tdevice_sub_class: {=u8:#x},
+\tdevice_protocol: {=u8:#x},
+\tmax_packet_size: {},
+\tnum_configurations: {}
+}}",
+            { self.usb_release },
+            self.device_class,
+            self.device_sub_class,
+            self.device_protocol,
+            self.max_packet_size,
+            self.num_configurations,
+        )
+    }
+}
+
 #[derive(Clone, Copy)]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
 #[repr(transparent)]
@@ -484,6 +921,59 @@ pub struct EndpointDescriptor {
     // pub bRefreshRate: u8,
 }
 
+impl EndpointDescriptor {
+    pub fn transfer_type(&self) -> EndpointType {
+        match self.bm_attributes & 0x03 {
+            0b00 => EndpointType::Control,
+            0b01 => EndpointType::Isochronous,
+            0b10 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        }
+    }
+
+    pub fn direction(&self) -> EndpointDirection {
+        if self.b_endpoint_address & 0x80 == 0 {
+            EndpointDirection::Out
+        } else {
+            EndpointDirection::In
+        }
+    }
+
+    pub fn address(&self) -> EndpointAddress {
+        EndpointAddress {
+            number: self.b_endpoint_address & 0x0F,
+            direction: self.direction(),
+        }
+    }
+
+    /// Packet size, bits 10..0 of wMaxPacketSize
+    pub fn max_packet_size(&self) -> u16 {
+        self.w_max_packet_size & 0x7FF
+    }
+
+    /// Transactions per microframe for high speed periodic endpoints, 1 otherwise
+    pub fn packets_per_microframe(&self) -> u8 {
+        ((self.w_max_packet_size >> 11) & 0x3) as u8 + 1
+    }
+
+    /// Service interval of a periodic endpoint on a device running at `speed`.
+    ///
+    /// bInterval is in frames for full and low speed interrupt endpoints, and
+    /// an exponent of 2^(bInterval-1) (micro)frames for isochronous and high
+    /// speed interrupt endpoints.
+    pub fn interval(&self, speed: UsbSpeed) -> Duration {
+        let exponential = |unit: u64| {
+            let exponent = self.b_interval.clamp(1, 16) - 1;
+            Duration::from_micros(unit << exponent)
+        };
+        match (self.transfer_type(), speed) {
+            (_, UsbSpeed::HighSpeed) => exponential(125),
+            (EndpointType::Isochronous, _) => exponential(1000),
+            _ => Duration::from_millis(self.b_interval.max(1) as u64),
+        }
+    }
+}
+
 /// NOT READ BY A HUMAN. 99% generated
 #[cfg(feature = "defmt")]
 impl defmt::Format for EndpointDescriptor {
@@ -579,3 +1069,260 @@ pub struct InterfaceDescriptor {
     /// iInterface - Index of string descriptor describing this interface. Zero if there is no string descriptor for this interface.
     pub i_interface: u8,
 }
+
+impl InterfaceDescriptor {
+    pub fn id(&self) -> InterfaceId {
+        InterfaceId {
+            number: self.b_interface_number,
+            alternate_setting: self.b_alternate_setting,
+        }
+    }
+
+    /// bInterfaceClass, `None` for codes not assigned by the USB-IF
+    pub fn base_class(&self) -> Option<UsbBaseClass> {
+        UsbBaseClass::try_from(self.b_interface_class).ok()
+    }
+}
+
+/// Groups consecutive interfaces that belong to one function, e.g. the control and
+/// data interfaces of a CDC function. It precedes the first interface of the group.
+#[repr(C, packed)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+#[derive(Copy, Clone)]
+pub struct InterfaceAssociationDescriptor {
+    /// bLength - Size of this descriptor in bytes (8).
+    pub length: u8,
+
+    /// bDescriptorType - INTERFACE ASSOCIATION (0x0B).
+    pub descriptor_type: u8,
+
+    /// bFirstInterface - Interface number of the first interface associated with this function.
+    pub first_interface: u8,
+
+    /// bInterfaceCount - Number of contiguous interfaces associated with this function.
+    pub interface_count: u8,
+
+    /// bFunctionClass - Class code (assigned by the USB-IF).
+    pub function_class: u8,
+
+    /// bFunctionSubClass - Subclass code (assigned by the USB-IF).
+    pub function_sub_class: u8,
+
+    /// bFunctionProtocol - Protocol code (assigned by the USB-IF).
+    pub function_protocol: u8,
+
+    /// iFunction - Index of string descriptor describing this function.
+    pub function: u8,
+}
+
+impl InterfaceAssociationDescriptor {
+    /// Whether `interface_number` belongs to this function
+    pub fn contains(&self, interface_number: u8) -> bool {
+        let first = self.first_interface;
+        interface_number >= first && interface_number - first < self.interface_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device_addr::DeviceAddressManager,
+        pipe::USBHostPipe,
+        test_util::{MockDriver, MockPipe, Transaction},
+        types::{DevInfo, UsbSpeed},
+    };
+    use embassy_futures::block_on;
+
+    /// One interface with a bulk IN and a bulk OUT endpoint, 32 bytes
+    #[rustfmt::skip]
+    const BULK_CONFIGURATION: [u8; 32] = [
+        9, 2, 32, 0, 1, 1, 0, 0x80, 50,
+        9, 4, 0, 0, 2, 0xFF, 0, 0, 0,
+        7, 5, 0x81, 2, 64, 0, 0,
+        7, 5, 0x02, 2, 64, 0, 0,
+    ];
+
+    #[test]
+    fn configuration_of_exact_packet_multiple_ends_at_length() {
+        let mock = MockPipe::default();
+        let pipe = USBHostPipe::<MockDriver, 4>::new(mock.clone());
+        let device = DeviceAddressManager::<4>::new()
+            .alloc_device_address(8, DevInfo::root_device(UsbSpeed::LowSpeed))
+            .unwrap();
+        // No short packet or zero length packet ends the data stage
+        for packet in BULK_CONFIGURATION.chunks(8) {
+            mock.push_in(Ok(packet));
+        }
+
+        let mut buf = [0; BULK_CONFIGURATION.len()];
+        let len = block_on(
+            pipe.control(device)
+                .get_descriptor(DescriptorType::Configuration as u8, 0)
+                .read(&mut buf),
+        )
+        .unwrap();
+        assert_eq!(len, BULK_CONFIGURATION.len());
+        assert_eq!(buf, BULK_CONFIGURATION);
+
+        let log = mock.take_log();
+        let data_stage = log
+            .iter()
+            .filter(|transaction| matches!(transaction, Transaction::In { .. }))
+            .count();
+        assert_eq!(data_stage, 4);
+        assert!(matches!(log.last(), Some(Transaction::Out { data, .. }) if data.is_empty()));
+
+        let descriptors = DescriptorIterator::new(&buf).validating();
+        assert_eq!(descriptors.map(Result::unwrap).count(), 4);
+    }
+
+    /// CDC ACM function of a composite device: IAD, communication interface
+    /// with its functional descriptors and notification endpoint, data interface
+    #[rustfmt::skip]
+    const CDC_CONFIGURATION: [u8; 75] = [
+        9, 2, 75, 0, 2, 1, 0, 0x80, 50,
+        8, 11, 0, 2, 2, 2, 1, 0,
+        9, 4, 0, 0, 1, 2, 2, 1, 0,
+        5, 0x24, 0x00, 0x10, 0x01,
+        5, 0x24, 0x01, 0x00, 0x01,
+        4, 0x24, 0x02, 0x02,
+        5, 0x24, 0x06, 0x00, 0x01,
+        7, 5, 0x83, 3, 8, 0, 16,
+        9, 4, 1, 0, 2, 10, 0, 0, 0,
+        7, 5, 0x02, 2, 64, 0, 0,
+        7, 5, 0x81, 2, 64, 0, 0,
+    ];
+
+    #[test]
+    fn cdc_configuration_with_iad() {
+        let descriptors: Vec<_> = DescriptorIterator::new(&CDC_CONFIGURATION)
+            .validating()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(descriptors.len(), 11);
+
+        let iad = descriptors[1].interface_association().unwrap();
+        assert_eq!((iad.first_interface, iad.interface_count), (0, 2));
+        assert_eq!(
+            (
+                iad.function_class,
+                iad.function_sub_class,
+                iad.function_protocol
+            ),
+            (2, 2, 1)
+        );
+        let interfaces: Vec<_> = descriptors
+            .iter()
+            .filter_map(|desc| desc.interface())
+            .map(|interface| interface.b_interface_number)
+            .collect();
+        assert_eq!(interfaces, [0, 1]);
+        assert!(interfaces.iter().all(|&number| iad.contains(number)));
+        assert!(!iad.contains(2));
+
+        // The functional descriptors are passed on as class specific descriptors
+        let functional = descriptors
+            .iter()
+            .filter(|desc| {
+                matches!(
+                    desc,
+                    Descriptor::UnknownDescriptor {
+                        descriptor_type: 0x24,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(functional, 4);
+    }
+
+    /// Keyboard with a second HID interface, each with its HID descriptor and
+    /// an interrupt IN endpoint
+    #[rustfmt::skip]
+    const TWO_INTERFACE_CONFIGURATION: [u8; 59] = [
+        9, 2, 59, 0, 2, 1, 0, 0xA0, 50,
+        9, 4, 0, 0, 1, 3, 1, 1, 0,
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 63, 0,
+        7, 5, 0x81, 3, 8, 0, 10,
+        9, 4, 1, 0, 1, 3, 0, 0, 0,
+        9, 0x21, 0x11, 0x01, 0, 1, 0x22, 25, 0,
+        7, 5, 0x82, 3, 4, 0, 10,
+    ];
+
+    #[test]
+    fn endpoints_are_tagged_with_their_interface() {
+        let iter = DescriptorIterator::new(&TWO_INTERFACE_CONFIGURATION).validating();
+        let endpoints: Vec<_> = WithInterface::new(iter)
+            .map(Result::unwrap)
+            .filter_map(|(interface, desc)| {
+                let endpoint = desc.endpoint()?;
+                Some((interface.unwrap(), endpoint.b_endpoint_address))
+            })
+            .collect();
+        let id = |number| InterfaceId {
+            number,
+            alternate_setting: 0,
+        };
+        assert!(endpoints == [(id(0), 0x81), (id(1), 0x82)]);
+
+        // Descriptors before the first interface belong to none
+        let mut iter = WithInterface::new(DescriptorIterator::new(&TWO_INTERFACE_CONFIGURATION));
+        assert!(matches!(
+            iter.next(),
+            Some(Ok((None, Descriptor::Configuration(_))))
+        ));
+    }
+
+    /// Walks `buf` the ways the drivers do, every descriptor advances at least
+    /// two bytes and an error ends the iteration
+    fn assert_terminates(buf: &[u8]) {
+        let bound = buf.len() / 2 + 2;
+        assert!(DescriptorIterator::new(buf).take(bound + 1).count() <= bound);
+        let validating = DescriptorIterator::with_config_length(buf).validating();
+        assert!(validating.take(bound + 1).count() <= bound);
+        let raw = DescriptorIterator::new(buf).with_raw();
+        assert!(raw.take(bound + 1).count() <= bound);
+    }
+
+    #[test]
+    fn iteration_terminates_on_malformed_buffers() {
+        for len in 0..64 {
+            assert_terminates(&[0; 64][..len]);
+        }
+        // bLength below the header, past the buffer, and a truncated descriptor
+        assert_terminates(&[1, 2, 3, 4]);
+        assert_terminates(&[255, 2, 255, 255, 1, 1]);
+        assert_terminates(&[9, 2, 18, 0, 1, 1, 0, 0x80, 50, 9, 4]);
+
+        // Every byte of the fixtures replaced by values likely to confuse the parser
+        for fixture in [
+            &BULK_CONFIGURATION[..],
+            &CDC_CONFIGURATION,
+            &TWO_INTERFACE_CONFIGURATION,
+        ] {
+            let mut buf = fixture.to_vec();
+            for i in 0..buf.len() {
+                for value in [0, 1, 2, 7, 0x0F, 0x24, 0x80, 0xFF] {
+                    let original = core::mem::replace(&mut buf[i], value);
+                    assert_terminates(&buf);
+                    buf[i] = original;
+                }
+            }
+        }
+
+        // And random bytes, from a fixed seed
+        let mut state = 0x2545_F491_u32;
+        let mut buf = [0u8; 96];
+        for _ in 0..2000 {
+            for byte in buf.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *byte = state as u8;
+            }
+            assert_terminates(&buf);
+        }
+    }
+}
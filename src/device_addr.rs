@@ -1,8 +1,15 @@
+use arrayvec::ArrayVec;
 use bitvec::{array::BitArray, BitArr};
 
-use crate::types::{DevInfo, PortInfo};
+use crate::{
+    errors::UsbHostError,
+    types::{DevInfo, PortInfo, UsbSpeed},
+};
 
-#[derive(Clone, Copy)]
+/// Handles compare equal for the same address on the same port. Addresses
+/// are reused, a device plugged into the port of a detached device may get an
+/// equal handle, drop handles on [`HostEvent::DeviceDetach`](crate::HostEvent::DeviceDetach).
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
 pub struct DeviceHandle {
@@ -21,12 +28,70 @@ impl DeviceHandle {
         self.max_packet_size
     }
 
+    pub fn speed(&self) -> UsbSpeed {
+        self.parent.speed()
+    }
+
+    /// Port on the parent hub, 0 for the device on the root port
+    pub fn port(&self) -> u8 {
+        self.parent.port().port()
+    }
+
+    /// Address of the hub the device is attached to, `Some(0)` for the root port
+    pub fn parent_address(&self) -> Option<u8> {
+        self.parent.port().parent_addr()
+    }
+
+    /// Whether the device is one of the devices detached in `mask`
+    pub fn matches_mask(&self, mask: &DeviceDisconnectMask) -> bool {
+        mask.contains(self.address)
+    }
+
     pub(crate) fn dev_info(&self) -> DevInfo {
         self.parent
     }
+
+    /// Hub ports from the root port down to the device, empty for the device
+    /// on the root port. Walks the parents known to `devices`, a path deeper
+    /// than `N` is truncated at the device end.
+    pub(crate) fn path<const N: usize, const NR_DEVICES: usize>(
+        &self,
+        devices: &DeviceAddressManager<NR_DEVICES>,
+    ) -> ArrayVec<u8, N> {
+        let mut path = ArrayVec::new();
+        let mut port = self.parent.port();
+        while let Some(parent_addr) = port.parent_addr() {
+            if parent_addr == 0 || path.try_push(port.port()).is_err() {
+                break;
+            }
+            port = devices.port_info(parent_addr as usize - 1);
+        }
+        path.reverse();
+        path
+    }
 }
 
+/// Longest [`DeviceHandle`] path, the USB topology allows 5 hubs between the
+/// root port and a device
+pub const MAX_PATH_LEN: usize = 5;
+
+/// Device with an assigned address, see
+/// [`USBHostPipe::devices`](crate::pipe::USBHostPipe::devices)
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct AttachedDevice {
+    pub address: u8,
+    /// Address of the hub the device is attached to, `Some(0)` for the root port
+    pub parent_address: Option<u8>,
+    /// Port on the parent hub, 0 for the root port
+    pub port: u8,
+    pub speed: UsbSpeed,
+}
+
+/// Addresses of the devices removed by a detach, the detached device and
+/// everything behind it if it was a hub
+#[derive(Clone)]
 pub struct DeviceDisconnectMask {
     mask: BitArr!(for 128, in u8),
 }
@@ -50,6 +115,20 @@ impl defmt::Format for DeviceDisconnectMask {
     }
 }
 
+/// The addresses in the mask, e.g. `[2, 3]`
+#[cfg(not(feature = "defmt"))]
+impl core::fmt::Debug for DeviceDisconnectMask {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.mask.iter_ones()).finish()
+    }
+}
+
+impl Default for DeviceDisconnectMask {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DeviceDisconnectMask {
     pub fn new() -> Self {
         Self {
@@ -60,59 +139,127 @@ impl DeviceDisconnectMask {
         self.mask.iter_ones()
     }
 
+    /// Whether the device with address `addr` was detached
+    pub fn contains(&self, addr: u8) -> bool {
+        self.mask.get(addr as usize).is_some_and(|bit| *bit)
+    }
+
+    /// Number of detached devices
+    pub fn len(&self) -> usize {
+        self.mask.count_ones()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mask.not_any()
+    }
+
     pub(crate) fn remove(&mut self, addr: usize) {
         self.mask.set(addr, false);
     }
 }
 
+/// Current in mA a root port or a self-powered hub port supplies (5 unit loads)
+pub(crate) const HIGH_POWER_PORT_MA: u16 = 500;
+/// Current in mA a bus-powered hub port supplies (1 unit load)
+pub(crate) const LOW_POWER_PORT_MA: u16 = 100;
+
 pub(crate) struct DeviceAddressManager<const NR_DEVICES: usize> {
     // 1-based indexing
-    info: [PortInfo; NR_DEVICES],
+    devices: [Option<DeviceHandle>; NR_DEVICES],
+    /// Current in mA each downstream port of a hub supplies, 0 for other devices
+    port_current: [u16; NR_DEVICES],
 }
 
 impl<const NR_DEVICES: usize> DeviceAddressManager<NR_DEVICES> {
     // Construct an allocator with all addresses except 0 occupied.
     pub fn new() -> Self {
         Self {
-            info: [PortInfo::invalid(); NR_DEVICES],
+            devices: [None; NR_DEVICES],
+            port_current: [0; NR_DEVICES],
+        }
+    }
+
+    /// Records the current each downstream port of the hub at `address` supplies
+    pub fn set_port_current(&mut self, address: u8, current_ma: u16) {
+        if let Some(current) = self.port_current.get_mut(address as usize - 1) {
+            *current = current_ma;
+        }
+    }
+
+    /// Current in mA supplied to the device on `port`
+    pub fn available_current(&self, port: PortInfo) -> u16 {
+        match port.parent_addr() {
+            None | Some(0) => HIGH_POWER_PORT_MA,
+            Some(hub) => self
+                .port_current
+                .get(hub as usize - 1)
+                .copied()
+                .unwrap_or(LOW_POWER_PORT_MA),
         }
     }
 
-    pub fn alloc_device_address(&mut self, max_packet_size: u16, parent: DevInfo) -> DeviceHandle {
+    /// Port the device at index `i` is attached to, invalid for free addresses
+    fn port_info(&self, i: usize) -> PortInfo {
+        self.devices[i].map_or(PortInfo::invalid(), |handle| handle.parent.port())
+    }
+
+    /// Currently addressed devices
+    pub fn devices(&self) -> impl Iterator<Item = AttachedDevice> + '_ {
+        self.devices.iter().flatten().map(|handle| AttachedDevice {
+            address: handle.address,
+            parent_address: handle.parent_address(),
+            port: handle.port(),
+            speed: handle.speed(),
+        })
+    }
+
+    pub fn alloc_device_address(
+        &mut self,
+        max_packet_size: u16,
+        parent: DevInfo,
+    ) -> Result<DeviceHandle, UsbHostError> {
         debug_assert!(!parent.port().is_empty());
         for i in 0..NR_DEVICES {
-            if self.info[i].is_empty() {
-                self.info[i] = parent.port();
-                return DeviceHandle {
+            if self.devices[i].is_none() {
+                let handle = DeviceHandle {
                     address: i as u8 + 1,
                     max_packet_size,
                     parent,
                 };
+                self.devices[i] = Some(handle);
+                self.port_current[i] = 0;
+                return Ok(handle);
             }
         }
-        panic!("No address available");
+        warn!("No address available");
+        Err(UsbHostError::AddressExhausted)
     }
 
     pub fn free_address(&mut self, device_handle: DeviceHandle) {
-        debug_assert!(!self.info[device_handle.address as usize - 1].is_empty());
-        self.info[device_handle.address as usize - 1] = PortInfo::invalid();
+        debug_assert!(self.devices[device_handle.address as usize - 1].is_some());
+        self.devices[device_handle.address as usize - 1] = None;
     }
 
     pub fn free_all_addresses(&mut self) -> DeviceDisconnectMask {
         let mut mask = DeviceDisconnectMask::new();
         for i in 0..NR_DEVICES {
-            if !self.info[i].is_empty() {
+            if self.devices[i].is_some() {
                 mask.mask.set(i + 1, true);
-                self.info[i] = PortInfo::invalid();
+                self.devices[i] = None;
             }
         }
         mask
     }
 
+    /// Handle of the device attached to `port`
+    pub fn device_at(&self, port: PortInfo) -> Option<DeviceHandle> {
+        self.find_index(port).and_then(|i| self.devices[i])
+    }
+
     // TODO: fix the amazing union-find to not take a DevInfo
     fn find_index(&self, dev_info: PortInfo) -> Option<usize> {
         for i in 0..NR_DEVICES {
-            if self.info[i] == dev_info {
+            if self.port_info(i) == dev_info {
                 return Some(i);
             }
         }
@@ -124,8 +271,8 @@ impl<const NR_DEVICES: usize> DeviceAddressManager<NR_DEVICES> {
         let idx = self.find_index(dev_info);
 
         if let Some(idx) = idx {
-            debug_assert!(!self.info[idx].is_empty());
-            self.info[idx] = PortInfo::invalid();
+            debug_assert!(self.devices[idx].is_some());
+            self.devices[idx] = None;
             mask.mask.set(idx + 1, true);
         } else {
             warn!("freeing non-existent device {}", dev_info);
@@ -141,12 +288,20 @@ impl<const NR_DEVICES: usize> DeviceAddressManager<NR_DEVICES> {
             parent[i] = i;
         }
 
-        // Find root of component containing x with path compression
+        // Find root of component containing x with path compression. Iterative,
+        // this runs in an async task on a small stack
         fn find(parent: &mut [usize], x: usize) -> usize {
-            if parent[x] != x {
-                parent[x] = find(parent, parent[x]);
+            let mut root = x;
+            while parent[root] != root {
+                root = parent[root];
             }
-            parent[x]
+            let mut x = x;
+            while parent[x] != root {
+                let next = parent[x];
+                parent[x] = root;
+                x = next;
+            }
+            root
         }
 
         // Union components containing x and y
@@ -168,8 +323,8 @@ impl<const NR_DEVICES: usize> DeviceAddressManager<NR_DEVICES> {
 
         // Build connected components by connecting devices to their parents
         for i in 0..NR_DEVICES {
-            if !self.info[i].is_empty() {
-                if let Some(parent_addr) = self.info[i].parent_addr() {
+            if self.devices[i].is_some() {
+                if let Some(parent_addr) = self.port_info(i).parent_addr() {
                     if parent_addr > 0 {
                         union(&mut parent, &mut rank, i, parent_addr as usize - 1);
                     }
@@ -180,29 +335,139 @@ impl<const NR_DEVICES: usize> DeviceAddressManager<NR_DEVICES> {
         // Find root component (connected to root hub)
         let mut root_component = None;
         for i in 0..NR_DEVICES {
-            if !self.info[i].is_empty() {
-                if self.info[i].parent_addr() == Some(0) {
+            if self.devices[i].is_some() {
+                if self.port_info(i).parent_addr() == Some(0) {
                     root_component = Some(find(&mut parent, i));
                     break;
                 }
             }
         }
 
-        // Create mask of disconnected devices
-        if let Some(root) = root_component {
-            for i in 0..NR_DEVICES {
-                if !self.info[i].is_empty() && find(&mut parent, i) != root {
-                    trace!(
-                        "freeing disconnected device {} with addr {}",
-                        self.info[i],
-                        i + 1
-                    );
-                    mask.mask.set(i + 1, true);
-                    self.info[i] = PortInfo::invalid();
-                }
+        // Create mask of disconnected devices, all remaining ones if none is
+        // on the root port anymore
+        for i in 0..NR_DEVICES {
+            if self.devices[i].is_some()
+                && root_component.is_none_or(|root| find(&mut parent, i) != root)
+            {
+                trace!(
+                    "freeing disconnected device {} with addr {}",
+                    self.port_info(i),
+                    i + 1
+                );
+                mask.mask.set(i + 1, true);
+                self.devices[i] = None;
             }
         }
 
         mask
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Addresses a full-speed device on `port` of `hub`, on the root port without hub
+    fn attach<const N: usize>(
+        addresses: &mut DeviceAddressManager<N>,
+        hub: Option<DeviceHandle>,
+        port: u8,
+    ) -> DeviceHandle {
+        let dev_info = match hub {
+            Some(hub) => DevInfo::new(hub.address(), port, None, 0, UsbSpeed::FullSpeed),
+            None => DevInfo::root_device(UsbSpeed::FullSpeed),
+        };
+        addresses.alloc_device_address(64, dev_info).unwrap()
+    }
+
+    fn freed(mask: &DeviceDisconnectMask) -> Vec<usize> {
+        mask.iter().collect()
+    }
+
+    #[test]
+    fn free_subtree_of_three_tier_hubs() {
+        let mut addresses = DeviceAddressManager::<8>::new();
+        let root_hub = attach(&mut addresses, None, 0);
+        let hub = attach(&mut addresses, Some(root_hub), 1);
+        let leaf_hub = attach(&mut addresses, Some(hub), 1);
+        let leaf_hub_device = attach(&mut addresses, Some(leaf_hub), 2);
+        let hub_device = attach(&mut addresses, Some(hub), 2);
+        let root_hub_device = attach(&mut addresses, Some(root_hub), 3);
+
+        // A device without children
+        let mask = addresses.free_subtree(leaf_hub_device.dev_info().port());
+        assert_eq!(freed(&mask), [leaf_hub_device.address() as usize]);
+
+        // The middle tier takes the hub below it along
+        let mask = addresses.free_subtree(hub.dev_info().port());
+        assert_eq!(
+            freed(&mask),
+            [hub.address(), leaf_hub.address(), hub_device.address()].map(usize::from)
+        );
+        assert!(addresses.device_at(root_hub_device.dev_info().port()) == Some(root_hub_device));
+
+        // Nothing is left without the device on the root port
+        let mask = addresses.free_subtree(root_hub.dev_info().port());
+        assert_eq!(
+            freed(&mask),
+            [root_hub.address(), root_hub_device.address()].map(usize::from)
+        );
+        assert_eq!(addresses.devices().count(), 0);
+    }
+
+    #[test]
+    fn free_subtree_of_deep_chain() {
+        const N: usize = 32;
+        let mut addresses = DeviceAddressManager::<N>::new();
+        let mut chain = Vec::new();
+        let mut hub = None;
+        for _ in 0..N {
+            let device = attach(&mut addresses, hub, 1);
+            chain.push(device);
+            hub = Some(device);
+        }
+
+        let mask = addresses.free_subtree(chain[N / 2].dev_info().port());
+        assert_eq!(freed(&mask), (N / 2 + 1..=N).collect::<Vec<_>>());
+        assert_eq!(addresses.devices().count(), N / 2);
+
+        let mask = addresses.free_subtree(chain[1].dev_info().port());
+        assert_eq!(freed(&mask), (2..=N / 2).collect::<Vec<_>>());
+        assert!(addresses.device_at(chain[0].dev_info().port()) == Some(chain[0]));
+    }
+
+    #[test]
+    fn alloc_fails_once_all_addresses_are_taken() {
+        let mut addresses = DeviceAddressManager::<4>::new();
+        let hub = attach(&mut addresses, None, 0);
+        for port in 1..4 {
+            attach(&mut addresses, Some(hub), port);
+        }
+        let dev_info = DevInfo::new(hub.address(), 4, None, 0, UsbSpeed::FullSpeed);
+        assert!(matches!(
+            addresses.alloc_device_address(64, dev_info),
+            Err(UsbHostError::AddressExhausted)
+        ));
+
+        // A freed address is handed out again
+        let mask = addresses.free_subtree(PortInfo::new(0x80 | hub.address(), 2));
+        assert_eq!(freed(&mask), [3]);
+        let device = addresses.alloc_device_address(64, dev_info).unwrap();
+        assert_eq!(device.address(), 3);
+    }
+
+    #[test]
+    #[cfg(not(feature = "defmt"))]
+    fn debug_format() {
+        let mut addresses = DeviceAddressManager::<4>::new();
+        let hub = attach(&mut addresses, None, 0);
+        let device = attach(&mut addresses, Some(hub), 3);
+        attach(&mut addresses, Some(device), 1);
+
+        let mask = addresses.free_subtree(device.dev_info().port());
+        assert_eq!(format!("{:?}", mask), "[2, 3]");
+        assert_eq!(format!("{:?}", DeviceDisconnectMask::new()), "[]");
+        assert!(format!("{:?}", device)
+            .starts_with("DeviceHandle { address: 2, max_packet_size: 64, parent: DevInfo {"));
+    }
+}
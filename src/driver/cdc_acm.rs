@@ -0,0 +1,405 @@
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    pipe::Pipe,
+};
+
+use crate::{
+    consts::UsbBaseClass,
+    descriptor::{Descriptor, DeviceDescriptor},
+    driver::get_configuration_descriptor,
+    errors::UsbHostError,
+    pipe::{USBHostPipe, MAX_BULK_PACKET_SIZE},
+    request::Request,
+    types::{BulkChannel, EndpointDirection},
+    DeviceHandle, HostDriver,
+};
+
+use super::USBHostDeviceDriver;
+
+/// Abstract Control Model subclass of the CDC control interface
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+/// CS_INTERFACE class specific descriptor type
+const CS_INTERFACE: u8 = 0x24;
+/// Union functional descriptor subtype
+const CDC_UNION: u8 = 0x06;
+
+pub const CDC_PIPE_SIZE: usize = 128;
+const CDC_PACKET_SIZE: usize = 64;
+/// Devices the dispatcher can bridge to pipes at the same time
+const MAX_CDC_DEVICES: usize = 2;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum StopBits {
+    One = 0,
+    OnePointFive = 1,
+    Two = 2,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum Parity {
+    None = 0,
+    Odd = 1,
+    Even = 2,
+    Mark = 3,
+    Space = 4,
+}
+
+/// Line coding sent with SET_LINE_CODING, defaults to 115200 8N1
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct LineCoding {
+    pub baud_rate: u32,
+    pub stop_bits: StopBits,
+    pub parity: Parity,
+    /// 5, 6, 7, 8 or 16
+    pub data_bits: u8,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        LineCoding {
+            baud_rate: 115200,
+            stop_bits: StopBits::One,
+            parity: Parity::None,
+            data_bits: 8,
+        }
+    }
+}
+
+impl LineCoding {
+    fn to_bytes(self) -> [u8; 7] {
+        let baud = self.baud_rate.to_le_bytes();
+        [
+            baud[0],
+            baud[1],
+            baud[2],
+            baud[3],
+            self.stop_bits as u8,
+            self.parity as u8,
+            self.data_bits,
+        ]
+    }
+}
+
+/// Bytes received from each device while its driver is run by the dispatcher
+static CDC_RX_PIPES: [Pipe<CriticalSectionRawMutex, CDC_PIPE_SIZE>; MAX_CDC_DEVICES] =
+    [const { Pipe::new() }; MAX_CDC_DEVICES];
+/// Bytes to send to each device while its driver is run by the dispatcher
+static CDC_TX_PIPES: [Pipe<CriticalSectionRawMutex, CDC_PIPE_SIZE>; MAX_CDC_DEVICES] =
+    [const { Pipe::new() }; MAX_CDC_DEVICES];
+/// Device each pair of pipes is bound to, from attach until its driver is dropped
+static CDC_PIPE_OWNERS: Mutex<
+    CriticalSectionRawMutex,
+    Cell<[Option<DeviceHandle>; MAX_CDC_DEVICES]>,
+> = Mutex::new(Cell::new([None; MAX_CDC_DEVICES]));
+
+pub struct CdcAcm {
+    device: DeviceHandle,
+    control_interface: u8,
+    bulk_in: BulkChannel,
+    bulk_out: BulkChannel,
+    /// Index of the pipes bound to the device, `None` if all were taken
+    pipes: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CurrentInterface {
+    Control,
+    Data,
+    Other,
+}
+
+impl CdcAcm {
+    /// Bytes received from `device` when its driver is run by the dispatcher.
+    /// `None` if `device` isn't an attached CDC-ACM device, or if pipes for 2
+    /// other devices are in use.
+    pub fn rx_pipe(
+        device: DeviceHandle,
+    ) -> Option<&'static Pipe<CriticalSectionRawMutex, CDC_PIPE_SIZE>> {
+        Self::pipes_of(device).map(|idx| &CDC_RX_PIPES[idx])
+    }
+
+    /// Bytes sent to `device` when its driver is run by the dispatcher, see
+    /// [`CdcAcm::rx_pipe`]
+    pub fn tx_pipe(
+        device: DeviceHandle,
+    ) -> Option<&'static Pipe<CriticalSectionRawMutex, CDC_PIPE_SIZE>> {
+        Self::pipes_of(device).map(|idx| &CDC_TX_PIPES[idx])
+    }
+
+    fn pipes_of(device: DeviceHandle) -> Option<usize> {
+        CDC_PIPE_OWNERS.lock(|owners| owners.get().iter().position(|owner| *owner == Some(device)))
+    }
+
+    /// Binds a free pair of pipes to `device`
+    fn bind_pipes(device: DeviceHandle) -> Option<usize> {
+        CDC_PIPE_OWNERS.lock(|owners| {
+            let mut bound = owners.get();
+            let idx = bound.iter().position(Option::is_none)?;
+            bound[idx] = Some(device);
+            owners.set(bound);
+            Some(idx)
+        })
+    }
+
+    pub async fn set_line_coding<D: HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        line_coding: LineCoding,
+    ) -> Result<(), UsbHostError> {
+        let mut bytes = line_coding.to_bytes();
+        pipe.control_transfer(
+            self.device,
+            &Request::cdc_set_line_coding(self.control_interface),
+            &mut bytes,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn set_control_line_state<D: HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        dtr: bool,
+        rts: bool,
+    ) -> Result<(), UsbHostError> {
+        pipe.control_transfer(
+            self.device,
+            &Request::cdc_set_control_line_state(self.control_interface, dtr, rts),
+            &mut [],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Reads at least one byte from the device, waiting while the device has no data.
+    ///
+    /// `buf` should be a multiple of the endpoint's max packet size, the device
+    /// may not send less than a full packet.
+    pub async fn read<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match pipe.bulk_transfer(&mut self.bulk_in, buf).await {
+                Ok(0)
+                | Err(
+                    UsbHostError::NAK | UsbHostError::NakTimeout | UsbHostError::TransferTimeout,
+                ) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn write<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        buf: &[u8],
+    ) -> Result<usize, UsbHostError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        pipe.bulk_out(&mut self.bulk_out, buf).await
+    }
+
+    /// Borrows the port as an `embedded_io_async` reader/writer
+    pub fn io<'p, D: HostDriver, const NR_DEVICES: usize>(
+        &'p mut self,
+        pipe: &'p USBHostPipe<D, NR_DEVICES>,
+    ) -> CdcAcmIo<'p, D, NR_DEVICES> {
+        CdcAcmIo { acm: self, pipe }
+    }
+}
+
+/// [`CdcAcm`] bound to a pipe, implementing `embedded_io_async::{Read, Write}`
+pub struct CdcAcmIo<'p, D: HostDriver, const NR_DEVICES: usize> {
+    acm: &'p mut CdcAcm,
+    pipe: &'p USBHostPipe<D, NR_DEVICES>,
+}
+
+impl<D: HostDriver, const NR_DEVICES: usize> embedded_io_async::ErrorType
+    for CdcAcmIo<'_, D, NR_DEVICES>
+{
+    type Error = UsbHostError;
+}
+
+impl<D: HostDriver, const NR_DEVICES: usize> embedded_io_async::Read
+    for CdcAcmIo<'_, D, NR_DEVICES>
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.acm.read(self.pipe, buf).await
+    }
+}
+
+impl<D: HostDriver, const NR_DEVICES: usize> embedded_io_async::Write
+    for CdcAcmIo<'_, D, NR_DEVICES>
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.acm.write(self.pipe, buf).await
+    }
+}
+
+impl USBHostDeviceDriver for CdcAcm {
+    const CLASS: Option<u8> = Some(UsbBaseClass::CDCControl as u8);
+    const SUBCLASS: Option<u8> = Some(CDC_SUBCLASS_ACM);
+
+    async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        _desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        let mut buf = pipe.descriptor_buffer().await;
+        let config_iter = get_configuration_descriptor(device, 0, &mut buf[..], pipe).await?;
+
+        let mut configuration = None;
+        let mut current = CurrentInterface::Other;
+        let mut control_interface = None;
+        // Data interface named by the union functional descriptor, if present
+        let mut union_data_interface = None;
+        let mut data_interface = None;
+        let mut bulk_in = None;
+        let mut bulk_out = None;
+
+        for desc in config_iter {
+            match desc? {
+                Descriptor::Configuration(configuration_descriptor) => {
+                    configuration = Some(configuration_descriptor.value);
+                }
+                Descriptor::Interface(interface_descriptor) => {
+                    let number = interface_descriptor.b_interface_number;
+                    current = if interface_descriptor.base_class() == Some(UsbBaseClass::CDCControl)
+                        && interface_descriptor.b_interface_sub_class == CDC_SUBCLASS_ACM
+                        && control_interface.is_none()
+                    {
+                        control_interface = Some(number);
+                        CurrentInterface::Control
+                    } else if interface_descriptor.base_class() == Some(UsbBaseClass::CDCData)
+                        && control_interface.is_some()
+                        && union_data_interface.is_none_or(|union| union == number)
+                        && data_interface.is_none_or(|data| data == number)
+                    {
+                        data_interface = Some(number);
+                        CurrentInterface::Data
+                    } else {
+                        CurrentInterface::Other
+                    };
+                }
+                Descriptor::Endpoint(endpoint_descriptor) => {
+                    // Bulk endpoints of the data interface
+                    if current != CurrentInterface::Data {
+                        continue;
+                    }
+                    if let Some(channel) =
+                        BulkChannel::from_descriptor(device, endpoint_descriptor)?
+                    {
+                        match channel.endpoint_address().direction {
+                            EndpointDirection::In => bulk_in = bulk_in.or(Some(channel)),
+                            EndpointDirection::Out => bulk_out = bulk_out.or(Some(channel)),
+                        }
+                    }
+                }
+                Descriptor::UnknownDescriptor {
+                    descriptor_type: CS_INTERFACE,
+                    length,
+                    data,
+                } => {
+                    // Union functional descriptor: bControlInterface, bSubordinateInterface0
+                    if current == CurrentInterface::Control && length >= 5 && data[2] == CDC_UNION {
+                        trace!("CDC union: control {} data {}", data[3], data[4]);
+                        union_data_interface = Some(data[4]);
+                    }
+                }
+                _ => continue,
+            }
+        }
+
+        let (Some(configuration), Some(control_interface), Some(bulk_in), Some(bulk_out)) =
+            (configuration, control_interface, bulk_in, bulk_out)
+        else {
+            return Err(UsbHostError::UnexpectedDevice);
+        };
+
+        pipe.set_configuration(device, configuration).await?;
+
+        let acm = CdcAcm {
+            device,
+            control_interface,
+            bulk_in,
+            bulk_out,
+            pipes: Self::bind_pipes(device),
+        };
+        acm.set_line_coding(pipe, LineCoding::default()).await?;
+        acm.set_control_line_state(pipe, true, true).await?;
+        debug!("CDC-ACM attached on interface {}", control_interface);
+
+        Ok(acm)
+    }
+
+    /// Bridges the device to [`CdcAcm::rx_pipe`] and [`CdcAcm::tx_pipe`]
+    async fn run<D: HostDriver, const NR_DEVICES: usize>(
+        mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        let Some(idx) = self.pipes else {
+            warn!("No free CDC pipes for {:?}", self.device);
+            return Err(UsbHostError::Unsupported);
+        };
+        let (rx_pipe, tx_pipe) = (&CDC_RX_PIPES[idx], &CDC_TX_PIPES[idx]);
+        // One packet per IN transfer, sized for high-speed packets
+        let mut rx_buf = [0u8; MAX_BULK_PACKET_SIZE];
+        let rx_len = (self.bulk_in.max_packet_size as usize).min(MAX_BULK_PACKET_SIZE);
+        let mut tx_buf = [0u8; CDC_PACKET_SIZE];
+
+        loop {
+            // TX is checked between IN transfers rather than raced with them, a
+            // cancelled IN would lose the data the device already sent and
+            // leave the data toggle out of sync
+            if let Ok(len) = tx_pipe.try_read(&mut tx_buf) {
+                self.write(pipe, &tx_buf[..len]).await?;
+            }
+            match pipe
+                .bulk_transfer(&mut self.bulk_in, &mut rx_buf[..rx_len])
+                .await
+            {
+                Ok(len) => {
+                    let mut written = 0;
+                    while written < len {
+                        written += rx_pipe.write(&rx_buf[written..len]).await;
+                    }
+                }
+                Err(
+                    UsbHostError::NAK | UsbHostError::NakTimeout | UsbHostError::TransferTimeout,
+                ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for CdcAcm {
+    /// Releases the device's pipes, bytes not read or sent yet are dropped
+    fn drop(&mut self) {
+        if let Some(idx) = self.pipes {
+            CDC_RX_PIPES[idx].clear();
+            CDC_TX_PIPES[idx].clear();
+            CDC_PIPE_OWNERS.lock(|owners| {
+                let mut bound = owners.get();
+                bound[idx] = None;
+                owners.set(bound);
+            });
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Running several drivers on one device.
+//!
+//! [`USBDeviceDispatcher`](super::USBDeviceDispatcher) is generic over a single
+//! driver type. [`Composite`] is itself a driver that offers the device to two
+//! drivers and runs all of them that attach concurrently, so a composite
+//! keyboard + mouse can be handled with
+//! `USBDeviceDispatcher<Composite<HidKbd, HidMouse>, _, N>`. More drivers are
+//! registered by nesting: `Composite<A, Composite<B, C>>`.
+//!
+//! # Interface claiming
+//!
+//! Drivers whose [`matches`](USBHostDeviceDriver::matches) rejects the device
+//! are skipped. Each driver walks the configuration descriptor in `try_attach` and only
+//! claims the interface(s) it recognizes, e.g. `HidKbd` the boot keyboard
+//! interface (3/1/1) and `HidMouse` the boot mouse interface (3/1/2). Drivers
+//! are offered the device in order `A` then `B`, so when two drivers would
+//! match the same interface both get it; list drivers so that their
+//! interfaces do not overlap. The device is put in its first configuration
+//! once, before the drivers are offered it, and their own SET_CONFIGURATION of
+//! it is skipped, so the drivers must select the first configuration.
+
+use embassy_futures::join::join;
+
+use crate::{
+    descriptor::DeviceDescriptor, errors::UsbHostError, pipe::USBHostPipe, types::DeviceInterfaces,
+    DeviceHandle, HostDriver,
+};
+
+use super::USBHostDeviceDriver;
+
+/// Driver offering a device to both `A` and `B`, see the [module docs](self)
+pub struct Composite<A, B> {
+    a: Option<A>,
+    b: Option<B>,
+}
+
+impl<A, B> Composite<A, B> {
+    pub fn first(&self) -> Option<&A> {
+        self.a.as_ref()
+    }
+
+    pub fn second(&self) -> Option<&B> {
+        self.b.as_ref()
+    }
+}
+
+impl<A: USBHostDeviceDriver, B: USBHostDeviceDriver> USBHostDeviceDriver for Composite<A, B> {
+    fn matches(desc: &DeviceDescriptor, interfaces: &DeviceInterfaces) -> bool {
+        A::matches(desc, interfaces) || B::matches(desc, interfaces)
+    }
+
+    /// Attaches if at least one of the drivers attaches
+    async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        // Only the outermost of nested composites configures the device
+        let outer = pipe.begin_composed(device).await?;
+        let a = A::attach(pipe, device, desc.clone()).await;
+        let b = B::attach(pipe, device, desc).await;
+        if outer {
+            pipe.end_composed(device).await;
+        }
+
+        match (a, b) {
+            (Err(e), Err(_)) => Err(e),
+            (a, b) => Ok(Composite {
+                a: a.ok(),
+                b: b.ok(),
+            }),
+        }
+    }
+
+    /// Runs the attached drivers until all of them return, the first error is reported
+    async fn run<D: HostDriver, const NR_DEVICES: usize>(
+        self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        match (self.a, self.b) {
+            (Some(a), Some(b)) => {
+                let (a, b) = join(a.run(pipe), b.run(pipe)).await;
+                a.and(b)
+            }
+            (Some(a), None) => a.run(pipe).await,
+            (None, Some(b)) => b.run(pipe).await,
+            (None, None) => Ok(()),
+        }
+    }
+}
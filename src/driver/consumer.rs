@@ -0,0 +1,190 @@
+use arrayvec::ArrayVec;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, Receiver},
+};
+use embassy_time::Timer;
+
+use crate::{
+    consts::UsbBaseClass,
+    descriptor::{
+        hid::{ReportDescriptorParser, ReportField, USAGE_PAGE_CONSUMER},
+        DeviceDescriptor,
+    },
+    errors::UsbHostError,
+    pipe::USBHostPipe,
+    types::InterruptChannel,
+    DeviceHandle, HostDriver,
+};
+
+use super::{attach_hid_interface, USBHostDeviceDriver};
+
+/// Largest report read from the device, longer reports are truncated
+const MAX_REPORT_SIZE: usize = 64;
+/// Consumer control input fields kept from the report descriptor
+const MAX_FIELDS: usize = 32;
+/// Usages reported as pressed at the same time, further usages are ignored
+const MAX_PRESSED: usize = 16;
+const CONSUMER_CHANNEL_SIZE: usize = 8;
+
+/// Press or release of a consumer control, e.g. 0xE9 Volume Increment or
+/// 0xCD Play/Pause
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct ConsumerEvent {
+    /// Usage ID on the consumer page (0x0C)
+    pub usage: u16,
+    pub pressed: bool,
+}
+
+static CONSUMER_CHANNEL: Channel<CriticalSectionRawMutex, ConsumerEvent, CONSUMER_CHANNEL_SIZE> =
+    Channel::new();
+
+/// Driver for the consumer control (media keys) interface of a keyboard,
+/// decoded with the interface's report descriptor.
+///
+/// Keyboards expose it next to the boot keyboard interface, use it together
+/// with [`HidKbd`](super::kbd::HidKbd) through [`Composite`](super::composite::Composite).
+pub struct HidConsumer {
+    interrupt_channel: InterruptChannel,
+    max_packet_size: u16,
+    fields: ArrayVec<ReportField, MAX_FIELDS>,
+    /// Reports are prefixed with a report ID byte
+    report_ids: bool,
+}
+
+impl HidConsumer {
+    /// Events from all attached devices, events are dropped when the channel is full
+    pub fn channel_receiver(
+    ) -> Receiver<'static, CriticalSectionRawMutex, ConsumerEvent, CONSUMER_CHANNEL_SIZE> {
+        CONSUMER_CHANNEL.receiver()
+    }
+
+    /// Consumer input fields of a report descriptor, `None` if it declares none
+    fn consumer_fields(
+        report_descriptor: &[u8],
+    ) -> Result<Option<(ArrayVec<ReportField, MAX_FIELDS>, bool)>, UsbHostError> {
+        let mut fields = ArrayVec::new();
+        let mut report_ids = false;
+        for field in ReportDescriptorParser::new(report_descriptor) {
+            let field = field?;
+            report_ids |= field.report_id != 0;
+            if field.is_input()
+                && field.usage_page == USAGE_PAGE_CONSUMER
+                && fields.try_push(field).is_err()
+            {
+                warn!("more than {} consumer fields", MAX_FIELDS);
+            }
+        }
+        Ok((!fields.is_empty()).then_some((fields, report_ids)))
+    }
+
+    /// Usages pressed in `report` (without report ID) according to the fields of `report_id`
+    fn pressed_usages(&self, report_id: u8, report: &[u8]) -> ArrayVec<u16, MAX_PRESSED> {
+        let mut pressed = ArrayVec::new();
+        for field in self.fields.iter().filter(|f| f.report_id == report_id) {
+            let Some(value) = field.extract_signed(report) else {
+                continue;
+            };
+            let usage = if field.is_variable() {
+                // One bit (or value) per usage, non zero is pressed
+                (value != 0).then_some(field.usage)
+            } else {
+                // Array of usage indices, relative to the logical minimum
+                field.array_usage(value)
+            };
+            // Usage 0 is "Unassigned", i.e. an empty array slot
+            if let Some(usage) = usage.filter(|usage| *usage != 0) {
+                if !pressed.contains(&usage) {
+                    let _ = pressed.try_push(usage);
+                }
+            }
+        }
+        pressed
+    }
+}
+
+impl USBHostDeviceDriver for HidConsumer {
+    const CLASS: Option<u8> = Some(UsbBaseClass::HID as u8);
+
+    async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        _desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        let hid = attach_hid_interface(
+            pipe,
+            device,
+            // Boot mice are left to HidMouse, everything else may carry consumer controls
+            |interface| {
+                !(interface.b_interface_sub_class == 0x01 && interface.b_interface_protocol == 0x02)
+            },
+            |report_descriptor| report_descriptor.map_or(Ok(None), Self::consumer_fields),
+        )
+        .await?;
+        let (fields, report_ids) = hid.report;
+        debug!(
+            "Consumer control attached on interface {}, {} fields",
+            hid.interface,
+            fields.len()
+        );
+
+        Ok(HidConsumer {
+            interrupt_channel: hid.in_channel,
+            max_packet_size: hid.max_packet_size,
+            fields,
+            report_ids,
+        })
+    }
+
+    async fn run<D: HostDriver, const NR_DEVICES: usize>(
+        mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        let report_len = core::cmp::min(self.max_packet_size as usize, MAX_REPORT_SIZE);
+        // Usages currently pressed, with the report ID they were reported in
+        let mut pressed: ArrayVec<(u8, u16), MAX_PRESSED> = ArrayVec::new();
+        let sender = CONSUMER_CHANNEL.sender();
+        let send = |usage: u16, pressed: bool| {
+            let event = ConsumerEvent { usage, pressed };
+            if sender.try_send(event).is_err() {
+                trace!("consumer event dropped: {:?}", event);
+            }
+        };
+
+        loop {
+            Timer::after(self.interrupt_channel.interval()).await;
+            let len = match pipe
+                .interrupt_transfer(&mut self.interrupt_channel, &mut buf[..report_len])
+                .await
+            {
+                Ok(len) => len,
+                Err(UsbHostError::NAK) => continue,
+                Err(e) => return Err(e),
+            };
+            let (report_id, report) = match (self.report_ids, &buf[..len]) {
+                (true, [report_id, report @ ..]) => (*report_id, report),
+                (false, report) => (0, report),
+                (true, []) => continue,
+            };
+
+            let current = self.pressed_usages(report_id, report);
+            pressed.retain(|(id, usage)| {
+                let held = *id != report_id || current.contains(usage);
+                if !held {
+                    send(*usage, false);
+                }
+                held
+            });
+            for usage in current {
+                if !pressed.contains(&(report_id, usage))
+                    && pressed.try_push((report_id, usage)).is_ok()
+                {
+                    send(usage, true);
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,11 @@
+use arrayvec::ArrayVec;
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
-    channel::{self, Channel, Receiver, Sender},
-    pipe::Writer,
+    channel::{Channel, Receiver, Sender},
+    pipe::Pipe,
 };
-use usb_dfu_target::consts::{DfuRequest, State, DFU_PROTOCOL_RT, USB_CLASS_APPN_SPEC};
+use embassy_time::Timer;
+use usb_dfu_target::consts::{State, DFU_PROTOCOL_RT, USB_CLASS_APPN_SPEC};
 
 use crate::{
     descriptor::{Descriptor, DeviceDescriptor},
@@ -14,11 +16,23 @@ use crate::{
     DeviceHandle,
 };
 
-use super::{get_configuration_descriptor, DeviceChannel};
+use super::get_configuration_descriptor;
+
+/// Largest DNLOAD block, devices advertising a larger `wTransferSize` get
+/// blocks of this size
+pub const DFU_MAX_TRANSFER_SIZE: usize = 1024;
+pub const DFU_STREAM_SIZE: usize = 256;
 
 pub enum DFUOperation {
     StartDownload,
-    Bytes([u8; 8]),
+    /// Firmware bytes, e.g. an image in flash, downloaded in blocks of up to
+    /// the device's transfer size. Use [`DFUOperation::Stream`] for data that
+    /// isn't `'static`.
+    Bytes(&'static [u8]),
+    /// Downloads the next `len` bytes written to [`UsbDfu::stream_pipe`]
+    Stream(usize),
+    /// Reads the firmware back, blocks are sent to [`UsbDfu::upload_receiver`]
+    Upload,
     Manifest,
     Detach,
 }
@@ -44,6 +58,19 @@ struct DFUInfo {
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
 struct DFUCapabilities(u8);
 
+/// DFU_GETSTATUS response
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+struct DFUStatus {
+    /// bStatus, 0 when OK
+    status: u8,
+    /// bwPollTimeout in ms
+    poll_timeout: u32,
+    /// bState
+    state: u8,
+}
+
 impl DFUCapabilities {
     pub fn can_download(&self) -> bool {
         self.0 & 0b00000001 != 0
@@ -59,10 +86,20 @@ impl DFUCapabilities {
     }
 }
 
+/// Firmware block read with [`DFUOperation::Upload`]
+pub struct DFUUploadBlock {
+    pub data: ArrayVec<u8, DFU_MAX_TRANSFER_SIZE>,
+    /// Set on the final, short block of the upload
+    pub last: bool,
+}
+
 static DFU_CHANNEL: Channel<CriticalSectionRawMutex, DFUOperation, 1> = Channel::new();
+static DFU_STREAM: Pipe<CriticalSectionRawMutex, DFU_STREAM_SIZE> = Pipe::new();
+static DFU_UPLOAD_CHANNEL: Channel<CriticalSectionRawMutex, DFUUploadBlock, 1> = Channel::new();
 
 pub struct UsbDfu {
     pub device: DeviceHandle,
+    interface: u8,
     info: DFUInfo,
 }
 
@@ -71,21 +108,185 @@ impl UsbDfu {
         DFU_CHANNEL.sender()
     }
 
+    /// Firmware bytes of a [`DFUOperation::Stream`] download
+    pub fn stream_pipe() -> &'static Pipe<CriticalSectionRawMutex, DFU_STREAM_SIZE> {
+        &DFU_STREAM
+    }
+
+    pub fn upload_receiver() -> Receiver<'static, CriticalSectionRawMutex, DFUUploadBlock, 1> {
+        DFU_UPLOAD_CHANNEL.receiver()
+    }
+
     fn channel_receiver(&self) -> Receiver<'static, CriticalSectionRawMutex, DFUOperation, 1> {
         DFU_CHANNEL.receiver()
     }
+
+    /// DNLOAD block size, `wTransferSize` capped at [`DFU_MAX_TRANSFER_SIZE`]
+    fn block_size(&self) -> usize {
+        match self.info.transfer_size as usize {
+            0 => DFU_MAX_TRANSFER_SIZE,
+            size => core::cmp::min(size, DFU_MAX_TRANSFER_SIZE),
+        }
+    }
+
+    async fn get_status<D: crate::HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &crate::pipe::USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<DFUStatus, UsbHostError> {
+        let mut buf = [0u8; 6];
+        let len = pipe
+            .control_transfer(
+                self.device,
+                &Request::dfu_get_status(self.interface),
+                &mut buf,
+            )
+            .await?;
+        if len < buf.len() {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        Ok(DFUStatus {
+            status: buf[0],
+            poll_timeout: u32::from_le_bytes([buf[1], buf[2], buf[3], 0]),
+            state: buf[4],
+        })
+    }
+
+    /// Polls GET_STATUS while the device is in `busy_states`, waiting
+    /// `bwPollTimeout` before each request. dfuERROR is cleared and reported.
+    async fn wait_while<D: crate::HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &crate::pipe::USBHostPipe<D, NR_DEVICES>,
+        busy_states: &[State],
+    ) -> Result<DFUStatus, UsbHostError> {
+        loop {
+            let status = self.get_status(pipe).await?;
+            if status.state == State::Error as u8 {
+                error!("DFU error, status {}", status.status);
+                pipe.control_transfer(
+                    self.device,
+                    &Request::dfu_clear_status(self.interface),
+                    &mut [],
+                )
+                .await?;
+                return Err(UsbHostError::InvalidState);
+            }
+            if !busy_states.iter().any(|state| *state as u8 == status.state) {
+                return Ok(status);
+            }
+            Timer::after_millis(status.poll_timeout as u64).await;
+        }
+    }
+
+    /// DNLOAD of one block, returns once the device is back in dfuDNLOAD-IDLE
+    async fn download_block<D: crate::HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &crate::pipe::USBHostPipe<D, NR_DEVICES>,
+        block_num: u16,
+        block: &mut [u8],
+    ) -> Result<(), UsbHostError> {
+        let dfu_download = Request::dfu_dnload(self.interface, block_num, block.len() as u16);
+        trace!("sending ctrl transfer to do DFU");
+        if let Err(e) = pipe
+            .control_transfer(self.device, &dfu_download, block)
+            .await
+        {
+            error!("Error downloading bytes: {:?}", e);
+            // A STALLed DNLOAD leaves the device in dfuERROR
+            self.wait_while(pipe, &[]).await?;
+            return Err(e);
+        }
+
+        let status = self
+            .wait_while(pipe, &[State::DownloadSync, State::DownloadBusy])
+            .await?;
+        if status.state != State::DownloadIdle as u8 {
+            error!("DFU unexpected state {} after download", status.state);
+            return Err(UsbHostError::InvalidState);
+        }
+        info!("Downloaded block {:?}", block_num);
+        Ok(())
+    }
+
+    /// Reads the firmware in `block_size` blocks until the device returns a
+    /// short block, returns the number of bytes read
+    async fn upload<D: crate::HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &crate::pipe::USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<usize, UsbHostError> {
+        if !self.info.capabilities.can_upload() {
+            error!("DFU device can not upload");
+            return Err(UsbHostError::InvalidState);
+        }
+
+        let block_size = self.block_size();
+        let sender = DFU_UPLOAD_CHANNEL.sender();
+        let mut block_num = 0u16;
+        let mut total = 0;
+        let mut block = [0u8; DFU_MAX_TRANSFER_SIZE];
+        loop {
+            let len = pipe
+                .control_transfer(
+                    self.device,
+                    &Request::dfu_upload(self.interface, block_num, block_size as u16),
+                    &mut block[..block_size],
+                )
+                .await?;
+            let data = block[..len].iter().copied().collect();
+            total += len;
+            let last = len < block_size;
+            sender.send(DFUUploadBlock { data, last }).await;
+            if last {
+                info!("Uploaded {} bytes", total);
+                return Ok(total);
+            }
+            block_num = block_num.wrapping_add(1);
+        }
+    }
+
+    /// Zero length DNLOAD ending the download, then waits for manifestation
+    async fn manifest<D: crate::HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &crate::pipe::USBHostPipe<D, NR_DEVICES>,
+        block_num: u16,
+    ) -> Result<(), UsbHostError> {
+        pipe.control_transfer(
+            self.device,
+            &Request::dfu_dnload(self.interface, block_num, 0),
+            &mut [],
+        )
+        .await?;
+
+        if !self.info.capabilities.can_manifest() {
+            // The device may stop responding once it enters dfuMANIFEST
+            let status = self.wait_while(pipe, &[State::ManifestSync]).await?;
+            Timer::after_millis(status.poll_timeout as u64).await;
+            return Ok(());
+        }
+
+        let status = self
+            .wait_while(pipe, &[State::ManifestSync, State::Manifest])
+            .await?;
+        if status.state != State::DfuIdle as u8 {
+            error!("DFU unexpected state {} after manifestation", status.state);
+            return Err(UsbHostError::InvalidState);
+        }
+        Ok(())
+    }
 }
 
 impl USBHostDeviceDriver for UsbDfu {
+    const CLASS: Option<u8> = Some(USB_CLASS_APPN_SPEC);
+    const SUBCLASS: Option<u8> = Some(DFU_PROTOCOL_RT);
+
     async fn try_attach<D: crate::HostDriver, const NR_DEVICES: usize>(
         pipe: &crate::pipe::USBHostPipe<D, NR_DEVICES>,
         device: DeviceHandle,
         _desc: DeviceDescriptor,
     ) -> Result<Self, crate::errors::UsbHostError> {
-        let mut buf: [u8; 255] = [0; 255];
-        let desc_iter = get_configuration_descriptor(device, &mut buf, pipe).await?;
+        let mut buf = pipe.descriptor_buffer().await;
+        let desc_iter = get_configuration_descriptor(device, 0, &mut buf[..], pipe).await?;
 
-        let mut is_dfu = false;
+        let mut dfu_interface = None;
         let mut dfu_info: Option<DFUInfo> = None;
 
         for desc in desc_iter {
@@ -99,13 +300,12 @@ impl USBHostDeviceDriver for UsbDfu {
                 Descriptor::Endpoint(endpoint_descriptor) => {
                     trace!("found endpoint descriptor: {:?}", endpoint_descriptor);
                 }
-                Descriptor::Interface(interface_descriptor) => {
+                Descriptor::Interface(interface_descriptor)
                     if interface_descriptor.b_interface_class == USB_CLASS_APPN_SPEC
-                        && interface_descriptor.b_interface_sub_class == DFU_PROTOCOL_RT
-                    {
-                        trace!("found DFU interface descriptor: {:?}", interface_descriptor);
-                        is_dfu = true;
-                    }
+                        && interface_descriptor.b_interface_sub_class == DFU_PROTOCOL_RT =>
+                {
+                    trace!("found DFU interface descriptor: {:?}", interface_descriptor);
+                    dfu_interface = Some(interface_descriptor.b_interface_number);
                 }
                 Descriptor::UnknownDescriptor {
                     descriptor_type,
@@ -128,13 +328,15 @@ impl USBHostDeviceDriver for UsbDfu {
                         trace!("found unknown descriptor: {:?}", descriptor_type);
                     }
                 },
-                _ => panic!("unexpected descriptor: {:?}", desc),
+                // Other interfaces, associations, audio and device level descriptors
+                _ => {}
             }
         }
 
-        if is_dfu {
+        if let Some(interface) = dfu_interface {
             Ok(UsbDfu {
                 device,
+                interface,
                 info: dfu_info.ok_or(UsbHostError::InvalidState)?,
             })
         } else {
@@ -148,23 +350,16 @@ impl USBHostDeviceDriver for UsbDfu {
     ) -> Result<(), crate::errors::UsbHostError> {
         let device_handle = self.device;
         let mut buffer = [0u8; 64];
-        let dfu_get_state = Request {
-            request_type: {
-                let mut t = crate::request::RequestType::default();
-                t.set_data_direction(crate::request::RequestTypeDirection::DeviceToHost);
-                t.set_type(crate::request::RequestTypeType::Class);
-                t.set_recipient(crate::request::RequestTypeRecipient::Interface);
-                t
-            },
-            request: 0x05,
-            value: 0,
-            index: 0,
-            length: 1,
-        };
-        pipe.control_transfer(device_handle, &dfu_get_state, &mut buffer)
-            .await?;
+        pipe.control_transfer(
+            device_handle,
+            &Request::dfu_get_state(self.interface),
+            &mut buffer,
+        )
+        .await?;
         trace!("DFU device attached, state: {:?}", buffer[0]);
         let channel_receiver = self.channel_receiver();
+        let block_size = self.block_size();
+        let mut block = [0u8; DFU_MAX_TRANSFER_SIZE];
         let mut dfu_block_counter = 0u16;
         loop {
             let dfu_op = channel_receiver.receive().await;
@@ -173,41 +368,34 @@ impl USBHostDeviceDriver for UsbDfu {
                     trace!("Starting download");
                     dfu_block_counter = 0;
                 }
-                DFUOperation::Bytes(mut bytes) => {
-                    for _ in 0..3 {
-                        let dfu_download = Request {
-                            request_type: {
-                                let mut t = crate::request::RequestType::default();
-                                t.set_type(crate::request::RequestTypeType::Class);
-                                t.set_recipient(crate::request::RequestTypeRecipient::Interface);
-                                t.set_data_direction(
-                                    crate::request::RequestTypeDirection::HostToDevice,
-                                );
-                                t
-                            },
-                            request: DfuRequest::Dnload as u8,
-                            value: dfu_block_counter,
-                            index: 0,
-                            length: 8,
-                        };
-                        trace!("sending ctrl transfer to do DFU");
-                        match pipe
-                            .control_transfer(device_handle, &dfu_download, &mut bytes)
-                            .await
-                        {
-                            Ok(_) => {
-                                info!("Downloaded block {:?}", dfu_block_counter);
-                                dfu_block_counter = dfu_block_counter.wrapping_add(1);
-                                break;
-                            }
-                            Err(e) => {
-                                error!("Error downloading bytes: {:?}", e);
-                            }
+                DFUOperation::Bytes(bytes) => {
+                    for chunk in bytes.chunks(block_size) {
+                        let block = &mut block[..chunk.len()];
+                        block.copy_from_slice(chunk);
+                        self.download_block(pipe, dfu_block_counter, block).await?;
+                        dfu_block_counter = dfu_block_counter.wrapping_add(1);
+                    }
+                }
+                DFUOperation::Stream(mut len) => {
+                    while len > 0 {
+                        let block_len = core::cmp::min(len, block_size);
+                        let mut filled = 0;
+                        while filled < block_len {
+                            filled += DFU_STREAM.read(&mut block[filled..block_len]).await;
                         }
+                        self.download_block(pipe, dfu_block_counter, &mut block[..block_len])
+                            .await?;
+                        dfu_block_counter = dfu_block_counter.wrapping_add(1);
+                        len -= block_len;
                     }
                 }
+                DFUOperation::Upload => {
+                    trace!("Starting upload");
+                    self.upload(pipe).await?;
+                }
                 DFUOperation::Manifest => {
                     trace!("Received manifest");
+                    self.manifest(pipe, dfu_block_counter).await?;
                 }
                 DFUOperation::Detach => {
                     trace!("Detaching");
@@ -0,0 +1,208 @@
+use arrayvec::ArrayVec;
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, Receiver},
+};
+use embassy_time::Timer;
+
+use crate::{
+    consts::UsbBaseClass,
+    descriptor::{
+        hid::{
+            ReportDescriptorParser, ReportField, USAGE_GAMEPAD, USAGE_JOYSTICK, USAGE_PAGE_BUTTON,
+            USAGE_PAGE_GENERIC_DESKTOP,
+        },
+        DeviceDescriptor,
+    },
+    errors::UsbHostError,
+    pipe::USBHostPipe,
+    types::InterruptChannel,
+    DeviceHandle, HostDriver,
+};
+
+use super::{attach_hid_interface, USBHostDeviceDriver};
+
+/// Axes reported in [`GamepadState::axes`], in usage order: X, Y, Z, Rx, Ry,
+/// Rz, slider, dial
+pub const MAX_AXES: usize = 8;
+/// Usage of the first axis (X) on the generic desktop page
+const USAGE_X: u16 = 0x30;
+/// Buttons reported in [`GamepadState::buttons`], one bit each
+const MAX_BUTTONS: u16 = 32;
+/// Largest report read from the device, longer reports are truncated
+const MAX_REPORT_SIZE: usize = 64;
+/// Axis and button input fields kept from the report descriptor
+const MAX_FIELDS: usize = 48;
+const GAMEPAD_CHANNEL_SIZE: usize = 4;
+
+/// State of a joystick or gamepad, sent whenever it changes
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct GamepadState {
+    /// Axes scaled from their logical range to the full `i16` range, 0 for
+    /// axes the device doesn't have
+    pub axes: [i16; MAX_AXES],
+    /// Bit n is button n + 1
+    pub buttons: u32,
+}
+
+static GAMEPAD_CHANNEL: Channel<CriticalSectionRawMutex, GamepadState, GAMEPAD_CHANNEL_SIZE> =
+    Channel::new();
+
+/// Driver for HID joysticks and gamepads, decoded with the interface's report
+/// descriptor.
+///
+/// Only the axes and buttons of the joystick or gamepad application collection
+/// are reported, hat switches and vendor specific fields are ignored.
+pub struct HidGamepad {
+    interrupt_channel: InterruptChannel,
+    max_packet_size: u16,
+    fields: ArrayVec<ReportField, MAX_FIELDS>,
+    /// Reports are prefixed with a report ID byte
+    report_ids: bool,
+}
+
+impl HidGamepad {
+    /// States of all attached devices, states are dropped when the channel is full
+    pub fn channel_receiver(
+    ) -> Receiver<'static, CriticalSectionRawMutex, GamepadState, GAMEPAD_CHANNEL_SIZE> {
+        GAMEPAD_CHANNEL.receiver()
+    }
+
+    /// Index of the axis `field` reports, `None` if it is no axis
+    fn axis(field: &ReportField) -> Option<usize> {
+        let index = field.usage.checked_sub(USAGE_X)? as usize;
+        (field.usage_page == USAGE_PAGE_GENERIC_DESKTOP && field.is_variable() && index < MAX_AXES)
+            .then_some(index)
+    }
+
+    /// Axis and button fields of a report descriptor, `None` if it declares no
+    /// joystick or gamepad
+    fn gamepad_fields(
+        report_descriptor: &[u8],
+    ) -> Result<Option<(ArrayVec<ReportField, MAX_FIELDS>, bool)>, UsbHostError> {
+        let mut fields = ArrayVec::new();
+        let mut report_ids = false;
+        for field in ReportDescriptorParser::new(report_descriptor) {
+            let field = field?;
+            report_ids |= field.report_id != 0;
+            let gamepad = field.application_usage_page == USAGE_PAGE_GENERIC_DESKTOP
+                && matches!(field.application_usage, USAGE_JOYSTICK | USAGE_GAMEPAD);
+            if gamepad
+                && field.is_input()
+                && (field.usage_page == USAGE_PAGE_BUTTON || Self::axis(&field).is_some())
+                && fields.try_push(field).is_err()
+            {
+                warn!("more than {} gamepad fields", MAX_FIELDS);
+            }
+        }
+        Ok((!fields.is_empty()).then_some((fields, report_ids)))
+    }
+
+    /// Updates `state` with the fields of `report_id` in `report` (without report ID)
+    fn update(&self, state: &mut GamepadState, report_id: u8, report: &[u8]) {
+        let fields = self.fields.iter().filter(|f| f.report_id == report_id);
+        // Buttons missing from the report are released
+        let mut buttons = 0;
+        let mut has_buttons = false;
+        for field in fields {
+            let Some(value) = field.extract_signed(report) else {
+                continue;
+            };
+            if let Some(axis) = Self::axis(field) {
+                state.axes[axis] = Self::scale(field, value);
+                continue;
+            }
+            has_buttons = true;
+            let button = if field.is_variable() {
+                (value != 0).then_some(field.usage)
+            } else {
+                // Array of button indices, relative to the logical minimum
+                field.array_usage(value)
+            };
+            // Button 0 is "No button pressed"
+            if let Some(button @ 1..=MAX_BUTTONS) = button {
+                buttons |= 1 << (button - 1);
+            }
+        }
+        if has_buttons {
+            state.buttons = buttons;
+        }
+    }
+
+    /// Scales `value` from the logical range of `field` to the `i16` range
+    fn scale(field: &ReportField, value: i32) -> i16 {
+        let (min, max) = (field.logical_minimum as i64, field.logical_maximum as i64);
+        if max <= min {
+            return value.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+        let value = (value as i64).clamp(min, max);
+        ((value - min) * u16::MAX as i64 / (max - min) + i16::MIN as i64) as i16
+    }
+}
+
+impl USBHostDeviceDriver for HidGamepad {
+    const CLASS: Option<u8> = Some(UsbBaseClass::HID as u8);
+
+    async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        _desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        let hid = attach_hid_interface(
+            pipe,
+            device,
+            // Boot keyboards and mice are left to their drivers
+            |interface| interface.b_interface_sub_class != 0x01,
+            |report_descriptor| report_descriptor.map_or(Ok(None), Self::gamepad_fields),
+        )
+        .await?;
+        let (fields, report_ids) = hid.report;
+        debug!(
+            "Gamepad attached on interface {}, {} fields",
+            hid.interface,
+            fields.len()
+        );
+
+        Ok(HidGamepad {
+            interrupt_channel: hid.in_channel,
+            max_packet_size: hid.max_packet_size,
+            fields,
+            report_ids,
+        })
+    }
+
+    async fn run<D: HostDriver, const NR_DEVICES: usize>(
+        mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        let report_len = core::cmp::min(self.max_packet_size as usize, MAX_REPORT_SIZE);
+        let mut state = GamepadState::default();
+        let sender = GAMEPAD_CHANNEL.sender();
+
+        loop {
+            Timer::after(self.interrupt_channel.interval()).await;
+            let len = match pipe
+                .interrupt_transfer(&mut self.interrupt_channel, &mut buf[..report_len])
+                .await
+            {
+                Ok(len) => len,
+                Err(UsbHostError::NAK) => continue,
+                Err(e) => return Err(e),
+            };
+            let (report_id, report) = match (self.report_ids, &buf[..len]) {
+                (true, [report_id, report @ ..]) => (*report_id, report),
+                (false, report) => (0, report),
+                (true, []) => continue,
+            };
+
+            let previous = state;
+            self.update(&mut state, report_id, report);
+            if state != previous && sender.try_send(state).is_err() {
+                trace!("gamepad state dropped: {:?}", state);
+            }
+        }
+    }
+}
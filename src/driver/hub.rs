@@ -1,79 +1,177 @@
 use bitvec::{array::BitArray, BitArr};
+use embassy_time::{Duration, Timer};
 
 use crate::{
     descriptor::{
-        hub::{HubDescriptor, HubPortFeature, HubPortStatus, HubPortStatusChange},
+        hub::{
+            DeviceRemovable, HubCharacteristics, HubDescriptor, HubPortFeature, HubPortStatus,
+            HubPortStatusChange, MAX_HUB_DESCRIPTOR_SIZE,
+        },
         ConfigurationDescriptor, Descriptor, DeviceDescriptor,
     },
+    device_addr::{HIGH_POWER_PORT_MA, LOW_POWER_PORT_MA},
     driver::get_configuration_descriptor,
     errors::UsbHostError,
     pipe::USBHostPipe,
     request::{Request, RequestTypeRecipient, RequestTypeType},
-    types::{DataTog, DevInfo, InterruptChannel, PortInfo, UsbSpeed},
-    DeviceHandle, HostDriver,
+    types::{DevInfo, EndpointDirection, InterruptChannel, PortInfo, UsbSpeed},
+    DeviceHandle, HostDriver, RESET_RECOVERY, RESUME_RECOVERY,
 };
 
-type PortChangeBitmask = BitArr!(for 128, in u8);
+/// Status change bitmap, bit 0 is the hub, bit n is port n
+type PortChangeBitmask = BitArr!(for 256, in u8);
+
+/// bInterfaceProtocol of the hub interface with one TT per port
+const HUB_PROTOCOL_MULTI_TT: u8 = 2;
+
+/// Connection debounce interval before a newly connected port is reset (tATTDB)
+const CONNECT_DEBOUNCE_MS: u64 = 100;
+
+/// Added to the hub's bPwrOn2PwrGood wait, some hubs report too short a time
+const POWER_GOOD_MARGIN_MS: u64 = 10;
+/// Consecutive failed polls after which a hub is given up on
+const MAX_POLL_FAILURES: u8 = 3;
 
 pub(crate) struct Hub {
     pub(crate) handle: DeviceHandle,
     interrupt_channel: InterruptChannel,
+    /// Bytes of the status change bitmap, one bit per port plus the hub
+    status_change_len: usize,
+    device_removable: DeviceRemovable,
+    characteristics: HubCharacteristics,
+    /// Ports the host started a resume on, a resume of any other port is a
+    /// remote wakeup
+    resuming: PortChangeBitmask,
+    /// Ports that connected while another port was enumerated, reset one by
+    /// one once the enumeration finished
+    pending_connect: PortChangeBitmask,
+    /// Polls failed in a row, reset by every successful poll
+    poll_failures: u8,
 }
 
 pub(crate) enum HubEvent {
-    DeviceReset,
+    /// The port is being reset, the device on it is enumerated once the reset ends
+    DeviceReset(PortInfo),
     DeviceAttach(DevInfo),
     DeviceDetach(PortInfo),
+    OverCurrent(PortInfo),
+    /// The device on the port resumed itself from selective suspend
+    RemoteWakeup(PortInfo),
+    /// Polling the hub on the port failed repeatedly, it is treated as detached
+    Failed(PortInfo),
 }
 
 impl Hub {
+    /// Polling interval of the status change endpoint
+    pub(crate) fn interval(&self) -> Duration {
+        self.interrupt_channel.interval()
+    }
+
     pub async fn new<D: HostDriver, const NR_DEVICES: usize>(
         pipe: &USBHostPipe<D, NR_DEVICES>,
         handle: DeviceHandle,
         _descriptor: DeviceDescriptor, // TODO: maybe check if this is a hub?
     ) -> Result<Self, UsbHostError> {
         // Pull uConfiguraiton Descriptor
-        let mut buf: [u8; 255] = [0; 255];
-        let desc_iter = get_configuration_descriptor(handle, &mut buf, pipe).await?;
+        let mut buf = pipe.descriptor_buffer().await;
+        let desc_iter = get_configuration_descriptor(handle, 0, &mut buf[..], pipe).await?;
 
-        let mut endpoint_address = None;
+        let mut interrupt_channel = None;
+        // Interface and alternate setting of the multi TT hub interface
+        let mut multi_tt = None;
         for desc in desc_iter {
             match desc? {
                 Descriptor::Configuration(cfg) => {
-                    pipe.control_transfer(handle, &Request::set_configuration(cfg.value), &mut [])
-                        .await?;
+                    pipe.set_configuration(handle, cfg.value).await?;
                     debug!("found hub configuration: {:?}", cfg);
                 }
+                Descriptor::Interface(interface_descriptor) => {
+                    if interface_descriptor.b_interface_protocol == HUB_PROTOCOL_MULTI_TT {
+                        multi_tt = Some((
+                            interface_descriptor.b_interface_number,
+                            interface_descriptor.b_alternate_setting,
+                        ));
+                    }
+                }
                 Descriptor::Endpoint(endpoint_descriptor) => {
-                    assert!(endpoint_address.is_none()); // TODO: this happens on the Anker hub
-                    endpoint_address = Some(endpoint_descriptor.into());
+                    // The status change endpoint is the interrupt IN endpoint. Multi TT
+                    // hubs repeat it in an alternate setting, some hubs expose
+                    // additional endpoints, only the first one is used.
+                    match InterruptChannel::from_descriptor(handle, endpoint_descriptor) {
+                        Some(channel)
+                            if interrupt_channel.is_none()
+                                && endpoint_descriptor.direction() == EndpointDirection::In =>
+                        {
+                            interrupt_channel = Some(channel);
+                        }
+                        _ => debug!(
+                            "ignoring hub endpoint {:#x}",
+                            endpoint_descriptor.b_endpoint_address
+                        ),
+                    }
                 }
                 _ => continue, // skip other descriptors
             }
         }
-        // set config
+        // Multi TT hubs start with a single TT, the alternate setting gives
+        // every port its own TT. The TT is picked by the port number in the
+        // SPLIT token, which is already part of the device's DevInfo.
+        if let Some((interface, alternate_setting)) = multi_tt {
+            debug!(
+                "selecting multi TT interface {} alt {}",
+                interface, alternate_setting
+            );
+            pipe.set_interface(handle, interface, alternate_setting)
+                .await?;
+        }
 
-        let mut hub_desc = HubDescriptor::default();
-        let hub_desc_buf = unsafe {
-            core::slice::from_raw_parts_mut(
-                &raw mut hub_desc as *mut u8,
-                core::mem::size_of::<HubDescriptor>(),
-            )
+        // Bus-powered hubs share their own unit loads between the ports
+        let self_powered = pipe.get_device_status(handle).await?.self_powered;
+        let port_current = if self_powered {
+            HIGH_POWER_PORT_MA
+        } else {
+            LOW_POWER_PORT_MA
         };
-        pipe.control_transfer(
-            handle,
-            &Request::get_descriptor(
-                0x29, // Hub Descriptor
-                RequestTypeType::Class,
-                0,
-                0,
-                hub_desc_buf.len() as u16,
-            ),
-            hub_desc_buf,
-        )
-        .await?;
+        pipe.set_hub_port_current(handle, port_current).await;
+
+        let mut hub_desc_buf = [0u8; MAX_HUB_DESCRIPTOR_SIZE];
+        let hub_desc_len = pipe
+            .control_transfer(
+                handle,
+                &Request::get_descriptor(
+                    0x29, // Hub Descriptor
+                    RequestTypeType::Class,
+                    0,
+                    0,
+                    hub_desc_buf.len() as u16,
+                ),
+                &mut hub_desc_buf,
+            )
+            .await?;
+        if hub_desc_len < core::mem::size_of::<HubDescriptor>() {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        // SAFETY: HubDescriptor is packed, the length is checked above
+        let hub_desc: HubDescriptor =
+            unsafe { core::ptr::read_unaligned(hub_desc_buf.as_ptr() as *const HubDescriptor) };
+
+        // Ports are treated as removable unless the hub says otherwise
+        let mut device_removable = DeviceRemovable::ZERO;
+        let removable_start = core::mem::size_of::<HubDescriptor>();
+        let removable_end = removable_start + hub_desc.device_removable_len();
+        if removable_end <= hub_desc_len {
+            device_removable.as_raw_mut_slice()[..hub_desc.device_removable_len()]
+                .copy_from_slice(&hub_desc_buf[removable_start..removable_end]);
+        } else {
+            warn!(
+                "hub descriptor too short for DeviceRemovable: {}",
+                hub_desc_len
+            );
+        }
 
         debug!("hub descriptor: {:?}", hub_desc);
+        let characteristics = hub_desc.characteristics();
+        debug!("hub characteristics: {:?}", characteristics);
 
         // enable ports
         for port in 1..=hub_desc.number_of_ports {
@@ -91,6 +189,10 @@ impl Hub {
             .await?;
         }
 
+        // Wait for power to be good on the ports before reading their status
+        Timer::after_millis(hub_desc.power_on_to_power_good_time as u64 * 2 + POWER_GOOD_MARGIN_MS)
+            .await;
+
         for port in 1..=hub_desc.number_of_ports {
             let mut port_status = [0u8; 4];
             pipe.control_transfer(
@@ -107,15 +209,18 @@ impl Hub {
             .await?;
         }
 
-        let endpoint_address = endpoint_address.ok_or(UsbHostError::InvalidResponse)?;
+        let interrupt_channel = interrupt_channel.ok_or(UsbHostError::InvalidResponse)?;
 
         let mut hub = Hub {
             handle,
-            interrupt_channel: InterruptChannel {
-                device_handle: handle,
-                endpoint_address,
-                tog: DataTog::DATA0,
-            },
+            interrupt_channel,
+            // Same layout as DeviceRemovable
+            status_change_len: hub_desc.device_removable_len(),
+            device_removable,
+            characteristics,
+            resuming: BitArray::ZERO,
+            pending_connect: BitArray::ZERO,
+            poll_failures: 0,
         };
 
         // Port number are 1 based
@@ -139,6 +244,52 @@ impl Hub {
         Ok(hub)
     }
 
+    /// `port` (1 based) of this hub, as the devices on it record their parent port
+    fn port_info(&self, port: u8) -> PortInfo {
+        PortInfo::new(0x80 | self.handle.address(), port)
+    }
+
+    /// Whether the device on `port` (1 based) can be unplugged, false for
+    /// devices built into a compound hub
+    pub fn port_removable(&self, port: u8) -> bool {
+        !self.characteristics.is_compound_device() || !self.device_removable[port as usize]
+    }
+
+    /// Selectively suspends the device on `port`
+    pub async fn suspend_port<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        port: u8,
+    ) -> Result<(), UsbHostError> {
+        self.set_port_feature(pipe, port, HubPortFeature::Suspend)
+            .await
+    }
+
+    /// Starts resume signaling on `port`, the hub reports the end of the
+    /// resume with C_PORT_SUSPEND
+    pub async fn resume_port<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        port: u8,
+    ) -> Result<(), UsbHostError> {
+        self.clear_port_feature(pipe, port, HubPortFeature::Suspend)
+            .await?;
+        self.resuming.set(port as usize, true);
+        Ok(())
+    }
+
+    /// Resets the device on `port` again, the end of the reset is reported
+    /// like the reset of a newly connected device
+    pub async fn reset_port<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        port: u8,
+    ) -> Result<(), UsbHostError> {
+        trace!("Resetting port {} on hub {}", port, self.handle.address());
+        self.set_port_feature(pipe, port, HubPortFeature::Reset)
+            .await
+    }
+
     async fn clear_port_feature<D: HostDriver, const NR_DEVICES: usize>(
         &mut self,
         pipe: &USBHostPipe<D, NR_DEVICES>,
@@ -202,23 +353,50 @@ impl Hub {
             )
             .await
         {
+            Ok(4) => Ok((
+                u16::from_le_bytes([status_buf[0], status_buf[1]]).into(),
+                u16::from_le_bytes([status_buf[2], status_buf[3]]).into(),
+            )),
             Ok(len) => {
-                assert_eq!(len, 4);
-                Ok((
-                    u16::from_le_bytes([status_buf[0], status_buf[1]]).into(),
-                    u16::from_le_bytes([status_buf[2], status_buf[3]]).into(),
-                ))
+                warn!("port {} status is {} bytes long", port, len);
+                Err(UsbHostError::InvalidResponse)
             }
-            Err(UsbHostError::BufferOverflow) => panic!("buffer overflow"),
             Err(e) => Err(e),
         }
     }
 
+    /// Resets the newly connected device on `port` once the connection is
+    /// stable, `None` if it is not
+    async fn connect_port<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        port: u8,
+    ) -> Result<Option<HubEvent>, UsbHostError> {
+        // Let the connection settle before the reset, a plug that is still
+        // moving sets C_PORT_CONNECTION again, which is left set so the port
+        // is handled on the next status change
+        Timer::after_millis(CONNECT_DEBOUNCE_MS).await;
+        let (status, change) = self.get_port_status(pipe, port).await?;
+        if !status.connected() || change.connection() {
+            debug!("port {} connection not stable", port);
+            return Ok(None);
+        }
+        trace!(
+            "Resetting port {} on hub {}, removable: {}",
+            port,
+            self.handle.address(),
+            self.port_removable(port)
+        );
+        self.set_port_feature(pipe, port, HubPortFeature::Reset)
+            .await?;
+        Ok(Some(HubEvent::DeviceReset(self.port_info(port))))
+    }
+
     async fn on_status_change<D: HostDriver, const NR_DEVICES: usize>(
         &mut self,
         pipe: &USBHostPipe<D, NR_DEVICES>,
         bitmask: &PortChangeBitmask,
-        enumeration_in_progress: bool,
+        enumerating: Option<PortInfo>,
     ) -> Result<Option<HubEvent>, UsbHostError> {
         // Poll port status
         for port in bitmask.iter_ones() {
@@ -228,35 +406,58 @@ impl Hub {
             if let Ok((status, change)) = self.get_port_status(pipe, port as u8).await {
                 debug!("port {} status: {:?}\n change: {:?}", port, status, change);
 
+                if change.over_current() {
+                    self.clear_port_feature(pipe, port as u8, HubPortFeature::ChangeOverCurrent)
+                        .await?;
+                    if status.over_current() {
+                        warn!(
+                            "port {} on hub {} over-current",
+                            port,
+                            self.handle.address()
+                        );
+                        return Ok(Some(HubEvent::OverCurrent(self.port_info(port as u8))));
+                    }
+                    // Over-current cleared, the hub may have switched the port off
+                    debug!("port {} over-current cleared", port);
+                    if !status.power() {
+                        self.set_port_feature(pipe, port as u8, HubPortFeature::Power)
+                            .await?;
+                    }
+                }
+
+                if change.suspend() {
+                    // Resume of the port finished
+                    self.clear_port_feature(pipe, port as u8, HubPortFeature::ChangeSuspend)
+                        .await?;
+                    debug!("port {} on hub {} resumed", port, self.handle.address());
+                    Timer::after(RESUME_RECOVERY).await;
+                    if !self.resuming.replace(port, false) && status.connected() {
+                        return Ok(Some(HubEvent::RemoteWakeup(self.port_info(port as u8))));
+                    }
+                }
+
                 if change.connection() {
+                    self.clear_port_feature(pipe, port as u8, HubPortFeature::ChangeConnection)
+                        .await?;
                     if status.connected() {
-                        if !enumeration_in_progress {
-                            self.clear_port_feature(
-                                pipe,
-                                port as u8,
-                                HubPortFeature::ChangeConnection,
-                            )
-                            .await?;
-                            trace!("Resetting port {} on hub {}", port, self.handle.address());
-                            unwrap!(
-                                self.set_port_feature(pipe, port as u8, HubPortFeature::Reset)
-                                    .await
-                            );
-                            return Ok(Some(HubEvent::DeviceReset));
+                        if enumerating.is_none() {
+                            match self.connect_port(pipe, port as u8).await? {
+                                Some(event) => return Ok(Some(event)),
+                                None => continue,
+                            }
                         }
-                        // Enumeration in progress, wait for current enumeration to finish
+                        // Only one device can be at address 0, reset the port
+                        // once the current enumeration finished
+                        debug!("port {} connected during enumeration, queued", port);
+                        self.pending_connect.set(port, true);
                     } else {
-                        self.clear_port_feature(pipe, port as u8, HubPortFeature::ChangeConnection)
-                            .await?;
-                        return Ok(Some(HubEvent::DeviceDetach(PortInfo::new(
-                            self.handle.address(),
-                            port as u8,
-                        ))));
+                        self.pending_connect.set(port, false);
+                        return Ok(Some(HubEvent::DeviceDetach(self.port_info(port as u8))));
                     }
                 }
 
                 if change.reset() {
-                    if !enumeration_in_progress {
+                    if enumerating != Some(self.port_info(port as u8)) {
                         error!(
                             "port {} reset changed, but currently not in enumeration",
                             port
@@ -267,20 +468,31 @@ impl Hub {
                             .await
                     );
                     if !status.reset() {
-                        let tt = match (self.handle.dev_info().speed(), status.speed()) {
-                            (UsbSpeed::HighSpeed, UsbSpeed::FullSpeed | UsbSpeed::LowSpeed) => {
-                                // Hub is the TT for this device
-                                Some((self.handle.address(), port as u8))
-                            }
-                            (_, _) => {
-                                // device has the same TT as the hub.
-                                self.handle.dev_info().transaction_translator()
-                            }
-                        };
+                        // The hub ended the reset, give the device its recovery time
+                        // before it is addressed
+                        Timer::after(RESET_RECOVERY).await;
+                        let (tt, tt_think_time) =
+                            match (self.handle.dev_info().speed(), status.speed()) {
+                                (UsbSpeed::HighSpeed, UsbSpeed::FullSpeed | UsbSpeed::LowSpeed) => {
+                                    // Hub is the TT for this device
+                                    (
+                                        Some((self.handle.address(), port as u8)),
+                                        self.characteristics.tt_think_time(),
+                                    )
+                                }
+                                (_, _) => {
+                                    // device has the same TT as the hub.
+                                    (
+                                        self.handle.dev_info().transaction_translator(),
+                                        self.handle.dev_info().tt_think_time(),
+                                    )
+                                }
+                            };
                         return Ok(Some(HubEvent::DeviceAttach(DevInfo::new(
                             self.handle.address(),
                             port as u8,
                             tt,
+                            tt_think_time,
                             status.speed(),
                         ))));
                     } else {
@@ -293,22 +505,63 @@ impl Hub {
     }
 
     // Main deal
+    /// Polls the hub for port changes.
+    ///
+    /// Errors are returned as is until [`MAX_POLL_FAILURES`] polls failed in a
+    /// row, then the hub reports [`HubEvent::Failed`] instead.
     pub async fn poll<D: HostDriver, const NR_DEVICES: usize>(
         &mut self,
         pipe: &USBHostPipe<D, NR_DEVICES>,
-        enumeration_in_progress: bool,
+        enumerating: Option<PortInfo>,
+    ) -> Result<Option<HubEvent>, UsbHostError> {
+        match self.poll_once(pipe, enumerating).await {
+            Ok(event) => {
+                self.poll_failures = 0;
+                Ok(event)
+            }
+            Err(e) => {
+                self.poll_failures = self.poll_failures.saturating_add(1);
+                warn!(
+                    "hub {} poll failed ({}/{}): {:?}",
+                    self.handle.address(),
+                    self.poll_failures,
+                    MAX_POLL_FAILURES,
+                    e
+                );
+                if self.poll_failures >= MAX_POLL_FAILURES {
+                    return Ok(Some(HubEvent::Failed(self.handle.dev_info().port())));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn poll_once<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        enumerating: Option<PortInfo>,
     ) -> Result<Option<HubEvent>, UsbHostError> {
+        if enumerating.is_none() {
+            if let Some(port) = self.pending_connect.first_one() {
+                self.pending_connect.set(port, false);
+                if let Some(event) = self.connect_port(pipe, port as u8).await? {
+                    return Ok(Some(event));
+                }
+            }
+        }
+
         // interrupt transfer with pipe
         let mut in_buf: PortChangeBitmask = BitArray::ZERO;
         let in_buf_len = pipe
-            .interrupt_transfer(&mut self.interrupt_channel, in_buf.as_raw_mut_slice())
+            .interrupt_transfer(
+                &mut self.interrupt_channel,
+                &mut in_buf.as_raw_mut_slice()[..self.status_change_len],
+            )
             .await;
         match in_buf_len {
-            Ok(len) => {
-                assert!(len > 0);
-                self.on_status_change(pipe, &in_buf, enumeration_in_progress)
-                    .await
-            }
+            // Some hubs answer with an empty report when nothing changed
+            Ok(0) => Ok(None),
+            Ok(_) => self.on_status_change(pipe, &in_buf, enumerating).await,
             Err(UsbHostError::NAK) => Ok(None),
             Err(e) => {
                 error!("interrupt transfer error: {:?}", e);
@@ -317,3 +570,27 @@ impl Hub {
         }
     }
 }
+
+#[cfg(test)]
+impl Hub {
+    /// Hub with `ports` downstream ports and its status change endpoint at 1,
+    /// as if it had been enumerated
+    pub(crate) fn mock(handle: DeviceHandle, ports: u8) -> Self {
+        Self {
+            handle,
+            interrupt_channel: InterruptChannel::new(
+                handle,
+                crate::types::EndpointAddress {
+                    number: 1,
+                    direction: EndpointDirection::In,
+                },
+            ),
+            status_change_len: ports as usize / 8 + 1,
+            device_removable: BitArray::ZERO,
+            characteristics: HubCharacteristics::default(),
+            resuming: BitArray::ZERO,
+            pending_connect: BitArray::ZERO,
+            poll_failures: 0,
+        }
+    }
+}
@@ -1,24 +1,227 @@
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use arrayvec::ArrayVec;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::{Channel, Receiver},
+};
 use embassy_time::Timer;
 
 use crate::{
-    descriptor::{DeviceDescriptor, ParsingError},
-    driver::get_configuration_descriptor,
+    consts::UsbBaseClass,
+    descriptor::{
+        hid::{ReportDescriptorParser, ReportField},
+        DeviceDescriptor,
+    },
     errors::UsbHostError,
     pipe::USBHostPipe,
-    request::{Request, RequestType, RequestTypeDirection, RequestTypeRecipient, RequestTypeType},
-    types::{DataTog, EndpointAddress, EndpointDirection, InterruptChannel},
+    request::{
+        hid::{HidProtocol, HidReportType},
+        Request,
+    },
+    types::InterruptChannel,
     DeviceHandle, HostDriver,
 };
 
-use super::USBHostDeviceDriver;
+use super::{attach_hid_interface, USBHostDeviceDriver};
+
+/// Boot keyboard LED output report, one bit per LED
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct KeyboardLeds(pub u8);
+
+impl KeyboardLeds {
+    pub const NUM_LOCK: u8 = 0b00000001;
+    pub const CAPS_LOCK: u8 = 0b00000010;
+    pub const SCROLL_LOCK: u8 = 0b00000100;
+    pub const COMPOSE: u8 = 0b00001000;
+    pub const KANA: u8 = 0b00010000;
+
+    pub fn is_set(&self, led: u8) -> bool {
+        self.0 & led != 0
+    }
+
+    pub fn set(&mut self, led: u8, on: bool) {
+        if on {
+            self.0 |= led;
+        } else {
+            self.0 &= !led;
+        }
+    }
+
+    pub fn toggle(&mut self, led: u8) {
+        self.0 ^= led;
+    }
+}
+
+/// Key press or release decoded from boot keyboard reports
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct KeyEvent {
+    /// HID usage ID, modifier keys are reported as 0xE0 (left CTRL) to 0xE7 (right GUI)
+    pub key: u8,
+    pub pressed: bool,
+    /// Modifier byte of the report the event was decoded from
+    pub modifiers: u8,
+}
+
+const KEY_CHANNEL_SIZE: usize = 16;
+/// Keyboards that can have an LED request pending at the same time
+const MAX_LED_REQUESTS: usize = 4;
+/// Usage ID of the first modifier key, left CTRL
+const MODIFIER_KEY_BASE: u8 = 0xE0;
+/// Usage ID reported in every key slot when too many keys are pressed
+const KEY_ERROR_ROLL_OVER: u8 = 0x01;
+const KEY_ERROR_ROLL_OVER_USAGE: u16 = KEY_ERROR_ROLL_OVER as u16;
+
+/// Boot protocol report: modifiers, reserved, 6 keys
+const BOOT_REPORT_SIZE: usize = 8;
+/// Largest report read in report protocol, longer reports are truncated
+const MAX_REPORT_SIZE: usize = 64;
+/// Runs of keyboard page input fields kept from the report descriptor, the
+/// modifiers, key array or NKRO bitmap are one run each
+const MAX_KEY_FIELDS: usize = 8;
+/// Usage page of keyboard keys
+const USAGE_PAGE_KEYBOARD: u16 = 0x07;
+
+/// Protocol sent with SET_PROTOCOL to newly attached keyboards, boot by default
+static DEFAULT_PROTOCOL: AtomicU8 = AtomicU8::new(HidProtocol::Boot as u8);
+/// LED states requested with [`HidKbd::request_leds`] and not sent yet, the
+/// latest one per keyboard
+static LED_REQUESTS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<ArrayVec<(DeviceHandle, KeyboardLeds), MAX_LED_REQUESTS>>,
+> = Mutex::new(RefCell::new(ArrayVec::new_const()));
+static KEY_CHANNEL: Channel<CriticalSectionRawMutex, KeyEvent, KEY_CHANNEL_SIZE> = Channel::new();
+
+/// `count` adjacent keyboard page input fields of the same size. The usage
+/// of variable fields increases by one per field, array fields share theirs.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+struct KeyField {
+    first: ReportField,
+    count: u16,
+}
+
+impl KeyField {
+    /// Field `i` of the run
+    fn get(&self, i: u16) -> ReportField {
+        let mut field = self.first;
+        field.bit_offset += i as u32 * field.bit_size;
+        if field.is_variable() {
+            field.usage = field.usage.wrapping_add(i);
+        }
+        field
+    }
+
+    /// Appends `field` to the run if it directly follows it
+    fn try_extend(&mut self, field: &ReportField) -> bool {
+        let next = self.get(self.count);
+        let follows = field.report_id == next.report_id
+            && field.flags == next.flags
+            && field.bit_offset == next.bit_offset
+            && field.bit_size == next.bit_size
+            && field.usage == next.usage
+            && field.logical_minimum == next.logical_minimum
+            && field.logical_maximum == next.logical_maximum;
+        if follows {
+            self.count += 1;
+        }
+        follows
+    }
+}
 
 pub struct HidKbd {
     device: DeviceHandle,
+    interface: u8,
+    /// Protocol selected on attach, see [`HidKbd::set_default_protocol`]
+    protocol: HidProtocol,
+    /// Keyboard page input fields and whether reports carry a report ID, report protocol only
+    report_fields: Option<(ArrayVec<KeyField, MAX_KEY_FIELDS>, bool)>,
+    /// Bytes read from the interrupt endpoint per report
+    report_len: usize,
     interrupt_channel: Option<InterruptChannel>,
+    /// Interrupt OUT endpoint used for output reports, if the interface has one
+    out_channel: Option<InterruptChannel>,
 }
 
 impl HidKbd {
-    fn process_keyboard_report(report: &[u8]) {
+    /// Protocol requested from keyboards attached from now on.
+    ///
+    /// In report protocol reports are decoded with the report descriptor,
+    /// which e.g. allows more than 6 keys on NKRO keyboards. Keyboards without
+    /// a usable report descriptor fall back to the boot protocol.
+    pub fn set_default_protocol(protocol: HidProtocol) {
+        DEFAULT_PROTOCOL.store(protocol as u8, Ordering::Relaxed);
+    }
+
+    /// Protocol the keyboard was switched to on attach
+    pub fn protocol(&self) -> HidProtocol {
+        self.protocol
+    }
+
+    /// Key events from all attached keyboards, events are dropped when the channel is full
+    pub fn key_receiver() -> Receiver<'static, CriticalSectionRawMutex, KeyEvent, KEY_CHANNEL_SIZE>
+    {
+        KEY_CHANNEL.receiver()
+    }
+
+    /// Requests `leds` on the keyboard `device`, sent by its driver before
+    /// the next report poll. Replaces a request for the same keyboard that
+    /// wasn't sent yet. Returns false if requests for 4 other keyboards are
+    /// pending.
+    pub fn request_leds(device: DeviceHandle, leds: KeyboardLeds) -> bool {
+        LED_REQUESTS.lock(|requests| {
+            let mut requests = requests.borrow_mut();
+            if let Some(request) = requests.iter_mut().find(|(d, _)| *d == device) {
+                request.1 = leds;
+                return true;
+            }
+            requests.try_push((device, leds)).is_ok()
+        })
+    }
+
+    /// Takes the pending LED request for `device`
+    fn take_led_request(device: DeviceHandle) -> Option<KeyboardLeds> {
+        LED_REQUESTS.lock(|requests| {
+            let mut requests = requests.borrow_mut();
+            let idx = requests.iter().position(|(d, _)| *d == device)?;
+            Some(requests.swap_remove(idx).1)
+        })
+    }
+
+    /// Sends the LED output report, through the interrupt OUT endpoint when
+    /// present, otherwise with SET_REPORT on the control endpoint.
+    pub async fn set_leds<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        leds: KeyboardLeds,
+    ) -> Result<(), UsbHostError> {
+        let mut report = [leds.0];
+        match self.out_channel.as_mut() {
+            Some(out_channel) => pipe.interrupt_transfer(out_channel, &mut report).await?,
+            None => {
+                pipe.control_transfer(
+                    self.device,
+                    &Request::hid_set_report(self.interface, HidReportType::Output, 0, 1),
+                    &mut report,
+                )
+                .await?
+            }
+        };
+        trace!("keyboard LEDs set to {:02x}", leds.0);
+        Ok(())
+    }
+
+    /// Sends a [`KeyEvent`] for every key that changed between `prev` and
+    /// `report`. Returns false for roll over error reports, which are ignored.
+    fn process_keyboard_report(prev: &[u8; 8], report: &[u8; 8]) -> bool {
         // Standard HID keyboard report format:
         // Byte 0: Modifier keys (CTRL, SHIFT, ALT, etc.)
         // Byte 1: Reserved
@@ -28,51 +231,122 @@ impl HidKbd {
         let keys = &report[2..8];
 
         debug!(
-            "Keyboard report - modifiers: {:02x}, keys: {:02x}",
+            "Keyboard report - modifiers: {:02x}, keys: {:?}",
             modifiers, keys
         );
 
-        // Process modifier keys
-        if modifiers != 0 {
-            if modifiers & 0x01 != 0 {
-                debug!("Left CTRL pressed");
-            }
-            if modifiers & 0x02 != 0 {
-                debug!("Left SHIFT pressed");
+        if keys.iter().all(|key| *key == KEY_ERROR_ROLL_OVER) {
+            debug!("Keyboard roll over, report ignored");
+            return false;
+        }
+
+        let sender = KEY_CHANNEL.sender();
+        let send = |key: u8, pressed: bool| {
+            let event = KeyEvent {
+                key,
+                pressed,
+                modifiers,
+            };
+            if sender.try_send(event).is_err() {
+                trace!("key event dropped: {:?}", event);
             }
-            if modifiers & 0x04 != 0 {
-                debug!("Left ALT pressed");
+        };
+
+        let changed_modifiers = prev[0] ^ modifiers;
+        for bit in 0..8 {
+            if changed_modifiers & (1 << bit) != 0 {
+                send(MODIFIER_KEY_BASE + bit, modifiers & (1 << bit) != 0);
             }
-            if modifiers & 0x08 != 0 {
-                debug!("Left GUI pressed");
+        }
+
+        let prev_keys = &prev[2..8];
+        for key in prev_keys {
+            if *key != 0 && !keys.contains(key) {
+                send(*key, false);
             }
-            if modifiers & 0x10 != 0 {
-                debug!("Right CTRL pressed");
+        }
+        for key in keys {
+            if *key != 0 && !prev_keys.contains(key) {
+                send(*key, true);
             }
-            if modifiers & 0x20 != 0 {
-                debug!("Right SHIFT pressed");
+        }
+        true
+    }
+
+    /// Keyboard page input fields of a report descriptor, `None` if it declares none
+    fn keyboard_fields(
+        report_descriptor: &[u8],
+    ) -> Result<Option<(ArrayVec<KeyField, MAX_KEY_FIELDS>, bool)>, UsbHostError> {
+        let mut fields: ArrayVec<KeyField, MAX_KEY_FIELDS> = ArrayVec::new();
+        let mut report_ids = false;
+        for field in ReportDescriptorParser::new(report_descriptor) {
+            let field = field?;
+            report_ids |= field.report_id != 0;
+            if !field.is_input() || field.usage_page != USAGE_PAGE_KEYBOARD {
+                continue;
             }
-            if modifiers & 0x40 != 0 {
-                debug!("Right ALT pressed");
+            if fields.last_mut().is_some_and(|run| run.try_extend(&field)) {
+                continue;
             }
-            if modifiers & 0x80 != 0 {
-                debug!("Right GUI pressed");
+            if fields
+                .try_push(KeyField {
+                    first: field,
+                    count: 1,
+                })
+                .is_err()
+            {
+                warn!("more than {} keyboard fields", MAX_KEY_FIELDS);
             }
         }
+        Ok((!fields.is_empty()).then_some((fields, report_ids)))
+    }
 
-        // Process pressed keys
-        for key in keys {
-            if *key != 0 {
-                debug!(
-                    "Key pressed: {:02x} ({})",
-                    key,
-                    Self::hid_key_to_ascii(*key)
-                );
+    /// Translates a report protocol report into the boot report layout, `None`
+    /// if the report holds no keyboard fields. More than 6 keys are reported
+    /// as roll over.
+    fn to_boot_report(&self, report: &[u8]) -> Option<[u8; BOOT_REPORT_SIZE]> {
+        let (fields, report_ids) = self.report_fields.as_ref()?;
+        let (report_id, report) = match (report_ids, report) {
+            (true, [report_id, report @ ..]) => (*report_id, report),
+            (false, report) => (0, report),
+            (true, []) => return None,
+        };
+
+        let mut boot_report = [0u8; BOOT_REPORT_SIZE];
+        let mut keys = 0;
+        let mut found = false;
+        let fields = fields
+            .iter()
+            .filter(|run| run.first.report_id == report_id)
+            .flat_map(|run| (0..run.count).map(|i| run.get(i)));
+        for field in fields {
+            found = true;
+            let Some(value) = field.extract_signed(report) else {
+                continue;
+            };
+            let usage = if field.is_variable() {
+                (value != 0).then_some(field.usage)
+            } else {
+                field.array_usage(value)
+            };
+            match usage {
+                Some(usage @ 0xE0..=0xE7) => boot_report[0] |= 1 << (usage - 0xE0),
+                // 0 is no key, 1 to 3 are the error codes
+                Some(usage @ 0x04..=0xDF) if keys < 6 => {
+                    boot_report[2 + keys] = usage as u8;
+                    keys += 1;
+                }
+                Some(0x04..=0xDF | KEY_ERROR_ROLL_OVER_USAGE) => {
+                    boot_report[2..].fill(KEY_ERROR_ROLL_OVER);
+                }
+                _ => {}
             }
         }
+        found.then_some(boot_report)
     }
 
-    fn hid_key_to_ascii(key: u8) -> char {
+    /// Maps a HID usage ID to ASCII, ignoring modifiers. Unknown keys map to `?`.
+    pub fn hid_key_to_ascii(key: u8) -> char {
         // Simple mapping of common HID keyboard codes to ASCII
         // This is a basic implementation - a full implementation would handle
         // all keys and consider modifiers
@@ -103,118 +377,73 @@ impl HidKbd {
         &mut self,
         pipe: &USBHostPipe<D, NR_DEVICES>,
     ) -> Result<(), UsbHostError> {
-        // Pull Configuration Descriptor
-        let mut buf: [u8; 255] = [0; 255];
-
-        let config_iter = get_configuration_descriptor(self.device, &mut buf, pipe).await?;
-        let mut endpoint_address = None;
-
-        for desc in config_iter {
-            match desc? {
-                crate::descriptor::Descriptor::Device(_device_descriptor) => todo!(),
-                crate::descriptor::Descriptor::Configuration(configuration_descriptor) => {
-                    // Set configuration
-                    pipe.control_transfer(
-                        self.device,
-                        &crate::request::Request::set_configuration(configuration_descriptor.value),
-                        &mut [],
-                    )
-                    .await?;
-                    trace!("set configuration");
-                }
-                crate::descriptor::Descriptor::Endpoint(endpoint_descriptor) => {
-                    // TODO: handle multiple endpoints
-                    // For HID keyboard, we're looking for an IN interrupt endpoint
-                    if endpoint_address.is_none()
-                        && (endpoint_descriptor.b_endpoint_address & 0x80) != 0
-                    {
-                        endpoint_address = Some(endpoint_descriptor.b_endpoint_address);
-                    }
-                }
-                crate::descriptor::Descriptor::Interface(interface_descriptor) => {
-                    // Verify this is a HID keyboard interface (class 3, subclass 1, protocol 1)
-                    if interface_descriptor.b_interface_class == 0x03
-                        && interface_descriptor.b_interface_sub_class == 0x01
-                        && interface_descriptor.b_interface_protocol == 0x01
-                    {
-                        debug!("Found HID keyboard interface");
-                    } else {
-                        debug!("Found non-HID keyboard interface");
-                    }
-                }
-                crate::descriptor::Descriptor::UnknownDescriptor {
-                    descriptor_type,
-                    length: _,
-                    data,
-                } => {
-                    if descriptor_type == crate::descriptor::hid::HID_DESCRIPTOR_TYPE {
-                        if let Some(hid_desc) = crate::descriptor::hid::HIDDescriptor::parse(data) {
-                            trace!("Found HID descriptor: {:?}", hid_desc);
-                            continue;
-                        }
-                    }
+        let protocol = self.protocol;
+        // Only the boot keyboard interface is claimed, other interfaces of a
+        // composite device are left to their own drivers
+        let hid = attach_hid_interface(
+            pipe,
+            self.device,
+            |interface| {
+                interface.b_interface_sub_class == 0x01 && interface.b_interface_protocol == 0x01
+            },
+            |report_descriptor| match (protocol, report_descriptor) {
+                (HidProtocol::Report, Some(report_descriptor)) => {
+                    Self::keyboard_fields(report_descriptor).map(Some)
                 }
-            }
+                _ => Ok(Some(None)),
+            },
+        )
+        .await?;
+        debug!("Found HID keyboard interface {}", hid.interface);
+        self.interface = hid.interface;
+        self.report_fields = hid.report;
+        if self.protocol == HidProtocol::Report && self.report_fields.is_none() {
+            warn!("No usable report descriptor, using the boot protocol");
+            self.protocol = HidProtocol::Boot;
         }
 
-        // Send SET_IDLE request to disable automatic repeat
-        let set_idle_request = Request {
-            request_type: {
-                let mut r = RequestType::default();
-                r.set_data_direction(RequestTypeDirection::HostToDevice);
-                r.set_type(RequestTypeType::Class);
-                r.set_recipient(RequestTypeRecipient::Interface);
-                r
-            },
-            request: 0x0A, // SET_IDLE
-            value: 0,      // 0 = disable idle
-            index: 0,      // interface number
-            length: 0,
+        pipe.control_transfer(
+            self.device,
+            &Request::hid_set_protocol(self.interface, self.protocol),
+            &mut [],
+        )
+        .await?;
+        debug!("SET_PROTOCOL({:?}) sent", self.protocol);
+        self.report_len = match self.protocol {
+            HidProtocol::Boot => BOOT_REPORT_SIZE,
+            HidProtocol::Report => core::cmp::min(hid.max_packet_size as usize, MAX_REPORT_SIZE),
         };
 
-        pipe.control_transfer(self.device, &set_idle_request, &mut [])
-            .await?;
-        debug!("SET_IDLE request sent successfully");
-
-        if let Some(addr) = endpoint_address {
-            // Create an InterruptChannel instead of just storing the endpoint address
-            let endpoint = EndpointAddress {
-                number: addr & 0x0F,
-                direction: if (addr & 0x80) != 0 {
-                    EndpointDirection::In
-                } else {
-                    EndpointDirection::Out
-                },
-            };
-
-            self.interrupt_channel = Some(InterruptChannel {
-                device_handle: self.device,
-                endpoint_address: endpoint,
-                tog: DataTog::DATA0,
-            });
-
-            debug!("Using keyboard endpoint: {:?}", endpoint);
-            Ok(())
-        } else {
-            Err(UsbHostError::InvalidResponse)
+        debug!("Using keyboard endpoint: {:?}", hid.in_channel);
+        self.interrupt_channel = Some(hid.in_channel);
+        // Optional interrupt OUT endpoint for output reports (LEDs)
+        if let Some(out_channel) = hid.out_channel {
+            debug!("Using keyboard output endpoint: {:?}", out_channel);
+            self.out_channel = Some(out_channel);
         }
+        Ok(())
     }
 }
 
 impl USBHostDeviceDriver for HidKbd {
+    const CLASS: Option<u8> = Some(UsbBaseClass::HID as u8);
+
     async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
         pipe: &USBHostPipe<D, NR_DEVICES>,
         device: DeviceHandle,
-        desc: DeviceDescriptor,
+        _desc: DeviceDescriptor,
     ) -> Result<Self, UsbHostError> {
-        // HID use the interface class to declare their class
-        if desc.device_class != 0 {
-            return Err(UsbHostError::UnexpectedDevice);
-        }
-
         let mut kbd = Self {
             device,
+            interface: 0,
+            protocol: match DEFAULT_PROTOCOL.load(Ordering::Relaxed) {
+                1 => HidProtocol::Report,
+                _ => HidProtocol::Boot,
+            },
+            report_fields: None,
+            report_len: BOOT_REPORT_SIZE,
             interrupt_channel: None,
+            out_channel: None,
         };
 
         kbd.configure(pipe).await?;
@@ -223,32 +452,40 @@ impl USBHostDeviceDriver for HidKbd {
     }
 
     async fn run<'a, D: HostDriver, const NR_DEVICES: usize>(
-        self,
+        mut self,
         pipe: &'a USBHostPipe<D, NR_DEVICES>,
     ) -> Result<(), UsbHostError> {
-        let mut prev_report = [0u8; 8];
-        let mut buf = [0u8; 8]; // Standard HID keyboard report is 8 bytes
-
-        let Self {
-            device: _, // Mark device as unused for now
-            interrupt_channel,
-        } = self;
+        let mut prev_report = [0u8; BOOT_REPORT_SIZE];
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        let report_len = self.report_len;
 
         // Ensure we have an interrupt channel configured
-        let mut interrupt_channel = interrupt_channel.ok_or(UsbHostError::InvalidState)?;
+        let mut interrupt_channel = self
+            .interrupt_channel
+            .take()
+            .ok_or(UsbHostError::InvalidState)?;
 
         loop {
-            Timer::after_millis(10).await;
+            Timer::after(interrupt_channel.interval()).await;
+            if let Some(leds) = Self::take_led_request(self.device) {
+                self.set_leds(pipe, leds).await?;
+            }
             // Poll the interrupt endpoint for keyboard reports
             match pipe
-                .interrupt_transfer(&mut interrupt_channel, &mut buf)
+                .interrupt_transfer(&mut interrupt_channel, &mut buf[..report_len])
                 .await
             {
                 Ok(len) => {
-                    if len > 0 && buf != prev_report {
-                        // Process the keyboard report
-                        Self::process_keyboard_report(&buf);
-                        prev_report.copy_from_slice(&buf);
+                    let report = match self.protocol {
+                        HidProtocol::Boot => buf[..len].try_into().ok(),
+                        HidProtocol::Report => self.to_boot_report(&buf[..len]),
+                    };
+                    if let Some(report) = report {
+                        if report != prev_report
+                            && Self::process_keyboard_report(&prev_report, &report)
+                        {
+                            prev_report = report;
+                        }
                     }
                 }
                 Err(UsbHostError::NAK) => {
@@ -260,3 +497,87 @@ impl USBHostDeviceDriver for HidKbd {
         }
     }
 }
+
+impl Drop for HidKbd {
+    fn drop(&mut self) {
+        // Detached, the address may go to another device
+        Self::take_led_request(self.device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device_addr::DeviceAddressManager,
+        types::{DevInfo, UsbSpeed},
+    };
+
+    #[test]
+    fn led_requests_are_kept_per_keyboard() {
+        let mut addresses = DeviceAddressManager::<4>::new();
+        let hub = addresses
+            .alloc_device_address(64, DevInfo::root_device(UsbSpeed::FullSpeed))
+            .unwrap();
+        let [first, second] = [1, 2].map(|port| {
+            let dev_info = DevInfo::new(hub.address(), port, None, 0, UsbSpeed::FullSpeed);
+            addresses.alloc_device_address(8, dev_info).unwrap()
+        });
+
+        let [num, caps, scroll] = [
+            KeyboardLeds::NUM_LOCK,
+            KeyboardLeds::CAPS_LOCK,
+            KeyboardLeds::SCROLL_LOCK,
+        ]
+        .map(KeyboardLeds);
+        assert!(HidKbd::request_leds(first, num));
+        assert!(HidKbd::request_leds(second, caps));
+        // The latest request replaces the one not sent yet
+        assert!(HidKbd::request_leds(first, scroll));
+
+        assert!(HidKbd::take_led_request(second) == Some(caps));
+        assert!(HidKbd::take_led_request(second).is_none());
+        assert!(HidKbd::take_led_request(first) == Some(scroll));
+    }
+
+    #[test]
+    fn adjacent_key_fields_are_merged() {
+        #[rustfmt::skip]
+        let nkro = [
+            0x05, 0x01, 0x09, 0x06, 0xA1, 0x01,
+            // Modifiers
+            0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01,
+            0x75, 0x01, 0x95, 0x08, 0x81, 0x02,
+            // One bit per key from 0x04 to 0x73
+            0x19, 0x04, 0x29, 0x73, 0x95, 0x70, 0x81, 0x02,
+            0xC0,
+        ];
+        let (fields, report_ids) = HidKbd::keyboard_fields(&nkro).unwrap().unwrap();
+        assert!(!report_ids);
+        let counts: Vec<_> = fields.iter().map(|run| run.count).collect();
+        assert_eq!(counts, [8, 0x70]);
+
+        let mut addresses = DeviceAddressManager::<4>::new();
+        let device = addresses
+            .alloc_device_address(8, DevInfo::root_device(UsbSpeed::FullSpeed))
+            .unwrap();
+        let kbd = HidKbd {
+            device,
+            interface: 0,
+            protocol: HidProtocol::Report,
+            report_fields: Some((fields, report_ids)),
+            report_len: MAX_REPORT_SIZE,
+            interrupt_channel: None,
+            out_channel: None,
+        };
+        // Left shift, 'a' (0x04) and 'z' (0x1D)
+        let mut report = [0u8; 15];
+        report[0] = 0b10;
+        report[1] = 0b1;
+        report[1 + (0x1D - 0x04) / 8] |= 1 << ((0x1D - 0x04) % 8);
+        assert_eq!(
+            kbd.to_boot_report(&report),
+            Some([0b10, 0, 0x04, 0x1D, 0, 0, 0, 0])
+        );
+    }
+}
@@ -1,31 +1,86 @@
 /// USB Hub class driver, private because it is only used by the main driver.
 ///
-use core::{error, future::Future, marker::PhantomData, pin::pin};
+use core::{
+    error,
+    future::Future,
+    iter::Peekable,
+    marker::PhantomData,
+    pin::{pin, Pin},
+};
 
 use crate::{
-    descriptor::{Descriptor, DescriptorIterator, DeviceDescriptor},
+    consts::UsbBaseClass,
+    descriptor::{
+        hid::{HIDDescriptor, HID_DESCRIPTOR_TYPE},
+        Descriptor, DescriptorIterator, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor,
+    },
+    device_addr::DeviceDisconnectMask,
     driver::kbd::HidKbd,
     errors::UsbHostError,
     futures::StaticUnpinPoller,
     pipe::USBHostPipe,
+    request::Request,
+    types::{DeviceInterfaces, EndpointDirection, InterruptChannel},
     DeviceHandle, HostDriver,
 };
-use embassy_futures::select::{select, Either};
+use arrayvec::ArrayVec;
+use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 
+pub mod cdc_acm;
+pub mod composite;
+pub mod consumer;
 pub mod dfu;
+pub mod gamepad;
 pub(crate) mod hub;
 pub mod kbd;
+pub mod mouse;
+pub mod msc;
+pub mod uvc;
 
 pub type DeviceChannel = Channel<CriticalSectionRawMutex, (DeviceHandle, DeviceDescriptor), 1>;
+pub type DetachChannel = Channel<CriticalSectionRawMutex, DeviceDisconnectMask, 1>;
 
 #[allow(async_fn_in_trait)]
 pub trait USBHostDeviceDriver: Sized {
+    /// Class of the device or one of its interfaces, checked by [`Self::matches`]
     const CLASS: Option<u8> = None;
+    /// Subclass of the device or interface matching [`Self::CLASS`]
     const SUBCLASS: Option<u8> = None;
     const VENDOR: Option<u16> = None;
     const PRODUCT: Option<u16> = None;
 
+    /// Whether the driver may handle the device, [`Self::attach`] skips
+    /// [`Self::try_attach`] if not. By default `VENDOR` and `PRODUCT` have to
+    /// match the device, `CLASS` and `SUBCLASS` the device or one of its
+    /// interfaces.
+    fn matches(desc: &DeviceDescriptor, interfaces: &DeviceInterfaces) -> bool {
+        let class = |class: u8, subclass: u8| {
+            Self::CLASS.is_none_or(|c| c == class) && Self::SUBCLASS.is_none_or(|s| s == subclass)
+        };
+        Self::VENDOR.is_none_or(|vendor| vendor == desc.id_vendor)
+            && Self::PRODUCT.is_none_or(|product| product == desc.id_product)
+            && (class(desc.device_class, desc.device_sub_class)
+                || interfaces
+                    .iter()
+                    .any(|interface| class(interface.class, interface.subclass)))
+    }
+
+    /// [`Self::try_attach`] if the device [`matches`](Self::matches),
+    /// `UnexpectedDevice` otherwise
+    async fn attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        // Answered from the configuration cache, filled by the enumeration
+        let interfaces = pipe.device_interfaces(device).await.unwrap_or_default();
+        if !Self::matches(&desc, &interfaces) {
+            return Err(UsbHostError::UnexpectedDevice);
+        }
+        Self::try_attach(pipe, device, desc).await
+    }
+
     async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
         pipe: &USBHostPipe<D, NR_DEVICES>,
         device: DeviceHandle,
@@ -38,6 +93,9 @@ pub trait USBHostDeviceDriver: Sized {
     ) -> Result<(), UsbHostError>;
 }
 
+/// Offers every new device to `HDD` and runs it while attached.
+///
+/// Use [`composite::Composite`] as `HDD` to register more than one driver.
 pub struct USBDeviceDispatcher<
     'a,
     HDD: USBHostDeviceDriver,
@@ -46,6 +104,7 @@ pub struct USBDeviceDispatcher<
 > {
     pipe: &'a USBHostPipe<HD, NR_DEVICES>,
     new_dev: DeviceChannel,
+    removed_dev: DetachChannel,
     _phantom: PhantomData<HDD>,
 }
 
@@ -56,49 +115,72 @@ impl<'a, HDD: USBHostDeviceDriver, HD: HostDriver, const NR_DEVICES: usize>
         Self {
             pipe,
             new_dev: DeviceChannel::new(),
+            removed_dev: DetachChannel::new(),
             _phantom: PhantomData,
         }
     }
 
     pub fn run<'b>(&'b self) -> impl Future<Output = ()> + use<'a, 'b, HDD, HD, NR_DEVICES> {
-        Self::run_inner(self.pipe, &self.new_dev)
+        Self::run_inner(self.pipe, &self.new_dev, &self.removed_dev)
     }
 
     pub async fn insert_new_device(&self, device: DeviceHandle, descriptor: DeviceDescriptor) {
         self.new_dev.send((device, descriptor)).await;
     }
 
-    async fn run_inner<'b>(pipe: &'a USBHostPipe<HD, NR_DEVICES>, new_dev: &'b DeviceChannel) {
+    /// Drops the drivers of all devices in `mask`, as reported by `HostEvent::DeviceDetach`
+    pub async fn remove_device(&self, mask: &DeviceDisconnectMask) {
+        self.removed_dev.send(mask.clone()).await;
+    }
+
+    async fn run_inner<'b>(
+        pipe: &'a USBHostPipe<HD, NR_DEVICES>,
+        new_dev: &'b DeviceChannel,
+        removed_dev: &'b DetachChannel,
+    ) {
         let poller = StaticUnpinPoller::<_, NR_DEVICES>::new();
         let mut poller = pin!(poller);
+        // Address of the device driven by the future in each poller slot
+        let mut slots: [Option<u8>; NR_DEVICES] = [None; NR_DEVICES];
 
         loop {
             let new_dev_fut = new_dev.receive();
+            let removed_dev_fut = removed_dev.receive();
             let (device, descriptor) = if poller.as_mut().is_empty() {
-                new_dev_fut.await
-            } else {
-                match select(new_dev_fut, poller.as_mut()).await {
+                match select(new_dev_fut, removed_dev_fut).await {
                     Either::First((device, descriptor)) => (device, descriptor),
-                    Either::Second(Some((idx, result))) => {
+                    // No driver running, nothing to remove
+                    Either::Second(_) => continue,
+                }
+            } else {
+                match select3(new_dev_fut, removed_dev_fut, poller.as_mut()).await {
+                    Either3::First((device, descriptor)) => (device, descriptor),
+                    Either3::Second(mask) => {
+                        remove_slots(poller.as_mut(), &mut slots, &mask);
+                        continue;
+                    }
+                    Either3::Third(Some((idx, result))) => {
                         match result {
                             Ok(_) => {
                                 trace!("Device at slot {} completed successfully", idx);
                             }
                             Err(e) => error!("Device error at slot {}: {}", idx, e),
                         }
+                        slots[idx] = None;
                         continue;
                     }
-                    Either::Second(None) => {
+                    Either3::Third(None) => {
                         continue;
                     }
                 }
             };
-            let hdd = HDD::try_attach(pipe, device, descriptor).await;
+            let hdd = HDD::attach(pipe, device, descriptor).await;
             match hdd {
                 Ok(hdd) => {
                     // Find an empty slot for the new device
-                    if let Err(e) = poller.as_mut().insert(hdd.run(pipe)) {
-                        error!("No empty slots available for new device: {}", e);
+                    match poller.as_mut().insert(hdd.run(pipe)) {
+                        Ok(idx) => slots[idx] = Some(device.address()),
+                        Err(e) => error!("No empty slots available for new device: {}", e),
                     }
                 }
                 Err(e) => {
@@ -109,28 +191,50 @@ impl<'a, HDD: USBHostDeviceDriver, HD: HostDriver, const NR_DEVICES: usize>
     }
 }
 
+/// Drops the futures of the slots whose device address is in `mask`
+fn remove_slots<F: Future, const N: usize>(
+    mut poller: Pin<&mut StaticUnpinPoller<F, N>>,
+    slots: &mut [Option<u8>; N],
+    mask: &DeviceDisconnectMask,
+) {
+    for (idx, slot) in slots.iter_mut().enumerate() {
+        if slot.is_some_and(|addr| mask.contains(addr)) {
+            trace!("Removing detached device {} at slot {}", slot, idx);
+            if let Err(e) = poller.as_mut().remove(idx) {
+                error!("Failed to remove slot {}: {}", idx, e);
+            }
+            *slot = None;
+        }
+    }
+}
+
+/// Reads the configuration descriptor at `config_index` and everything following it
+///
+/// `config_index` ranges from 0 to `DeviceDescriptor::num_configurations - 1`, it is
+/// not the `bConfigurationValue` passed to SET_CONFIGURATION. The descriptors are
+/// validated against the interface and endpoint counts, see
+/// [`DescriptorIterator::validating`].
 pub async fn get_configuration_descriptor<'a, HD: HostDriver, const NR_DEVICES: usize>(
     device_handle: DeviceHandle,
+    config_index: u8,
     buf: &'a mut [u8],
     pipe: &USBHostPipe<HD, NR_DEVICES>,
 ) -> Result<impl Iterator<Item = Result<Descriptor<'a>, UsbHostError>> + 'a, UsbHostError> {
     let len = pipe
-        .control_transfer(
-            device_handle,
-            &crate::request::Request::get_configuration_descriptor(
-                // TODO: take an index for configuration
-                0,
-                buf.len() as u16,
-            ),
-            buf,
-        )
+        .read_configuration_descriptor(device_handle, config_index, buf)
         .await?;
     let buf_len = buf.len();
 
-    let mut iter = DescriptorIterator::new(&mut buf[..len]).peekable();
+    let mut iter = DescriptorIterator::with_config_length(&buf[..len])
+        .validating()
+        .peekable();
     match iter.peek() {
         Some(Ok(Descriptor::Configuration(c))) => {
-            if c.total_length as usize == len {
+            if c.value == 0 {
+                // 0 puts the device back in the address state, no configuration uses it
+                error!("Configuration {} has bConfigurationValue 0", config_index);
+                Err(UsbHostError::InvalidResponse)
+            } else if c.total_length as usize == len {
                 // If the total length matches, we can return the iterator
                 Ok(iter)
             } else if buf_len < c.total_length as usize {
@@ -151,3 +255,302 @@ pub async fn get_configuration_descriptor<'a, HD: HostDriver, const NR_DEVICES:
         }
     }
 }
+
+/// Iterator over the interfaces of a configuration descriptor, see [`interfaces`]
+pub struct Interfaces<'a, I: Iterator<Item = Result<Descriptor<'a>, UsbHostError>>, const N: usize>
+{
+    iter: Peekable<I>,
+}
+
+impl<'a, I: Iterator<Item = Result<Descriptor<'a>, UsbHostError>>, const N: usize> Iterator
+    for Interfaces<'a, I, N>
+{
+    type Item = Result<(InterfaceDescriptor, ArrayVec<EndpointDescriptor, N>), UsbHostError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let interface = loop {
+            match self.iter.next()? {
+                Ok(Descriptor::Interface(interface)) => break *interface,
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        };
+
+        let mut endpoints = ArrayVec::new();
+        // Class specific descriptors between the endpoints are skipped, an error
+        // is returned by the next call
+        while let Some(Ok(desc)) = self.iter.peek() {
+            match desc {
+                Descriptor::Interface(_) | Descriptor::InterfaceAssociation(_) => break,
+                Descriptor::Endpoint(endpoint) if endpoints.try_push(**endpoint).is_err() => {
+                    warn!(
+                        "Interface {} has more than {} endpoints",
+                        interface.b_interface_number, N
+                    );
+                }
+                _ => {}
+            }
+            self.iter.next();
+        }
+
+        Some(Ok((interface, endpoints)))
+    }
+}
+
+/// Groups the endpoints of a configuration descriptor under the interface they
+/// follow. Endpoints past the first `N` of an interface are dropped.
+pub fn interfaces<'a, I, const N: usize>(iter: I) -> Interfaces<'a, I, N>
+where
+    I: Iterator<Item = Result<Descriptor<'a>, UsbHostError>>,
+{
+    Interfaces {
+        iter: iter.peekable(),
+    }
+}
+
+/// Finds the first interface (alternate settings included) matching `class`,
+/// `subclass` and `protocol`, `None` matches any value.
+pub fn find_interface<'a, const N: usize>(
+    iter: impl Iterator<Item = Result<Descriptor<'a>, UsbHostError>>,
+    class: u8,
+    subclass: Option<u8>,
+    protocol: Option<u8>,
+) -> Result<Option<(InterfaceDescriptor, ArrayVec<EndpointDescriptor, N>)>, UsbHostError> {
+    for interface in interfaces::<_, N>(iter) {
+        let (interface, endpoints) = interface?;
+        if interface.b_interface_class == class
+            && subclass.is_none_or(|subclass| interface.b_interface_sub_class == subclass)
+            && protocol.is_none_or(|protocol| interface.b_interface_protocol == protocol)
+        {
+            return Ok(Some((interface, endpoints)));
+        }
+    }
+    Ok(None)
+}
+
+/// HID interfaces [`attach_hid_interface`] reads the report descriptor of
+const MAX_HID_CANDIDATES: usize = 4;
+
+/// HID interface walked by [`attach_hid_interface`]
+struct HidCandidate {
+    interface: u8,
+    /// wDescriptorLength of the report descriptor, from the HID descriptor
+    report_descriptor_length: Option<u16>,
+    in_channel: Option<(InterruptChannel, u16)>,
+    out_channel: Option<InterruptChannel>,
+}
+
+/// HID interface a driver attached to with [`attach_hid_interface`]
+pub(crate) struct HidInterface<T> {
+    pub interface: u8,
+    pub in_channel: InterruptChannel,
+    /// wMaxPacketSize of the interrupt IN endpoint
+    pub max_packet_size: u16,
+    /// Interrupt OUT endpoint for output reports, if the interface has one
+    pub out_channel: Option<InterruptChannel>,
+    /// Decoded from the report descriptor by the driver
+    pub report: T,
+}
+
+/// Attaches to the first HID interface (alternate setting 0) with an
+/// interrupt IN endpoint that `accept` takes and whose report descriptor
+/// `parse_report` decodes to `Some`.
+///
+/// `parse_report` gets `None` for interfaces without a report descriptor
+/// that fits [`USBHostPipe::descriptor_buffer`]. The device is put in its
+/// first configuration and the interface sent SET_IDLE(0), so it only reports
+/// changes.
+pub(crate) async fn attach_hid_interface<D: HostDriver, const NR_DEVICES: usize, T>(
+    pipe: &USBHostPipe<D, NR_DEVICES>,
+    device: DeviceHandle,
+    accept: impl Fn(&InterfaceDescriptor) -> bool,
+    mut parse_report: impl FnMut(Option<&[u8]>) -> Result<Option<T>, UsbHostError>,
+) -> Result<HidInterface<T>, UsbHostError> {
+    let mut buf = pipe.descriptor_buffer().await;
+    let config_iter = get_configuration_descriptor(device, 0, &mut buf[..], pipe).await?;
+
+    let mut configuration = None;
+    let mut candidates = ArrayVec::<HidCandidate, MAX_HID_CANDIDATES>::new();
+    let mut current: Option<HidCandidate> = None;
+    let mut push = |candidate: Option<HidCandidate>| {
+        if let Some(candidate) = candidate.filter(|c| c.in_channel.is_some()) {
+            if candidates.try_push(candidate).is_err() {
+                warn!("more than {} HID interfaces", MAX_HID_CANDIDATES);
+            }
+        }
+    };
+    for desc in config_iter {
+        match desc? {
+            Descriptor::Configuration(configuration_descriptor) => {
+                configuration = Some(configuration_descriptor.value);
+            }
+            Descriptor::Interface(interface_descriptor) => {
+                push(current.take());
+                current = (interface_descriptor.b_alternate_setting == 0
+                    && interface_descriptor.base_class() == Some(UsbBaseClass::HID)
+                    && accept(interface_descriptor))
+                .then_some(HidCandidate {
+                    interface: interface_descriptor.b_interface_number,
+                    report_descriptor_length: None,
+                    in_channel: None,
+                    out_channel: None,
+                });
+            }
+            Descriptor::UnknownDescriptor {
+                descriptor_type: HID_DESCRIPTOR_TYPE,
+                data,
+                ..
+            } => {
+                if let Some(candidate) = current.as_mut() {
+                    candidate.report_descriptor_length =
+                        HIDDescriptor::report_descriptor_length(data);
+                }
+            }
+            Descriptor::Endpoint(endpoint_descriptor) => {
+                let Some(candidate) = current.as_mut() else {
+                    continue;
+                };
+                let Some(channel) = InterruptChannel::from_descriptor(device, endpoint_descriptor)
+                else {
+                    continue;
+                };
+                match endpoint_descriptor.direction() {
+                    EndpointDirection::In if candidate.in_channel.is_none() => {
+                        candidate.in_channel =
+                            Some((channel, endpoint_descriptor.max_packet_size()));
+                    }
+                    EndpointDirection::Out if candidate.out_channel.is_none() => {
+                        candidate.out_channel = Some(channel);
+                    }
+                    _ => {}
+                }
+            }
+            _ => continue,
+        }
+    }
+    push(current);
+
+    let Some(configuration) = configuration else {
+        return Err(UsbHostError::UnexpectedDevice);
+    };
+
+    // The report descriptor is read into the now unused configuration buffer
+    let mut found = None;
+    for candidate in candidates {
+        let report_descriptor = match candidate.report_descriptor_length {
+            Some(length) if length as usize <= buf.len() => {
+                let len = pipe
+                    .control_transfer(
+                        device,
+                        &Request::hid_get_report_descriptor(candidate.interface, length),
+                        &mut buf[..length as usize],
+                    )
+                    .await?;
+                Some(&buf[..len])
+            }
+            Some(length) => {
+                warn!(
+                    "report descriptor of interface {} too long: {}",
+                    candidate.interface, length
+                );
+                None
+            }
+            None => None,
+        };
+        if let Some(report) = parse_report(report_descriptor)? {
+            found = Some((candidate, report));
+            break;
+        }
+    }
+    drop(buf);
+
+    let Some((candidate, report)) = found else {
+        return Err(UsbHostError::UnexpectedDevice);
+    };
+    let Some((in_channel, max_packet_size)) = candidate.in_channel else {
+        return Err(UsbHostError::InvalidState);
+    };
+
+    pipe.set_configuration(device, configuration).await?;
+    // Report only on change, some devices STALL SET_IDLE
+    match pipe
+        .control_transfer(
+            device,
+            &Request::hid_set_idle(candidate.interface, 0, 0),
+            &mut [],
+        )
+        .await
+    {
+        Ok(_) | Err(UsbHostError::STALL) => {}
+        Err(e) => return Err(e),
+    }
+
+    Ok(HidInterface {
+        interface: candidate.interface,
+        in_channel,
+        max_packet_size,
+        out_channel: candidate.out_channel,
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device_addr::DeviceAddressManager,
+        types::{DevInfo, UsbSpeed},
+    };
+    use core::{
+        cell::Cell,
+        task::{Context, Poll},
+    };
+
+    /// Driver future that never completes, records being dropped
+    struct Driver<'a>(&'a Cell<bool>);
+
+    impl Future for Driver<'_> {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Driver<'_> {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn remove_slots_drops_only_detached_devices() {
+        let mut addresses = DeviceAddressManager::<4>::new();
+        let hub = addresses
+            .alloc_device_address(64, DevInfo::root_device(UsbSpeed::FullSpeed))
+            .unwrap();
+        let device = addresses
+            .alloc_device_address(
+                8,
+                DevInfo::new(hub.address(), 1, None, 0, UsbSpeed::FullSpeed),
+            )
+            .unwrap();
+        let mask = addresses.free_subtree(device.dev_info().port());
+
+        let dropped = [Cell::new(false), Cell::new(false), Cell::new(false)];
+        let mut poller = pin!(StaticUnpinPoller::<Driver, 4>::new());
+        let mut slots = [None; 4];
+        for (dropped, handle) in dropped.iter().zip([hub, device]) {
+            let idx = poller.as_mut().insert(Driver(dropped)).ok().unwrap();
+            slots[idx] = Some(handle.address());
+        }
+
+        remove_slots(poller.as_mut(), &mut slots, &mask);
+        assert_eq!(slots, [Some(hub.address()), None, None, None]);
+        assert!(!dropped[0].get());
+        assert!(dropped[1].get());
+        assert_eq!(poller.len(), 1);
+        // The detached device's slot is free for the next driver
+        assert_eq!(poller.as_mut().insert(Driver(&dropped[2])).ok(), Some(1));
+    }
+}
@@ -0,0 +1,160 @@
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, Receiver},
+};
+use embassy_time::Timer;
+
+use crate::{
+    consts::UsbBaseClass,
+    descriptor::{Descriptor, DeviceDescriptor},
+    driver::{find_interface, get_configuration_descriptor},
+    errors::UsbHostError,
+    pipe::USBHostPipe,
+    request::{hid::HidProtocol, Request},
+    types::{EndpointDirection, InterruptChannel},
+    DeviceHandle, HostDriver,
+};
+
+use super::USBHostDeviceDriver;
+
+/// Largest report read from the mouse, longer reports are truncated
+const MAX_REPORT_SIZE: usize = 64;
+const MOUSE_CHANNEL_SIZE: usize = 4;
+/// Endpoints kept from the mouse interface, only the interrupt IN one is used
+const MAX_ENDPOINTS: usize = 2;
+
+/// Decoded boot protocol mouse report
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct MouseReport {
+    /// Bit 0: left, bit 1: right, bit 2: middle
+    pub buttons: u8,
+    pub dx: i8,
+    pub dy: i8,
+    /// 0 for mice that only send the 3 byte report
+    pub wheel: i8,
+}
+
+impl MouseReport {
+    /// Parses a boot protocol report, bytes past the wheel are ignored
+    fn parse(report: &[u8]) -> Option<Self> {
+        if report.len() < 3 {
+            return None;
+        }
+        Some(MouseReport {
+            buttons: report[0],
+            dx: report[1] as i8,
+            dy: report[2] as i8,
+            wheel: report.get(3).map_or(0, |w| *w as i8),
+        })
+    }
+}
+
+static MOUSE_CHANNEL: Channel<CriticalSectionRawMutex, MouseReport, MOUSE_CHANNEL_SIZE> =
+    Channel::new();
+
+pub struct HidMouse {
+    device: DeviceHandle,
+    interrupt_channel: InterruptChannel,
+    max_packet_size: u16,
+}
+
+impl HidMouse {
+    /// Reports from all attached mice, reports are dropped when the channel is full
+    pub fn channel_receiver(
+    ) -> Receiver<'static, CriticalSectionRawMutex, MouseReport, MOUSE_CHANNEL_SIZE> {
+        MOUSE_CHANNEL.receiver()
+    }
+}
+
+impl USBHostDeviceDriver for HidMouse {
+    const CLASS: Option<u8> = Some(UsbBaseClass::HID as u8);
+
+    async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        _desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        let mut buf = pipe.descriptor_buffer().await;
+        let mut config_iter = get_configuration_descriptor(device, 0, &mut buf[..], pipe).await?;
+        let Some(Ok(Descriptor::Configuration(configuration))) = config_iter.next() else {
+            return Err(UsbHostError::InvalidResponse);
+        };
+        let configuration = configuration.value;
+
+        // Boot mouse interface (class 3, subclass 1, protocol 2)
+        let Some((interface, endpoints)) = find_interface::<MAX_ENDPOINTS>(
+            config_iter,
+            UsbBaseClass::HID as u8,
+            Some(0x01),
+            Some(0x02),
+        )?
+        else {
+            return Err(UsbHostError::UnexpectedDevice);
+        };
+        debug!("Found HID mouse interface");
+        let interface = interface.b_interface_number;
+
+        let Some((interrupt_channel, max_packet_size)) = endpoints
+            .iter()
+            .filter(|endpoint| endpoint.direction() == EndpointDirection::In)
+            .find_map(|endpoint| {
+                InterruptChannel::from_descriptor(device, endpoint)
+                    .map(|channel| (channel, endpoint.max_packet_size()))
+            })
+        else {
+            return Err(UsbHostError::UnexpectedDevice);
+        };
+
+        pipe.set_configuration(device, configuration).await?;
+        pipe.control_transfer(
+            device,
+            &Request::hid_set_protocol(interface, HidProtocol::Boot),
+            &mut [],
+        )
+        .await?;
+        debug!("SET_PROTOCOL(boot) sent to interface {}", interface);
+
+        debug!("Using mouse endpoint: {:?}", interrupt_channel);
+
+        Ok(HidMouse {
+            device,
+            interrupt_channel,
+            max_packet_size,
+        })
+    }
+
+    async fn run<D: HostDriver, const NR_DEVICES: usize>(
+        self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        let Self {
+            device,
+            mut interrupt_channel,
+            max_packet_size,
+        } = self;
+
+        let mut buf = [0u8; MAX_REPORT_SIZE];
+        let report_len = core::cmp::min(max_packet_size as usize, MAX_REPORT_SIZE);
+        let sender = MOUSE_CHANNEL.sender();
+
+        loop {
+            Timer::after(interrupt_channel.interval()).await;
+            match pipe
+                .interrupt_transfer(&mut interrupt_channel, &mut buf[..report_len])
+                .await
+            {
+                Ok(len) => {
+                    if let Some(report) = MouseReport::parse(&buf[..len]) {
+                        if sender.try_send(report).is_err() {
+                            trace!("mouse {} report dropped", device.address());
+                        }
+                    }
+                }
+                Err(UsbHostError::NAK) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
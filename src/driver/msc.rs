@@ -0,0 +1,417 @@
+//! Mass storage class, SCSI transparent command set over Bulk-Only Transport.
+//!
+//! The block commands are methods taking the pipe, e.g. after attaching the
+//! driver with [`MassStorage::try_attach`]:
+//!
+//! ```ignore
+//! let capacity = msc.read_capacity(&pipe, 0).await?;
+//! msc.read10(&pipe, 0, lba, 1, &mut block[..capacity.block_size as usize]).await?;
+//! ```
+//!
+//! Run by [`USBDeviceDispatcher`](super::USBDeviceDispatcher) the driver only
+//! claims the device.
+
+use crate::{
+    consts::UsbBaseClass,
+    descriptor::{Descriptor, DeviceDescriptor},
+    driver::{find_interface, get_configuration_descriptor},
+    errors::UsbHostError,
+    pipe::USBHostPipe,
+    request::Request,
+    types::{BulkChannel, EndpointDirection},
+    DeviceHandle, HostDriver,
+};
+
+use super::USBHostDeviceDriver;
+
+/// SCSI transparent command set
+const MSC_SUBCLASS_SCSI: u8 = 0x06;
+/// Bulk-Only Transport
+const MSC_PROTOCOL_BOT: u8 = 0x50;
+/// Endpoints kept from the mass storage interface, bulk IN and bulk OUT
+const MAX_ENDPOINTS: usize = 2;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_SIZE: usize = 31;
+const CSW_SIZE: usize = 13;
+const CBW_FLAG_IN: u8 = 0x80;
+
+const CSW_STATUS_PASSED: u8 = 0x00;
+const CSW_STATUS_FAILED: u8 = 0x01;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+/// Length of fixed format sense data
+const SENSE_DATA_SIZE: usize = 18;
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum MscError {
+    Usb(UsbHostError),
+    /// The command failed, the sense data is read with REQUEST SENSE
+    CommandFailed(SenseData),
+    /// Phase error or invalid CSW, the device has been reset
+    PhaseError,
+}
+
+impl From<UsbHostError> for MscError {
+    fn from(value: UsbHostError) -> Self {
+        MscError::Usb(value)
+    }
+}
+
+/// Fixed format sense data returned by REQUEST SENSE
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct SenseData {
+    pub sense_key: u8,
+    /// Additional sense code
+    pub asc: u8,
+    /// Additional sense code qualifier
+    pub ascq: u8,
+}
+
+/// Result of READ CAPACITY (10)
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct Capacity {
+    pub last_lba: u32,
+    pub block_size: u32,
+}
+
+impl Capacity {
+    pub fn block_count(&self) -> u64 {
+        self.last_lba as u64 + 1
+    }
+}
+
+enum DataStage<'b> {
+    None,
+    In(&'b mut [u8]),
+    Out(&'b [u8]),
+}
+
+impl DataStage<'_> {
+    fn len(&self) -> usize {
+        match self {
+            DataStage::None => 0,
+            DataStage::In(buf) => buf.len(),
+            DataStage::Out(buf) => buf.len(),
+        }
+    }
+}
+
+pub struct MassStorage {
+    device: DeviceHandle,
+    interface: u8,
+    max_lun: u8,
+    bulk_in: BulkChannel,
+    bulk_out: BulkChannel,
+    tag: u32,
+}
+
+impl MassStorage {
+    pub fn max_lun(&self) -> u8 {
+        self.max_lun
+    }
+
+    /// Fails with [`MscError::CommandFailed`] while no medium is ready
+    pub async fn test_unit_ready<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        lun: u8,
+    ) -> Result<(), MscError> {
+        let mut cb = [0u8; 6];
+        cb[0] = SCSI_TEST_UNIT_READY;
+        self.execute(pipe, lun, &cb, DataStage::None)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn read_capacity<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        lun: u8,
+    ) -> Result<Capacity, MscError> {
+        let mut cb = [0u8; 10];
+        cb[0] = SCSI_READ_CAPACITY_10;
+        let mut data = [0u8; 8];
+        let len = self
+            .execute(pipe, lun, &cb, DataStage::In(&mut data))
+            .await?;
+        if len < data.len() {
+            return Err(MscError::Usb(UsbHostError::InvalidResponse));
+        }
+        Ok(Capacity {
+            last_lba: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            block_size: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        })
+    }
+
+    /// Reads `blocks` blocks starting at `lba`, `buf` has to hold all of them
+    pub async fn read10<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        lun: u8,
+        lba: u32,
+        blocks: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, MscError> {
+        let cb = Self::rw10(SCSI_READ_10, lba, blocks);
+        self.execute(pipe, lun, &cb, DataStage::In(buf)).await
+    }
+
+    /// Writes `blocks` blocks starting at `lba`, `buf` holds all of them
+    pub async fn write10<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        lun: u8,
+        lba: u32,
+        blocks: u16,
+        buf: &[u8],
+    ) -> Result<usize, MscError> {
+        let cb = Self::rw10(SCSI_WRITE_10, lba, blocks);
+        self.execute(pipe, lun, &cb, DataStage::Out(buf)).await
+    }
+
+    pub async fn request_sense<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        lun: u8,
+    ) -> Result<SenseData, MscError> {
+        let mut cb = [0u8; 6];
+        cb[0] = SCSI_REQUEST_SENSE;
+        cb[4] = SENSE_DATA_SIZE as u8;
+        let mut data = [0u8; SENSE_DATA_SIZE];
+        let (len, status) = self
+            .transport(pipe, lun, &cb, DataStage::In(&mut data))
+            .await?;
+        if status != CSW_STATUS_PASSED || len < 14 {
+            return Err(MscError::Usb(UsbHostError::InvalidResponse));
+        }
+        Ok(SenseData {
+            sense_key: data[2] & 0x0F,
+            asc: data[12],
+            ascq: data[13],
+        })
+    }
+
+    fn rw10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+        let lba = lba.to_be_bytes();
+        let blocks = blocks.to_be_bytes();
+        [
+            opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, blocks[0], blocks[1], 0,
+        ]
+    }
+
+    /// Runs a command, a failed command is reported with its sense data
+    async fn execute<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        lun: u8,
+        cb: &[u8],
+        data: DataStage<'_>,
+    ) -> Result<usize, MscError> {
+        match self.transport(pipe, lun, cb, data).await? {
+            (len, CSW_STATUS_PASSED) => Ok(len),
+            (_, CSW_STATUS_FAILED) => {
+                let sense = self.request_sense(pipe, lun).await?;
+                debug!(
+                    "MSC command {:#x} failed, sense key {:#x} asc {:#x} ascq {:#x}",
+                    cb[0], sense.sense_key, sense.asc, sense.ascq
+                );
+                Err(MscError::CommandFailed(sense))
+            }
+            _ => {
+                self.reset_recovery(pipe).await?;
+                Err(MscError::PhaseError)
+            }
+        }
+    }
+
+    /// CBW, data and CSW stages of one command, returns the bytes transferred
+    /// in the data stage and the CSW status
+    async fn transport<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        lun: u8,
+        cb: &[u8],
+        data: DataStage<'_>,
+    ) -> Result<(usize, u8), MscError> {
+        self.tag = self.tag.wrapping_add(1);
+        let tag = self.tag;
+
+        let mut cbw = [0u8; CBW_SIZE];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        cbw[12] = match data {
+            DataStage::In(_) => CBW_FLAG_IN,
+            _ => 0,
+        };
+        cbw[13] = lun;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+
+        if let Err(e) = pipe.bulk_out(&mut self.bulk_out, &cbw).await {
+            self.reset_recovery(pipe).await?;
+            return Err(e.into());
+        }
+
+        // A STALL ends the data stage early, the CSW is read after clearing it
+        let data_res = match data {
+            DataStage::None => Ok(0),
+            DataStage::In(buf) => match pipe.bulk_in_once(&mut self.bulk_in, buf).await {
+                Err(UsbHostError::STALL) => {
+                    pipe.clear_halt(self.device, self.bulk_in.endpoint_address)
+                        .await?;
+                    self.bulk_in.reset_tog();
+                    Ok(0)
+                }
+                res => res,
+            },
+            DataStage::Out(buf) => match pipe.bulk_out_once(&mut self.bulk_out, buf).await {
+                Err(UsbHostError::STALL) => {
+                    pipe.clear_halt(self.device, self.bulk_out.endpoint_address)
+                        .await?;
+                    self.bulk_out.reset_tog();
+                    Ok(0)
+                }
+                res => res,
+            },
+        };
+        let len = match data_res {
+            Ok(len) => len,
+            Err(e) => {
+                self.reset_recovery(pipe).await?;
+                return Err(e.into());
+            }
+        };
+
+        let mut csw = [0u8; CSW_SIZE];
+        let csw_len = match pipe.bulk_transfer(&mut self.bulk_in, &mut csw).await {
+            Ok(csw_len) => csw_len,
+            Err(e) => {
+                self.reset_recovery(pipe).await?;
+                return Err(e.into());
+            }
+        };
+        if csw_len != CSW_SIZE
+            || csw[0..4] != CSW_SIGNATURE.to_le_bytes()
+            || csw[4..8] != tag.to_le_bytes()
+        {
+            warn!("MSC device {} sent an invalid CSW", self.device.address());
+            self.reset_recovery(pipe).await?;
+            return Err(MscError::PhaseError);
+        }
+
+        Ok((len, csw[12]))
+    }
+
+    /// Bulk-Only Mass Storage Reset followed by clearing both bulk endpoints
+    async fn reset_recovery<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        warn!("MSC device {} reset recovery", self.device.address());
+        pipe.control_transfer(
+            self.device,
+            &Request::msc_bulk_only_reset(self.interface),
+            &mut [],
+        )
+        .await?;
+        pipe.clear_halt(self.device, self.bulk_in.endpoint_address)
+            .await?;
+        self.bulk_in.reset_tog();
+        pipe.clear_halt(self.device, self.bulk_out.endpoint_address)
+            .await?;
+        self.bulk_out.reset_tog();
+        Ok(())
+    }
+}
+
+impl USBHostDeviceDriver for MassStorage {
+    const CLASS: Option<u8> = Some(UsbBaseClass::MassStorage as u8);
+    const SUBCLASS: Option<u8> = Some(MSC_SUBCLASS_SCSI);
+
+    async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        _desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        let mut buf = pipe.descriptor_buffer().await;
+        let mut config_iter = get_configuration_descriptor(device, 0, &mut buf[..], pipe).await?;
+        let Some(Ok(Descriptor::Configuration(configuration))) = config_iter.next() else {
+            return Err(UsbHostError::InvalidResponse);
+        };
+        let configuration = configuration.value;
+
+        let Some((interface, endpoints)) = find_interface::<MAX_ENDPOINTS>(
+            config_iter,
+            UsbBaseClass::MassStorage as u8,
+            Some(MSC_SUBCLASS_SCSI),
+            Some(MSC_PROTOCOL_BOT),
+        )?
+        else {
+            return Err(UsbHostError::UnexpectedDevice);
+        };
+        let interface = interface.b_interface_number;
+
+        let mut bulk_in = None;
+        let mut bulk_out = None;
+        for endpoint in endpoints.iter() {
+            let Some(channel) = BulkChannel::from_descriptor(device, endpoint)? else {
+                continue;
+            };
+            match channel.endpoint_address().direction {
+                EndpointDirection::In => bulk_in = bulk_in.or(Some(channel)),
+                EndpointDirection::Out => bulk_out = bulk_out.or(Some(channel)),
+            }
+        }
+
+        let (Some(bulk_in), Some(bulk_out)) = (bulk_in, bulk_out) else {
+            return Err(UsbHostError::UnexpectedDevice);
+        };
+
+        pipe.set_configuration(device, configuration).await?;
+
+        // Devices with a single LUN may STALL GET_MAX_LUN
+        let mut max_lun = [0u8; 1];
+        let max_lun = match pipe
+            .control_transfer(device, &Request::msc_get_max_lun(interface), &mut max_lun)
+            .await
+        {
+            Ok(1) => max_lun[0],
+            Ok(_) | Err(UsbHostError::STALL) => 0,
+            Err(e) => return Err(e),
+        };
+        debug!(
+            "Mass storage attached on interface {}, max LUN {}",
+            interface, max_lun
+        );
+
+        Ok(MassStorage {
+            device,
+            interface,
+            max_lun,
+            bulk_in,
+            bulk_out,
+            tag: 0,
+        })
+    }
+
+    /// The driver has no background work, this only claims the device.
+    async fn run<D: HostDriver, const NR_DEVICES: usize>(
+        self,
+        _pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        core::future::pending().await
+    }
+}
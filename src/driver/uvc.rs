@@ -0,0 +1,351 @@
+use arrayvec::ArrayVec;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, pipe::Pipe};
+use embassy_time::Timer;
+
+use crate::{
+    consts::UsbBaseClass,
+    descriptor::{Descriptor, DeviceDescriptor, WithInterface},
+    driver::get_configuration_descriptor,
+    errors::UsbHostError,
+    pipe::USBHostPipe,
+    request::{
+        uvc::{UvcRequest, UvcStreamingControl},
+        Request,
+    },
+    types::{EndpointDirection, IsoChannel},
+    DeviceHandle, HostDriver,
+};
+
+use super::USBHostDeviceDriver;
+
+/// Video control interface subclass
+const UVC_SUBCLASS_CONTROL: u8 = 0x01;
+/// Video streaming interface subclass
+const UVC_SUBCLASS_STREAMING: u8 = 0x02;
+/// CS_INTERFACE class specific descriptor type
+const CS_INTERFACE: u8 = 0x24;
+/// Class specific video control interface header descriptor subtype
+const VC_HEADER: u8 = 0x01;
+/// Alternate settings of the streaming interface considered
+const MAX_ALT_SETTINGS: usize = 8;
+/// Largest probe / commit control, UVC 1.5
+const MAX_PROBE_LEN: usize = 48;
+/// Largest isochronous transaction read, longer packets are truncated
+const MAX_PACKET_SIZE: usize = 1024;
+/// Largest configuration descriptor read. The format and frame descriptors of
+/// the streaming interface take most of it, cameras easily exceed the 255
+/// bytes of [`USBHostPipe::descriptor_buffer`].
+const MAX_CONFIGURATION_SIZE: usize = 4096;
+
+pub const UVC_PIPE_SIZE: usize = 1024;
+
+/// Payload data (without payload headers) received while the driver is run by
+/// the dispatcher
+static UVC_PIPE: Pipe<CriticalSectionRawMutex, UVC_PIPE_SIZE> = Pipe::new();
+
+/// Configuration descriptor buffer of [`Uvc::try_attach`]
+static DESCRIPTOR_BUFFER: Mutex<CriticalSectionRawMutex, [u8; MAX_CONFIGURATION_SIZE]> =
+    Mutex::new([0; MAX_CONFIGURATION_SIZE]);
+
+/// Header of a UVC payload, the start of every isochronous packet
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct PayloadHeader {
+    /// Length of the header, the payload data follows it
+    pub length: u8,
+    /// bmHeaderInfo
+    pub info: u8,
+}
+
+impl PayloadHeader {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let length = *packet.first()?;
+        if length < 2 || length as usize > packet.len() {
+            return None;
+        }
+        Some(PayloadHeader {
+            length,
+            info: packet[1],
+        })
+    }
+
+    /// Frame ID, toggles with every video frame
+    pub fn frame_id(&self) -> bool {
+        self.info & 0x01 != 0
+    }
+
+    /// Last payload of the video frame
+    pub fn end_of_frame(&self) -> bool {
+        self.info & 0x02 != 0
+    }
+
+    /// The device reported an error for this payload
+    pub fn error(&self) -> bool {
+        self.info & 0x40 != 0
+    }
+}
+
+/// Alternate setting of the streaming interface with an isochronous IN endpoint
+struct AltSetting {
+    alternate_setting: u8,
+    channel: IsoChannel,
+    /// Bytes per (micro)frame, one packet since high-bandwidth endpoints are skipped
+    bandwidth: u32,
+}
+
+/// Video streaming interface of a UVC camera.
+///
+/// Negotiates the streaming parameters with the device's defaults
+/// (probe / commit) and selects the alternate setting with the lowest
+/// bandwidth carrying the negotiated payload size. Decoding the video is left
+/// to the application, [`Uvc::read_payload`] returns the payloads of the stream.
+pub struct Uvc {
+    device: DeviceHandle,
+    interface: u8,
+    alternate_setting: u8,
+    iso_channel: IsoChannel,
+}
+
+impl Uvc {
+    /// Payload data received when the driver is run by the dispatcher, payloads
+    /// are dropped when the pipe is full
+    pub fn pipe() -> &'static Pipe<CriticalSectionRawMutex, UVC_PIPE_SIZE> {
+        &UVC_PIPE
+    }
+
+    /// Alternate setting of the streaming interface in use
+    pub fn alternate_setting(&self) -> u8 {
+        self.alternate_setting
+    }
+
+    /// Waits for the endpoint's service interval and reads one payload into
+    /// `buf`. Returns the payload header and the number of bytes read, the
+    /// payload data follows the header in `buf`. `Ok(None)` for empty packets.
+    pub async fn read_payload<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        buf: &mut [u8],
+    ) -> Result<Option<(PayloadHeader, usize)>, UsbHostError> {
+        Timer::after(self.iso_channel.period()).await;
+        let len = pipe.iso_in(&mut self.iso_channel, buf).await?;
+        match PayloadHeader::parse(&buf[..len]) {
+            Some(header) => Ok(Some((header, len))),
+            None if len == 0 => Ok(None),
+            None => Err(UsbHostError::InvalidResponse),
+        }
+    }
+
+    /// Stops the stream by selecting the zero bandwidth alternate setting
+    pub async fn stop<D: HostDriver, const NR_DEVICES: usize>(
+        &self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        pipe.set_interface(self.device, self.interface, 0).await
+    }
+
+    /// Probe and commit of the device's default streaming parameters, returns
+    /// dwMaxPayloadTransferSize
+    async fn negotiate<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        interface: u8,
+        probe_len: usize,
+    ) -> Result<u32, UsbHostError> {
+        let mut probe = [0u8; MAX_PROBE_LEN];
+        let probe = &mut probe[..probe_len];
+        let request = |request, control| {
+            Request::uvc_streaming(request, control, interface, probe_len as u16)
+        };
+
+        pipe.control_transfer(
+            device,
+            &request(UvcRequest::GetCur, UvcStreamingControl::Probe),
+            probe,
+        )
+        .await?;
+        pipe.control_transfer(
+            device,
+            &request(UvcRequest::SetCur, UvcStreamingControl::Probe),
+            probe,
+        )
+        .await?;
+        // The device fills in the parameters it can do
+        let len = pipe
+            .control_transfer(
+                device,
+                &request(UvcRequest::GetCur, UvcStreamingControl::Probe),
+                probe,
+            )
+            .await?;
+        if len < 26 {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        pipe.control_transfer(
+            device,
+            &request(UvcRequest::SetCur, UvcStreamingControl::Commit),
+            probe,
+        )
+        .await?;
+        Ok(u32::from_le_bytes([
+            probe[22], probe[23], probe[24], probe[25],
+        ]))
+    }
+}
+
+impl USBHostDeviceDriver for Uvc {
+    const CLASS: Option<u8> = Some(UsbBaseClass::Video as u8);
+
+    async fn try_attach<D: HostDriver, const NR_DEVICES: usize>(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        device: DeviceHandle,
+        _desc: DeviceDescriptor,
+    ) -> Result<Self, UsbHostError> {
+        let mut buf = DESCRIPTOR_BUFFER.lock().await;
+        let config_iter = get_configuration_descriptor(device, 0, &mut buf[..], pipe).await?;
+
+        let mut configuration = None;
+        let mut in_control = false;
+        // bcdUVC from the video control header
+        let mut uvc_version = None;
+        let mut streaming_interface = None;
+        let mut alt_settings = ArrayVec::<AltSetting, MAX_ALT_SETTINGS>::new();
+
+        for desc in WithInterface::new(config_iter) {
+            let (interface, desc) = desc?;
+            match desc {
+                Descriptor::Configuration(configuration_descriptor) => {
+                    configuration = Some(configuration_descriptor.value);
+                }
+                Descriptor::Interface(interface_descriptor) => {
+                    let video = interface_descriptor.base_class() == Some(UsbBaseClass::Video);
+                    let subclass = interface_descriptor.b_interface_sub_class;
+                    in_control = video && subclass == UVC_SUBCLASS_CONTROL;
+                    if video && subclass == UVC_SUBCLASS_STREAMING && streaming_interface.is_none()
+                    {
+                        streaming_interface = Some(interface_descriptor.b_interface_number);
+                    }
+                }
+                Descriptor::UnknownDescriptor {
+                    descriptor_type: CS_INTERFACE,
+                    length,
+                    data,
+                } => {
+                    // bDescriptorSubtype, bcdUVC
+                    if in_control && length >= 5 && data[2] == VC_HEADER {
+                        uvc_version = Some(u16::from_le_bytes([data[3], data[4]]));
+                    }
+                }
+                Descriptor::Endpoint(endpoint_descriptor) => {
+                    let Some(interface) = interface else {
+                        continue;
+                    };
+                    if Some(interface.number) != streaming_interface
+                        || endpoint_descriptor.direction() != EndpointDirection::In
+                    {
+                        continue;
+                    }
+                    // iso_in makes one transaction per microframe, high-bandwidth
+                    // endpoints send up to 3 with DATA2/DATA1/MDATA PIDs
+                    if endpoint_descriptor.packets_per_microframe() > 1 {
+                        debug!(
+                            "skipping high-bandwidth alternate setting {}",
+                            interface.alternate_setting
+                        );
+                        continue;
+                    }
+                    if let Some(channel) = IsoChannel::from_descriptor(device, endpoint_descriptor)
+                    {
+                        let bandwidth = channel.max_packet_size() as u32;
+                        let alt_setting = AltSetting {
+                            alternate_setting: interface.alternate_setting,
+                            channel,
+                            bandwidth,
+                        };
+                        if alt_settings.try_push(alt_setting).is_err() {
+                            warn!(
+                                "more than {} streaming alternate settings",
+                                MAX_ALT_SETTINGS
+                            );
+                        }
+                    }
+                }
+                _ => continue,
+            }
+        }
+        drop(buf);
+
+        let (Some(configuration), Some(uvc_version), Some(interface)) =
+            (configuration, uvc_version, streaming_interface)
+        else {
+            return Err(UsbHostError::UnexpectedDevice);
+        };
+        // Bulk streaming interfaces have no isochronous alternate settings
+        if alt_settings.is_empty() {
+            return Err(UsbHostError::Unsupported);
+        }
+        let probe_len = match uvc_version {
+            0..0x0110 => 26,
+            0x0110..0x0150 => 34,
+            _ => 48,
+        };
+
+        pipe.set_configuration(device, configuration).await?;
+        let max_payload = Self::negotiate(pipe, device, interface, probe_len).await?;
+
+        // The lowest bandwidth fitting the payload, the highest if none does
+        let Some(alt_setting) = alt_settings
+            .iter()
+            .filter(|alt| alt.bandwidth >= max_payload)
+            .min_by_key(|alt| alt.bandwidth)
+            .or_else(|| alt_settings.iter().max_by_key(|alt| alt.bandwidth))
+        else {
+            return Err(UsbHostError::Unsupported);
+        };
+        pipe.set_interface(device, interface, alt_setting.alternate_setting)
+            .await?;
+        debug!(
+            "UVC streaming on interface {} alternate setting {}, payload {}",
+            interface, alt_setting.alternate_setting, max_payload
+        );
+
+        Ok(Uvc {
+            device,
+            interface,
+            alternate_setting: alt_setting.alternate_setting,
+            iso_channel: alt_setting.channel.clone(),
+        })
+    }
+
+    /// Forwards the payload data to [`Uvc::pipe`]
+    async fn run<D: HostDriver, const NR_DEVICES: usize>(
+        mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+    ) -> Result<(), UsbHostError> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        loop {
+            match self.read_payload(pipe, &mut buf).await {
+                Ok(Some((header, len))) => {
+                    if header.error() {
+                        trace!("UVC payload error");
+                        continue;
+                    }
+                    let data = &buf[header.length as usize..len];
+                    if UVC_PIPE
+                        .try_write(data)
+                        .map_or(true, |written| written < data.len())
+                    {
+                        trace!("UVC payload dropped");
+                    }
+                    if header.end_of_frame() {
+                        trace!("UVC end of frame");
+                    }
+                }
+                Ok(None) => {}
+                // A lost isochronous packet is not retried
+                Err(UsbHostError::TransferTimeout | UsbHostError::WrongTog) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
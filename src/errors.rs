@@ -1,8 +1,8 @@
 use crate::descriptor::ParsingError;
 
+// Debug is always derived, it is required by `embedded_io_async::Error`
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[cfg_attr(not(feature = "defmt"), derive(Debug))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub enum UsbHostError {
     Unknown,
     // USB Device packet level errors
@@ -16,9 +16,68 @@ pub enum UsbHostError {
     BufferOverflow,
     ParsingError(ParsingError),
     TransferTimeout,
+    /// The device kept responding with NAK past the pipe's NAK limit
+    NakTimeout,
     InvalidState,
     InvalidResponse,
     UnexpectedDevice,
     HubCapacity,
+    /// All device addresses are in use
+    AddressExhausted,
     Detached,
+    Unsupported,
+    /// The configuration draws more current than the device's port supplies
+    InsufficientPower,
+    /// The device didn't complete its enumeration in time
+    EnumerationTimeout,
+}
+
+impl embedded_io_async::Error for UsbHostError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            UsbHostError::TransferTimeout
+            | UsbHostError::NakTimeout
+            | UsbHostError::EnumerationTimeout => embedded_io_async::ErrorKind::TimedOut,
+            UsbHostError::Detached => embedded_io_async::ErrorKind::NotConnected,
+            UsbHostError::Unsupported => embedded_io_async::ErrorKind::Unsupported,
+            _ => embedded_io_async::ErrorKind::Other,
+        }
+    }
+}
+
+impl core::fmt::Display for UsbHostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            UsbHostError::Unknown => f.write_str("unknown error"),
+            UsbHostError::NAK => f.write_str("device responded with NAK"),
+            UsbHostError::NYET => f.write_str("device responded with NYET"),
+            UsbHostError::WrongTog => f.write_str("data toggle mismatch"),
+            UsbHostError::STALL => f.write_str("endpoint stalled"),
+            UsbHostError::UnexpectedPID => f.write_str("unexpected PID"),
+            UsbHostError::BufferOverflow => f.write_str("buffer too small"),
+            UsbHostError::ParsingError(e) => write!(f, "descriptor parsing failed: {}", e),
+            UsbHostError::TransferTimeout => f.write_str("transfer timed out"),
+            UsbHostError::NakTimeout => f.write_str("NAK retry limit reached"),
+            UsbHostError::InvalidState => f.write_str("invalid state"),
+            UsbHostError::InvalidResponse => f.write_str("invalid response from device"),
+            UsbHostError::UnexpectedDevice => f.write_str("device not supported by driver"),
+            UsbHostError::HubCapacity => f.write_str("too many hubs"),
+            UsbHostError::AddressExhausted => f.write_str("no device address available"),
+            UsbHostError::Detached => f.write_str("device detached"),
+            UsbHostError::Unsupported => f.write_str("operation not supported"),
+            UsbHostError::InsufficientPower => {
+                f.write_str("configuration exceeds the port's power budget")
+            }
+            UsbHostError::EnumerationTimeout => f.write_str("enumeration timed out"),
+        }
+    }
+}
+
+impl core::error::Error for UsbHostError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            UsbHostError::ParsingError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
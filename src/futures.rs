@@ -15,6 +15,30 @@ use embassy_futures::select::Either;
 ///
 /// Similar to `embassy_futures::select::select`, but owns the futures
 /// and manages their state internally, allowing for `!Unpin` types.
+///
+/// Unlike `select`, the selector can be polled again after it completed. The
+/// future that completed is dropped and its slot left `Empty`, while the other
+/// future stays in its slot untouched. Re-inserting the completed side with
+/// `insert_fut1` / `insert_fut2` and awaiting the selector again keeps polling
+/// the other future where it left off, e.g. to re-arm a periodic poll while a
+/// long running future keeps going:
+///
+/// ```ignore
+/// let mut select = pin!(SelectPin2::with_futures(hub_poll(), bus_poll()));
+/// loop {
+///     match select.as_mut().await {
+///         Either::First(status) => {
+///             handle(status);
+///             // `bus_poll` is still pending in slot 1
+///             select.as_mut().insert_fut1(hub_poll()).unwrap();
+///         }
+///         Either::Second(event) => return event,
+///     }
+/// }
+/// ```
+///
+/// Empty slots are skipped when polling, a selector with both slots `Empty`
+/// stays `Pending` until a future is inserted and it is polled again.
 #[must_use = "futures do nothing unless you `.await` or poll them"]
 pub struct SelectPin2<Fut1: Future, Fut2: Future> {
     fut1: MaybeUninit<Fut1>,
@@ -86,6 +110,29 @@ impl<Fut1: Future, Fut2: Future> SelectPin2<Fut1, Fut2> {
         Ok(())
     }
 
+    /// Inserts both futures into their slots.
+    ///
+    /// Requires `Pin<&mut Self>` to ensure structural integrity if `Fut1` or
+    /// `Fut2` is `!Unpin`.
+    ///
+    /// Returns `Ok(())` on success.
+    /// Returns `Err(PollerError::SlotOccupied)` if either slot is not empty, in
+    /// which case neither future is inserted.
+    pub fn insert_both(self: Pin<&mut Self>, fut1: Fut1, fut2: Fut2) -> Result<(), PollerError> {
+        // Safety: We don't move fields out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if this.states != [SlotState::Empty, SlotState::Empty] {
+            return Err(PollerError::SlotOccupied);
+        }
+
+        // Write the futures into the storage and update the states.
+        this.fut1.write(fut1);
+        this.fut2.write(fut2);
+        this.states = [SlotState::Occupied, SlotState::Occupied];
+        Ok(())
+    }
+
     /// Drops the future in the given slot and marks it as Empty.
     ///
     /// # Safety
@@ -115,6 +162,11 @@ impl<Fut1: Future, Fut2: Future> SelectPin2<Fut1, Fut2> {
 impl<Fut1: Future, Fut2: Future> Future for SelectPin2<Fut1, Fut2> {
     type Output = Either<Fut1::Output, Fut2::Output>;
 
+    /// Polls the occupied slots, `fut1` first.
+    ///
+    /// The future that completes is dropped and its slot marked `Empty`, the
+    /// other slot is left as is so it can keep running after the completed
+    /// side is re-inserted.
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         // Poll fut1 if it's occupied
         if self.as_ref().get_ref().states[0] == SlotState::Occupied {
@@ -217,6 +269,9 @@ where
     storage: [MaybeUninit<F>; N],
     // Tracks the state of each corresponding slot in `storage`.
     states: [SlotState; N],
+    // Slot polled first by the next `poll_next`, rotated so that an always
+    // ready future cannot starve the slots after it.
+    next_start: usize,
 }
 
 impl<F, const N: usize> StaticUnpinPoller<F, N>
@@ -231,6 +286,7 @@ where
             // Safety: An uninitialized `MaybeUninit<T>` is valid.
             storage: array::from_fn(|_| MaybeUninit::uninit()),
             states: [SlotState::Empty; N],
+            next_start: 0,
         }
     }
 
@@ -291,10 +347,9 @@ where
     /// although technically not strictly needed just for insertion if the poller
     /// hasn't been polled yet. Consistent API is preferred.
     ///
-    /// Returns `Ok(())` on success.
-    /// Returns `Err(PollerError::IndexOutOfBounds)` if the index is invalid.
-    /// Returns `Err(PollerError::SlotOccupied)` if the slot is not empty.
-    pub fn insert(mut self: Pin<&mut Self>, future: F) -> Result<(), PollerError> {
+    /// Returns `Ok(index)` of the slot used on success.
+    /// Returns `Err(PollerError::IndexOutOfBounds)` if there is no empty slot.
+    pub fn insert(mut self: Pin<&mut Self>, future: F) -> Result<usize, PollerError> {
         // Safety: We don't move fields out of `self`.
         let this = unsafe { self.as_mut().get_unchecked_mut() };
 
@@ -308,7 +363,34 @@ where
         // Write the future into the storage and update the state.
         this.storage[index].write(future);
         *state = SlotState::Occupied;
-        Ok(())
+        Ok(index)
+    }
+
+    /// Drops the future in a slot without polling it to completion.
+    ///
+    /// Returns `Ok(())` on success.
+    /// Returns `Err(PollerError::IndexOutOfBounds)` if the index is invalid.
+    /// Returns `Err(PollerError::SlotEmpty)` if the slot is not occupied.
+    pub fn remove(mut self: Pin<&mut Self>, index: usize) -> Result<(), PollerError> {
+        match self.as_ref().get_ref().states.get(index) {
+            None => Err(PollerError::IndexOutOfBounds),
+            Some(SlotState::Empty) => Err(PollerError::SlotEmpty),
+            Some(SlotState::Occupied) => {
+                // Safety: index is valid and the slot is Occupied.
+                unsafe { self.as_mut().drop_future_at(index) };
+                Ok(())
+            }
+        }
+    }
+
+    /// Drops the futures in all occupied slots, leaving the poller empty.
+    pub fn clear(mut self: Pin<&mut Self>) {
+        for index in 0..N {
+            if self.as_ref().get_ref().states[index] == SlotState::Occupied {
+                // Safety: index is valid and the slot is Occupied.
+                unsafe { self.as_mut().drop_future_at(index) };
+            }
+        }
     }
 
     /// Replaces the future in a slot, assuming it was previously occupied and completed.
@@ -351,6 +433,7 @@ where
     ///
     /// Returns `Poll::Ready(Some((index, output)))` when a future completes.
     /// The slot at `index` is automatically dropped and marked as `Empty`.
+    /// Slots are polled round-robin, starting after the slot that completed last.
     ///
     /// Returns `Poll::Ready(None)` if all slots are currently empty.
     ///
@@ -362,9 +445,12 @@ where
     ) -> Poll<Option<(usize, F::Output)>> {
         let mut pending_found = false;
         let mut occupied_count = 0;
+        let start = self.next_start;
+        // Safety: We don't move fields out of `self`.
+        unsafe { self.as_mut().get_unchecked_mut() }.next_start = (start + 1) % N.max(1);
 
         // We need to iterate carefully, as polling might modify `self.states`.
-        for index in 0..N {
+        for index in (0..N).map(|offset| (start + offset) % N) {
             // Check state *before* potentially getting a pinned reference.
             // We need `self` pinned *during* the unsafe block.
             let current_state = self.as_ref().get_ref().states[index];
@@ -382,6 +468,8 @@ where
                         // We need `self` pinned to safely drop.
                         // Safety: Future at `index` just completed, state is Occupied.
                         unsafe { self.as_mut().drop_future_at(index) };
+                        // Safety: We don't move fields out of `self`.
+                        unsafe { self.as_mut().get_unchecked_mut() }.next_start = (index + 1) % N;
 
                         return Poll::Ready(Some((index, output)));
                     }
@@ -452,3 +540,66 @@ impl<F: Future, const N: usize> Drop for StaticUnpinPoller<F, N> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{cell::Cell, pin::pin, task::Waker};
+
+    /// Counts its polls, ready with the count at the `ready_at`th poll
+    struct Counter<'a> {
+        polls: &'a Cell<u32>,
+        ready_at: Option<u32>,
+    }
+
+    impl Future for Counter<'_> {
+        type Output = u32;
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<u32> {
+            let polls = self.polls.get() + 1;
+            self.polls.set(polls);
+            match self.ready_at {
+                Some(ready_at) if polls >= ready_at => Poll::Ready(polls),
+                _ => Poll::Pending,
+            }
+        }
+    }
+
+    #[test]
+    fn reinserted_side_keeps_the_other_running() {
+        let (first, second) = (Cell::new(0), Cell::new(0));
+        let counter = |polls, ready_at| Counter { polls, ready_at };
+        let mut select = pin!(SelectPin2::with_futures(
+            counter(&first, Some(1)),
+            counter(&second, None),
+        ));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(matches!(
+            select.as_mut().poll(&mut cx),
+            Poll::Ready(Either::First(1))
+        ));
+        // The completed side is empty, the other one stays
+        assert!(select.as_mut().insert_fut2(counter(&second, None)).is_err());
+        assert!(select
+            .as_mut()
+            .insert_fut1(counter(&first, Some(3)))
+            .is_ok());
+
+        assert!(select.as_mut().poll(&mut cx).is_pending());
+        assert!(matches!(
+            select.as_mut().poll(&mut cx),
+            Poll::Ready(Either::First(3))
+        ));
+        // Polled whenever the first side was pending, never restarted
+        assert_eq!(second.get(), 1);
+
+        // Both sides at once, only into empty slots
+        let both = select
+            .as_mut()
+            .insert_both(counter(&first, None), counter(&second, None));
+        assert!(matches!(both, Err(PollerError::SlotOccupied)));
+        assert!(select.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(second.get(), 2);
+    }
+}
@@ -1,16 +1,17 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 use arrayvec::ArrayVec;
 use bus::BusWrap;
 use consts::UsbBaseClass;
 use core::marker::PhantomData;
 use descriptor::DeviceDescriptor;
-use device_addr::{DeviceAddressManager, DeviceDisconnectMask};
+use device_addr::DeviceAddressManager;
 use driver::hub::Hub;
-use embassy_futures::select::{select, Either};
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_time::{with_deadline, Duration, Instant, Timer};
 use errors::UsbHostError;
 use pipe::USBHostPipe;
-use types::DevInfo;
+use request::Request;
+use types::{DevInfo, DeviceInterfaces, InterruptChannel, PortInfo};
 
 #[macro_use]
 mod macros;
@@ -26,17 +27,71 @@ pub mod types;
 
 mod bus;
 pub mod pipe;
+#[cfg(test)]
+mod test_util;
 pub use bus::{Bus, Event};
-pub use device_addr::DeviceHandle;
+pub use device_addr::{AttachedDevice, DeviceDisconnectMask, DeviceHandle, MAX_PATH_LEN};
 pub use pipe::Pipe;
 
 const TRANSFER_TIMEOUT: Duration = Duration::from_millis(500);
+/// Retries of a failed root device enumeration, see [`Host::set_enumeration_retries`]
+const DEFAULT_ENUMERATION_RETRIES: u8 = 3;
+/// Wait after the bus reset before retrying, multiplied by the attempt number
+const ENUMERATION_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// Reset recovery time (TRSTRCY), the device may ignore requests until it has passed
+const RESET_RECOVERY: Duration = Duration::from_millis(10);
+/// Time a device has to complete its enumeration, see [`Host::set_enumeration_timeout`]
+const DEFAULT_ENUMERATION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Hub polling interval while no hub is attached
+const HUB_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Resume recovery time (TRSMRCY), the device may ignore requests until it has passed
+const RESUME_RECOVERY: Duration = Duration::from_millis(10);
 
 pub trait HostDriver {
     type Bus: Bus;
     type Pipe: Pipe;
 
     fn start(self) -> (Self::Bus, Self::Pipe);
+
+    /// Starts the controller with one pipe per hardware channel, up to
+    /// [`pipe::MAX_PIPES`], for [`USBHostPipe::with_pipes`]. Each pipe keeps its
+    /// own device address and endpoint state in the hardware, the host selects
+    /// the address before every transaction.
+    ///
+    /// Controllers with a single channel keep the default, the pipe of
+    /// [`HostDriver::start`].
+    fn start_pipes(self) -> (Self::Bus, ArrayVec<Self::Pipe, { pipe::MAX_PIPES }>)
+    where
+        Self: Sized,
+    {
+        let (bus, pipe) = self.start();
+        (bus, [pipe].into_iter().collect())
+    }
+}
+
+/// Decides whether a device is enumerated, see [`Host::set_device_filter`]
+pub type DeviceFilter = fn(&DeviceDescriptor) -> bool;
+
+/// Settings applied to every device enumeration
+#[derive(Clone, Copy)]
+struct EnumerationConfig {
+    filter: Option<DeviceFilter>,
+    timeout: Duration,
+}
+
+/// Transfer submitted to the host, completed by [`Host::run_until_event`]
+enum Transfer {
+    Control {
+        device: DeviceHandle,
+        request: Request,
+        buffer: &'static mut [u8],
+    },
+    Interrupt {
+        channel: InterruptChannel,
+        buffer: &'static mut [u8],
+        /// The endpoint NAKed, it is polled again at this time
+        next: Instant,
+    },
 }
 
 pub(crate) enum HostState<const NR_HUBS: usize> {
@@ -44,14 +99,20 @@ pub(crate) enum HostState<const NR_HUBS: usize> {
     EnumerateRoot,
     DeviceAttached {
         hubs: ArrayVec<driver::hub::Hub, NR_HUBS>,
-        enumeration_in_progress: bool,
+        /// Hub port whose device is being enumerated, other ports wait for it
+        enumerating: Option<PortInfo>,
+    },
+    /// The bus is suspended, the hubs (and addresses) of the attached devices
+    /// are kept for the resume
+    Suspended {
+        hubs: ArrayVec<driver::hub::Hub, NR_HUBS>,
+        attached: bool,
     },
-    Suspended,
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 enum HostInternalEvent {
-    EnumerationBegin,
+    EnumerationBegin(PortInfo),
     EnumerationEnd,
     BusEvent(Event),
     HostEvent(HostEvent),
@@ -62,19 +123,52 @@ pub enum HostEvent {
     NewDevice {
         descriptor: DeviceDescriptor,
         handle: DeviceHandle,
+        /// Interfaces of the first configuration, empty if it couldn't be read
+        interfaces: DeviceInterfaces,
     },
     DeviceDetach {
         mask: DeviceDisconnectMask,
     },
+    /// A hub port reported over-current, the hub switched the port off
+    OverCurrent {
+        port: PortInfo,
+    },
+    /// The device filter rejected the device, it keeps its address but is
+    /// left unconfigured
+    DeviceRejected {
+        vid: u16,
+        pid: u16,
+        handle: DeviceHandle,
+    },
+    /// Enumerating a device failed, the host went back to disconnected
+    EnumerationError {
+        error: UsbHostError,
+    },
+    /// A transfer of [`Host::submit_control_transfer`] completed, `result` is
+    /// the number of bytes transferred
     ControlTransferResponse {
         result: Result<usize, UsbHostError>,
         buffer: &'static mut [u8],
     },
+    /// A transfer of [`Host::submit_interrupt_transfer`] completed, `result`
+    /// is the number of bytes transferred. The channel is handed back with
+    /// its data toggle for the next transfer.
     InterruptTransferResponse {
         result: Result<usize, UsbHostError>,
         buffer: &'static mut [u8],
+        channel: InterruptChannel,
     },
     Suspended,
+    /// The bus resumed from suspend without a device to attribute the resume
+    /// to, e.g. the controller resumed with the root port empty
+    Resumed,
+    /// A suspended device signaled remote wakeup. For a global suspend this is
+    /// the device on the root port, a hub there forwards the wakeup of the
+    /// devices behind it. Devices resumed with [`Host::resume`] or
+    /// [`Host::resume_port`] are not reported.
+    RemoteWakeup {
+        handle: DeviceHandle,
+    },
 }
 
 pub struct Host<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize> {
@@ -82,6 +176,9 @@ pub struct Host<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize
     bus: BusWrap<D>,
     pipe: &'a USBHostPipe<D, NR_DEVICES>,
     state: HostState<NR_HUBS>,
+    enumeration_retries: u8,
+    enumeration: EnumerationConfig,
+    transfer: Option<Transfer>,
 }
 
 impl<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize>
@@ -92,17 +189,97 @@ impl<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize>
             bus: BusWrap::new(bus),
             pipe,
             state: HostState::Disconnected,
+            enumeration_retries: DEFAULT_ENUMERATION_RETRIES,
+            enumeration: EnumerationConfig {
+                filter: None,
+                timeout: DEFAULT_ENUMERATION_TIMEOUT,
+            },
+            transfer: None,
             phantom: PhantomData,
         }
     }
 
+    /// Snapshot of the devices currently attached, see [`USBHostPipe::devices`]
+    pub async fn devices(&self) -> ArrayVec<AttachedDevice, NR_DEVICES> {
+        self.pipe.devices().await
+    }
+
+    /// Number of times a failed root device enumeration is retried, with a
+    /// bus reset before every retry. Defaults to 3.
+    pub fn set_enumeration_retries(&mut self, retries: u8) {
+        self.enumeration_retries = retries;
+    }
+
+    /// Called with the device descriptor of every new device, hubs included,
+    /// before it is enumerated further. Devices it returns false for are
+    /// reported with [`HostEvent::DeviceRejected`].
+    pub fn set_device_filter(&mut self, filter: DeviceFilter) {
+        self.enumeration.filter = Some(filter);
+    }
+
+    /// Time a device has from its reset until it is enumerated, a device that
+    /// takes longer loses its address and is ignored. Defaults to 5s.
+    pub fn set_enumeration_timeout(&mut self, timeout: Duration) {
+        self.enumeration.timeout = timeout;
+    }
+
+    /// Submits a control transfer to `device`, it is carried out while
+    /// [`Host::run_until_event`] runs and completes with a
+    /// [`HostEvent::ControlTransferResponse`].
+    ///
+    /// One transfer is pending at a time, the buffer is handed back if another
+    /// one is. Once started the transfer runs to completion, hub and bus
+    /// events wait for it.
+    pub fn submit_control_transfer(
+        &mut self,
+        device: DeviceHandle,
+        request: Request,
+        buffer: &'static mut [u8],
+    ) -> Result<(), &'static mut [u8]> {
+        if self.transfer.is_some() {
+            return Err(buffer);
+        }
+        self.transfer = Some(Transfer::Control {
+            device,
+            request,
+            buffer,
+        });
+        Ok(())
+    }
+
+    /// Submits an interrupt IN transfer, it is retried at the channel's
+    /// interval while the device NAKs and completes with a
+    /// [`HostEvent::InterruptTransferResponse`] once the device sent data.
+    ///
+    /// One transfer is pending at a time, the channel and buffer are handed
+    /// back if another one is.
+    pub fn submit_interrupt_transfer(
+        &mut self,
+        channel: InterruptChannel,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (InterruptChannel, &'static mut [u8])> {
+        if self.transfer.is_some() {
+            return Err((channel, buffer));
+        }
+        self.transfer = Some(Transfer::Interrupt {
+            channel,
+            buffer,
+            next: Instant::now(),
+        });
+        Ok(())
+    }
+
     pub async fn run_until_event(mut self) -> (Self, HostEvent) {
         loop {
             match self {
                 Host {
                     state: HostState::Disconnected,
                     ..
-                } => self.run_disconnected().await,
+                } => {
+                    if let Some(event) = self.run_disconnected().await {
+                        return (self, event);
+                    }
+                }
                 Host {
                     state: HostState::EnumerateRoot,
                     ..
@@ -116,14 +293,23 @@ impl<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize>
                     state:
                         HostState::DeviceAttached {
                             ref mut hubs,
-                            ref mut enumeration_in_progress,
+                            ref mut enumerating,
                         },
                     pipe,
                     ref mut bus,
+                    enumeration,
+                    ref mut transfer,
                     ..
                 } => {
-                    let (event, state) =
-                        Self::run_device_attached(pipe, bus, hubs, enumeration_in_progress).await;
+                    let (event, state) = Self::run_device_attached(
+                        pipe,
+                        bus,
+                        hubs,
+                        enumerating,
+                        enumeration,
+                        transfer,
+                    )
+                    .await;
                     if let Some(state) = state {
                         self.state = state;
                     }
@@ -132,11 +318,22 @@ impl<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize>
                     }
                 }
                 Host {
-                    state: HostState::Suspended,
+                    state:
+                        HostState::Suspended {
+                            ref mut hubs,
+                            attached,
+                        },
+                    pipe,
+                    ref mut bus,
                     ..
                 } => {
-                    self.state = HostState::Disconnected;
-                    return (self, HostEvent::Suspended);
+                    let (event, state) = Self::run_suspended(pipe, bus, hubs, attached).await;
+                    if let Some(state) = state {
+                        self.state = state;
+                    }
+                    if let Some(event) = event {
+                        return (self, event);
+                    }
                 }
             }
         }
@@ -146,9 +343,13 @@ impl<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize>
         pipe: &USBHostPipe<D, NR_DEVICES>,
         bus: &mut BusWrap<D>,
         hubs: &mut ArrayVec<Hub, NR_HUBS>,
-        enumeration_in_progress: &mut bool,
+        enumerating: &mut Option<PortInfo>,
+        enumeration: EnumerationConfig,
+        transfer: &mut Option<Transfer>,
     ) -> (Option<HostEvent>, Option<HostState<NR_HUBS>>) {
-        match Self::run_device_attached_inner(pipe, bus, hubs, *enumeration_in_progress).await {
+        match Self::run_device_attached_inner(pipe, bus, hubs, enumerating, enumeration, transfer)
+            .await
+        {
             Ok(Some(HostInternalEvent::BusEvent(event))) => match event {
                 Event::DeviceAttach => {
                     warn!("device attached while device already attached");
@@ -162,170 +363,642 @@ impl<'a, D: HostDriver, const NR_HUBS: usize, const NR_DEVICES: usize>
                         Some(HostState::Disconnected),
                     )
                 }
-                Event::Suspend => (None, Some(HostState::Suspended)),
-                Event::Resume => (None, Some(HostState::Disconnected)),
+                Event::Suspend => (
+                    Some(HostEvent::Suspended),
+                    Some(HostState::Suspended {
+                        hubs: core::mem::take(hubs),
+                        attached: true,
+                    }),
+                ),
+                // The topology is kept over a suspend, unless the root device
+                // went away in the meantime
+                Event::Resume => Self::detach_if_gone(pipe, bus).await,
             },
-            Ok(Some(HostInternalEvent::EnumerationBegin)) => {
-                *enumeration_in_progress = true;
+            Ok(Some(HostInternalEvent::EnumerationBegin(port))) => {
+                *enumerating = Some(port);
                 (None, None)
             }
             Ok(Some(HostInternalEvent::EnumerationEnd)) => {
-                *enumeration_in_progress = false;
+                *enumerating = None;
                 (None, None)
             }
-            Ok(Some(HostInternalEvent::HostEvent(e @ HostEvent::NewDevice { .. }))) => {
-                *enumeration_in_progress = false;
+            Ok(Some(HostInternalEvent::HostEvent(
+                e @ (HostEvent::NewDevice { .. } | HostEvent::DeviceRejected { .. }),
+            ))) => {
+                *enumerating = None;
                 (Some(e), None)
             }
             Ok(Some(HostInternalEvent::HostEvent(event))) => (Some(event), None),
             Ok(None) => (None, None),
             Err(e) => {
                 error!("{}", e);
-                (None, Some(HostState::Disconnected))
+                (
+                    Some(HostEvent::EnumerationError { error: e }),
+                    Some(HostState::Disconnected),
+                )
+            }
+        }
+    }
+
+    async fn run_suspended(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        bus: &mut BusWrap<D>,
+        hubs: &mut ArrayVec<Hub, NR_HUBS>,
+        attached: bool,
+    ) -> (Option<HostEvent>, Option<HostState<NR_HUBS>>) {
+        match bus.poll().await {
+            Event::Resume => {
+                // Host::resume leaves the suspended state itself, this is the device
+                trace!("remote wakeup");
+                Timer::after(RESUME_RECOVERY).await;
+                if !attached {
+                    return (Some(HostEvent::Resumed), Some(HostState::Disconnected));
+                }
+                if let (Some(event), state) = Self::detach_if_gone(pipe, bus).await {
+                    return (Some(event), state);
+                }
+                let event = match pipe.device_at(PortInfo::root()).await {
+                    Some(handle) => HostEvent::RemoteWakeup { handle },
+                    None => HostEvent::Resumed,
+                };
+                (
+                    Some(event),
+                    Some(HostState::DeviceAttached {
+                        hubs: core::mem::take(hubs),
+                        enumerating: None,
+                    }),
+                )
+            }
+            Event::DeviceDetach if attached => {
+                let mask = pipe.root_detach().await;
+                (
+                    Some(HostEvent::DeviceDetach { mask }),
+                    Some(HostState::Disconnected),
+                )
+            }
+            Event::DeviceDetach => (None, Some(HostState::Disconnected)),
+            Event::DeviceAttach => {
+                if attached {
+                    pipe.root_detach().await;
+                }
+                (None, Some(HostState::EnumerateRoot))
+            }
+            Event::Suspend => (None, None),
+        }
+    }
+
+    /// Detaches the root device if the controller no longer sees one, used after a resume
+    async fn detach_if_gone(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        bus: &mut BusWrap<D>,
+    ) -> (Option<HostEvent>, Option<HostState<NR_HUBS>>) {
+        if bus.speed().await.is_some() {
+            return (None, None);
+        }
+        debug!("root device detached during suspend");
+        let mask = pipe.root_detach().await;
+        (
+            Some(HostEvent::DeviceDetach { mask }),
+            Some(HostState::Disconnected),
+        )
+    }
+
+    /// Current (micro)frame number, see [`Bus::frame_number`]. `Unsupported`
+    /// if the controller can't provide it.
+    pub async fn frame_number(&mut self) -> Result<u16, UsbHostError> {
+        self.bus.frame_number().await
+    }
+
+    /// Suspends the bus, e.g. for low-power operation while no device activity
+    /// is expected. The attached devices keep their addresses, a remote wakeup
+    /// is reported with [`HostEvent::RemoteWakeup`] for the device on the root
+    /// port, or [`HostEvent::Resumed`] if the root port has no device.
+    pub async fn suspend(&mut self) -> Result<(), UsbHostError> {
+        let (hubs, attached) = match &mut self.state {
+            HostState::Disconnected => (ArrayVec::new(), false),
+            HostState::DeviceAttached { hubs, .. } => (core::mem::take(hubs), true),
+            HostState::Suspended { .. } => return Ok(()),
+            HostState::EnumerateRoot => return Err(UsbHostError::InvalidState),
+        };
+        if let Err(e) = self.bus.suspend().await {
+            if attached {
+                self.state = HostState::DeviceAttached {
+                    hubs,
+                    enumerating: None,
+                };
             }
+            return Err(e);
         }
+        self.state = HostState::Suspended { hubs, attached };
+        Ok(())
     }
 
-    fn remove_disconnected_hubs(hubs: &mut ArrayVec<Hub, NR_HUBS>, mask: &mut DeviceDisconnectMask) {
-        // Remove disconnected hubs from both the hubs array and the mask
-        let mut i = 0;
-        while i < hubs.len() {
-            let hub_addr = hubs[i].handle.address() as usize;
-            if mask.iter().any(|addr| hub_addr == addr) {
+    /// Resumes a bus suspended with [`Host::suspend`]
+    pub async fn resume(&mut self) -> Result<(), UsbHostError> {
+        let HostState::Suspended { hubs, attached } = &mut self.state else {
+            return Ok(());
+        };
+        let attached = *attached;
+        let hubs = core::mem::take(hubs);
+        self.bus.resume().await?;
+        self.state = if attached {
+            HostState::DeviceAttached {
+                hubs,
+                enumerating: None,
+            }
+        } else {
+            HostState::Disconnected
+        };
+        Ok(())
+    }
+
+    /// Selectively suspends a device behind a hub with SET_FEATURE(PORT_SUSPEND).
+    /// The device on the root port is suspended with [`Host::suspend`].
+    pub async fn suspend_port(&mut self, handle: DeviceHandle) -> Result<(), UsbHostError> {
+        let port = handle.port();
+        let pipe = self.pipe;
+        self.parent_hub(handle)?.suspend_port(pipe, port).await
+    }
+
+    /// Resumes a device suspended with [`Host::suspend_port`]
+    pub async fn resume_port(&mut self, handle: DeviceHandle) -> Result<(), UsbHostError> {
+        let port = handle.port();
+        let pipe = self.pipe;
+        self.parent_hub(handle)?.resume_port(pipe, port).await
+    }
+
+    /// Resets a device and enumerates it again, e.g. to recover a device that
+    /// stopped responding. The device on the root port gets a bus reset, other
+    /// devices a port reset on their hub. The addresses of the device and of
+    /// the devices behind it are freed, the returned mask lists them. The
+    /// device is reported again with [`HostEvent::NewDevice`].
+    pub async fn reset_device(
+        &mut self,
+        handle: DeviceHandle,
+    ) -> Result<DeviceDisconnectMask, UsbHostError> {
+        let port = handle.dev_info().port();
+        if self.pipe.device_at(port).await != Some(handle) {
+            return Err(UsbHostError::Detached);
+        }
+        let HostState::DeviceAttached { hubs, enumerating } = &mut self.state else {
+            return Err(UsbHostError::InvalidState);
+        };
+        // Only one device can be at address 0
+        if enumerating.is_some() {
+            return Err(UsbHostError::InvalidState);
+        }
+
+        if port == PortInfo::root() {
+            let mask = self.pipe.root_detach().await;
+            self.bus.reset().await;
+            self.state = HostState::EnumerateRoot;
+            return Ok(mask);
+        }
+
+        let hub = hubs
+            .iter_mut()
+            .find(|hub| Some(hub.handle.address()) == handle.parent_address())
+            .ok_or(UsbHostError::Detached)?;
+        hub.reset_port(self.pipe, handle.port()).await?;
+        let mask = self.pipe.dev_detach(port).await;
+        Self::remove_disconnected_hubs(hubs, enumerating, &mut mask.clone());
+        // The hub reports the end of the reset, the device is enumerated then
+        *enumerating = Some(port);
+        Ok(mask)
+    }
+
+    fn parent_hub(&mut self, handle: DeviceHandle) -> Result<&mut Hub, UsbHostError> {
+        let HostState::DeviceAttached { hubs, .. } = &mut self.state else {
+            return Err(UsbHostError::InvalidState);
+        };
+        match handle.parent_address() {
+            Some(0) | None => Err(UsbHostError::InvalidState),
+            Some(parent) => hubs
+                .iter_mut()
+                .find(|hub| hub.handle.address() == parent)
+                .ok_or(UsbHostError::Detached),
+        }
+    }
+
+    fn remove_disconnected_hubs(
+        hubs: &mut ArrayVec<Hub, NR_HUBS>,
+        enumerating: &mut Option<PortInfo>,
+        mask: &mut DeviceDisconnectMask,
+    ) {
+        // Remove disconnected hubs from both the hubs array and the mask.
+        // The mask covers the whole detached subtree, so downstream hubs are
+        // removed together with their parent. `retain` visits every hub exactly
+        // once, adjacent removals cannot skip an entry.
+        hubs.retain(|hub| {
+            let hub_addr = hub.handle.address();
+            if mask.contains(hub_addr) {
                 trace!("removing disconnected hub {}", hub_addr);
-                hubs.swap_remove(i);
-                mask.remove(hub_addr);
+                mask.remove(hub_addr as usize);
+                // A port of the hub may have been in the middle of a reset,
+                // nothing reports its end anymore
+                if enumerating.and_then(|port| port.parent_addr()) == Some(hub_addr) {
+                    *enumerating = None;
+                }
+                false
             } else {
-                i += 1;
+                true
             }
-        }
+        });
     }
 
     async fn run_device_attached_inner(
         pipe: &USBHostPipe<D, NR_DEVICES>,
         bus: &mut BusWrap<D>,
         hubs: &mut ArrayVec<Hub, NR_HUBS>,
-        enumeration_in_progress: bool,
+        enumerating: &mut Option<PortInfo>,
+        enumeration: EnumerationConfig,
+        transfer: &mut Option<Transfer>,
     ) -> Result<Option<HostInternalEvent>, UsbHostError> {
         let bus_fut = bus.poll();
+        let mut transfer_due = false;
+        let current = *enumerating;
+        // Polled at the shortest status change interval of all hubs
+        let interval = hubs
+            .iter()
+            .map(Hub::interval)
+            .min()
+            .unwrap_or(HUB_POLL_INTERVAL);
         let mut hubs_fut = async || {
             for hub in hubs.iter_mut() {
-                match hub.poll(pipe, enumeration_in_progress).await {
+                match hub.poll(pipe, current).await {
                     Ok(Some(event)) => {
                         return Some(event);
                     }
                     Ok(None) => (),
-                    // whomp whomp
+                    // Counted and logged by the hub, a hub that keeps failing
+                    // reports `HubEvent::Failed`
                     Err(_) => (),
                 }
             }
-            Timer::after(Duration::from_millis(100)).await;
+            // Only the wait between polls gives way to the submitted transfer,
+            // a hub poll cancelled half way would lose the port change it
+            // already acknowledged
+            transfer_due = matches!(
+                select(Timer::after(interval), Self::transfer_due(transfer)).await,
+                Either::Second(())
+            );
             None
         };
         let hubs_fut = hubs_fut();
 
         match select(hubs_fut, bus_fut).await {
             Either::First(Some(event)) => match event {
-                driver::hub::HubEvent::DeviceReset => {
-                    trace!("device reset, enumeration begin");
-                    Ok(Some(HostInternalEvent::EnumerationBegin))
+                driver::hub::HubEvent::DeviceReset(port) => {
+                    trace!("device reset on {}, enumeration begin", port);
+                    Ok(Some(HostInternalEvent::EnumerationBegin(port)))
                 }
                 driver::hub::HubEvent::DeviceAttach(devinfo) => {
                     trace!("Device attached: {:?}", devinfo);
-                    match Self::enumerate_device(pipe, bus, hubs, devinfo).await? {
-                        Some((desc, handle)) => {
-                            Ok(Some(HostInternalEvent::HostEvent(HostEvent::NewDevice {
-                                descriptor: desc,
-                                handle,
-                            })))
+                    match Self::enumerate_device(pipe, bus, hubs, devinfo, enumeration).await {
+                        Ok(Some(event)) => Ok(Some(HostInternalEvent::HostEvent(event))),
+                        Ok(None) => Ok(Some(HostInternalEvent::EnumerationEnd)),
+                        // Only this device is rejected, the rest of the bus keeps working
+                        Err(UsbHostError::AddressExhausted) => {
+                            error!("no address left for device {}", devinfo);
+                            Ok(Some(HostInternalEvent::EnumerationEnd))
                         }
-                        None => Ok(Some(HostInternalEvent::EnumerationEnd)),
+                        // The device keeps its address, unconfigured, until it is detached
+                        Err(e @ (UsbHostError::InsufficientPower | UsbHostError::HubCapacity)) => {
+                            error!("device {} rejected: {}", devinfo, e);
+                            Ok(Some(HostInternalEvent::EnumerationEnd))
+                        }
+                        Err(UsbHostError::EnumerationTimeout) => {
+                            error!("enumeration of device {} timed out", devinfo);
+                            // Release the address of the partially enumerated device
+                            pipe.dev_detach(devinfo.port()).await;
+                            Ok(Some(HostInternalEvent::EnumerationEnd))
+                        }
+                        Err(e) => Err(e),
                     }
                 }
                 driver::hub::HubEvent::DeviceDetach(portinfo) => {
                     trace!("device detached {}", portinfo);
+                    if *enumerating == Some(portinfo) {
+                        // Gone before the reset ended, let the queued ports go ahead
+                        *enumerating = None;
+                    }
+                    let mut mask = pipe.dev_detach(portinfo).await;
+                    Self::remove_disconnected_hubs(hubs, enumerating, &mut mask);
+                    Ok(Some(HostInternalEvent::HostEvent(
+                        HostEvent::DeviceDetach { mask },
+                    )))
+                }
+                driver::hub::HubEvent::Failed(portinfo) => {
+                    warn!("hub on {} keeps failing, detaching it", portinfo);
                     let mut mask = pipe.dev_detach(portinfo).await;
-                    Self::remove_disconnected_hubs(hubs, &mut mask);
+                    Self::remove_disconnected_hubs(hubs, enumerating, &mut mask);
                     Ok(Some(HostInternalEvent::HostEvent(
                         HostEvent::DeviceDetach { mask },
                     )))
                 }
+                driver::hub::HubEvent::RemoteWakeup(port) => {
+                    trace!("remote wakeup on {}", port);
+                    Ok(pipe.device_at(port).await.map(|handle| {
+                        HostInternalEvent::HostEvent(HostEvent::RemoteWakeup { handle })
+                    }))
+                }
+                driver::hub::HubEvent::OverCurrent(port) => {
+                    warn!("over-current on {}", port);
+                    Ok(Some(HostInternalEvent::HostEvent(HostEvent::OverCurrent {
+                        port,
+                    })))
+                }
             },
+            // Not raced with anything, a transfer cancelled half way would lose
+            // data the device already sent and leave the toggle out of sync
+            Either::First(None) if transfer_due => Ok(Self::run_transfer(pipe, transfer)
+                .await
+                .map(HostInternalEvent::HostEvent)),
             Either::First(None) => Ok(None),
             Either::Second(event) => Ok(Some(HostInternalEvent::BusEvent(event))),
         }
     }
 
-    async fn run_disconnected(&mut self) {
-        // TODO free all addresses.
+    /// Completes once the submitted transfer is due, never if there is none
+    async fn transfer_due(transfer: &Option<Transfer>) {
+        match transfer {
+            None => core::future::pending().await,
+            Some(Transfer::Control { .. }) => {}
+            Some(Transfer::Interrupt { next, .. }) => Timer::at(*next).await,
+        }
+    }
+
+    /// Carries out the submitted transfer, `None` while it isn't complete yet
+    /// (the interrupt endpoint NAKed)
+    async fn run_transfer(
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        transfer: &mut Option<Transfer>,
+    ) -> Option<HostEvent> {
+        let result = match transfer {
+            None => return None,
+            Some(Transfer::Control {
+                device,
+                request,
+                buffer,
+            }) => {
+                if pipe.device_at(device.dev_info().port()).await != Some(*device) {
+                    Err(UsbHostError::Detached)
+                } else {
+                    pipe.control_transfer(*device, request, buffer).await
+                }
+            }
+            Some(Transfer::Interrupt {
+                channel,
+                buffer,
+                next,
+            }) => {
+                let device = channel.device_handle;
+                if pipe.device_at(device.dev_info().port()).await != Some(device) {
+                    Err(UsbHostError::Detached)
+                } else {
+                    match pipe.interrupt_transfer(channel, buffer).await {
+                        Err(UsbHostError::NAK) => {
+                            *next = Instant::now() + channel.interval();
+                            return None;
+                        }
+                        result => result,
+                    }
+                }
+            }
+        };
+        Some(match transfer.take() {
+            Some(Transfer::Control { buffer, .. }) => {
+                HostEvent::ControlTransferResponse { result, buffer }
+            }
+            Some(Transfer::Interrupt {
+                channel, buffer, ..
+            }) => HostEvent::InterruptTransferResponse {
+                result,
+                buffer,
+                channel,
+            },
+            None => unreachable!(),
+        })
+    }
+
+    async fn run_disconnected(&mut self) -> Option<HostEvent> {
         self.state = match self.bus.poll().await {
-            Event::DeviceAttach => HostState::EnumerateRoot,
+            Event::DeviceAttach => {
+                // Addresses left over from a failed enumeration
+                self.pipe.root_detach().await;
+                HostState::EnumerateRoot
+            }
             Event::DeviceDetach => {
                 trace!("root device detached when disconnected");
                 HostState::Disconnected
             }
             Event::Suspend => {
                 trace!("host suspended");
-                HostState::Suspended
+                self.state = HostState::Suspended {
+                    hubs: ArrayVec::new(),
+                    attached: false,
+                };
+                return Some(HostEvent::Suspended);
             }
             Event::Resume => {
                 trace!("host resumed");
                 HostState::Disconnected
             }
         };
+        None
     }
 
     async fn enumerate_root(&mut self) -> Option<HostEvent> {
-        let mut hubs = ArrayVec::new();
-        //TODO: fix this unwrap
-        let speed = unwrap!(self.bus.speed().await);
-        trace!("Root device speed: {:?}", speed);
-        match Self::enumerate_device(&self.pipe, &mut self.bus, &mut hubs, DevInfo::root_device(speed))
-            .await
-        {
-            Ok(event) => {
-                self.state = HostState::DeviceAttached {
-                    hubs,
-                    enumeration_in_progress: false,
-                };
-                event.map(|(descriptor, handle)| HostEvent::NewDevice { descriptor, handle })
-            }
-            Err(e) => {
-                error!("{}", e);
+        let mut attempt = 0;
+        loop {
+            let mut hubs = ArrayVec::new();
+            let Some(speed) = self.bus.speed().await else {
+                // Detached between the attach event and enumeration
+                debug!("root device gone before enumeration");
                 self.state = HostState::Disconnected;
-                Some(HostEvent::Suspended)
+                return None;
+            };
+            trace!("Root device speed: {:?}", speed);
+            match Self::enumerate_device(
+                &self.pipe,
+                &mut self.bus,
+                &mut hubs,
+                DevInfo::root_device(speed),
+                self.enumeration,
+            )
+            .await
+            {
+                Ok(event) => {
+                    self.state = HostState::DeviceAttached {
+                        hubs,
+                        enumerating: None,
+                    };
+                    return event;
+                }
+                Err(e)
+                    if !matches!(e, UsbHostError::Detached)
+                        && attempt < self.enumeration_retries =>
+                {
+                    attempt += 1;
+                    warn!("root enumeration failed: {}, retry {}", e, attempt);
+                    // Release the address of a partially enumerated device
+                    self.pipe.root_detach().await;
+                    self.bus.reset().await;
+                    Timer::after(ENUMERATION_RETRY_BACKOFF * attempt as u32).await;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    self.state = HostState::Disconnected;
+                    return Some(HostEvent::EnumerationError { error: e });
+                }
             }
         }
     }
 
     /// Ok(None) if the device is a hub
-    /// Ok(Some((descriptor, handle))) if the device is not a hub
+    /// Ok(Some(NewDevice)) if the device is not a hub
+    /// Ok(Some(DeviceRejected)) if the filter rejected the device
     /// Err if there is an error
     async fn enumerate_device(
         pipe: &USBHostPipe<D, NR_DEVICES>,
         bus: &mut BusWrap<D>,
         hubs: &mut ArrayVec<Hub, NR_HUBS>,
         hubinfo: DevInfo,
-    ) -> Result<Option<(DeviceDescriptor, DeviceHandle)>, UsbHostError> {
+        enumeration: EnumerationConfig,
+    ) -> Result<Option<HostEvent>, UsbHostError> {
+        let deadline = Instant::now() + enumeration.timeout;
         let pipe_future = pipe.dev_attach(hubinfo);
         let bus_future = bus.wait_until_detach();
+        let timeout_future = Timer::at(deadline);
 
-        let (descriptor, handle) = match select(pipe_future, bus_future).await {
-            Either::First(res) => {
-                Ok(res?)
-            },
-            Either::Second(_) => {
-                Err(UsbHostError::Detached)
-            },
-        }?;
+        let (descriptor, handle) = match select3(pipe_future, bus_future, timeout_future).await {
+            Either3::First(res) => res?,
+            Either3::Second(_) => return Err(UsbHostError::Detached),
+            Either3::Third(_) => return Err(UsbHostError::EnumerationTimeout),
+        };
 
-        if descriptor.device_class == UsbBaseClass::Hub.into() {
-            let hub = driver::hub::Hub::new(pipe, handle, descriptor).await?;
+        let filter = enumeration.filter;
+        if filter.is_some_and(|filter| !filter(&descriptor)) {
+            let (vid, pid) = (descriptor.id_vendor, descriptor.id_product);
+            debug!("device {:04x}:{:04x} rejected", vid, pid);
+            return Ok(Some(HostEvent::DeviceRejected { vid, pid, handle }));
+        }
+
+        let mut interfaces = DeviceInterfaces::default();
+        if descriptor.base_class() != Some(UsbBaseClass::Hub) {
+            // The drivers read the configuration again from the cache
+            match with_deadline(deadline, pipe.device_interfaces(handle))
+                .await
+                .map_err(|_| UsbHostError::EnumerationTimeout)?
+            {
+                Ok(device_interfaces) => interfaces = device_interfaces,
+                Err(e) => warn!(
+                    "failed to read the interfaces of {}: {}",
+                    handle.address(),
+                    e
+                ),
+            }
+        }
+        // Some hubs declare the hub class on their interface only
+        let interface_hub = descriptor.base_class() == Some(UsbBaseClass::Unspecified)
+            && interfaces.find(UsbBaseClass::Hub.into()).is_some();
+
+        if descriptor.base_class() == Some(UsbBaseClass::Hub) || interface_hub {
+            let hub = with_deadline(deadline, driver::hub::Hub::new(pipe, handle, descriptor))
+                .await
+                .map_err(|_| UsbHostError::EnumerationTimeout)??;
             hubs.try_push(hub).map_err(|_| UsbHostError::HubCapacity)?;
 
             Ok(None)
         } else {
-            Ok(Some((descriptor, handle)))
+            Ok(Some(HostEvent::NewDevice {
+                descriptor,
+                handle,
+                interfaces,
+            }))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MockBus, MockDriver, MockPipe};
+    use embassy_futures::block_on;
+    use types::UsbSpeed;
+
+    #[test]
+    fn low_speed_root_device_is_enumerated_at_low_speed() {
+        let mock = MockPipe::default();
+        let pipe = USBHostPipe::<MockDriver, 4>::new(mock.clone());
+        let mut host = Host::<MockDriver, 1, 4>::new(MockBus(Some(UsbSpeed::LowSpeed)), &pipe);
+        #[rustfmt::skip]
+        let descriptor = [
+            18, 1, 0x10, 0x01, 0, 0, 0, 8, 0x6D, 0x04, 0x16, 0xC3, 0x00, 0x01, 1, 2, 0, 1,
+        ];
+        mock.push_in(Ok(&descriptor[..8]));
+        for packet in descriptor.chunks(8) {
+            mock.push_in(Ok(packet));
+        }
+
+        let event = block_on(host.enumerate_root());
+        let Some(HostEvent::NewDevice { handle, .. }) = event else {
+            panic!("root device not enumerated");
+        };
+        assert!(handle.speed() == UsbSpeed::LowSpeed);
+        assert_eq!(handle.max_packet_size(), 8);
+        let devices = block_on(host.devices());
+        assert!(devices[0].speed == UsbSpeed::LowSpeed);
+    }
+
+    #[test]
+    fn hub_port_detach_frees_downstream_hubs_and_addresses() {
+        let mock = MockPipe::default();
+        let pipe = USBHostPipe::<MockDriver, 8>::new(mock.clone());
+        let mut host = Host::<MockDriver, 4, 8>::new(MockBus(Some(UsbSpeed::FullSpeed)), &pipe);
+        let attach = |parent: Option<DeviceHandle>, port| {
+            let dev_info = match parent {
+                Some(hub) => DevInfo::new(hub.address(), port, None, 0, UsbSpeed::FullSpeed),
+                None => DevInfo::root_device(UsbSpeed::FullSpeed),
+            };
+            block_on(pipe.assign_device_address(64, dev_info)).unwrap()
+        };
+        let root_hub = attach(None, 0);
+        let hub = attach(Some(root_hub), 1);
+        let leaf_hub = attach(Some(hub), 1);
+        let device = attach(Some(leaf_hub), 2);
+        let root_hub_device = attach(Some(root_hub), 2);
+        let mut hubs: ArrayVec<Hub, 4> = [root_hub, hub, leaf_hub]
+            .into_iter()
+            .map(|handle| Hub::mock(handle, 4))
+            .collect();
+        let mut enumerating = None;
+
+        // Port 1 of the root hub changed, its device is gone
+        mock.push_in(Ok(&[0b10]));
+        mock.push_in(Ok(&[0x00, 0x01, 0x01, 0x00]));
+        let event = block_on(Host::run_device_attached_inner(
+            &pipe,
+            &mut host.bus,
+            &mut hubs,
+            &mut enumerating,
+            host.enumeration,
+            &mut host.transfer,
+        ));
+
+        let Ok(Some(HostInternalEvent::HostEvent(HostEvent::DeviceDetach { mask }))) = event else {
+            panic!("hub port detach not reported");
+        };
+        // The removed hubs are dropped from the reported mask
+        let freed: Vec<usize> = mask.iter().collect();
+        assert_eq!(freed, [device.address() as usize]);
+        assert!(hubs.len() == 1 && hubs[0].handle == root_hub);
+        let remaining: Vec<u8> = block_on(host.devices())
+            .iter()
+            .map(|device| device.address)
+            .collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.contains(&root_hub.address()));
+        assert!(remaining.contains(&root_hub_device.address()));
+    }
+}
@@ -1,3 +1,7 @@
+//! Logging macros, forwarding to `defmt` or `log` depending on the enabled
+//! feature, `defmt` wins if both are enabled.
+
+#[allow(unused_macros)]
 #[collapse_debuginfo(yes)]
 macro_rules! panic {
     ($($x:tt)*) => {
@@ -16,7 +20,9 @@ macro_rules! trace {
         {
             #[cfg(feature = "defmt")]
             ::defmt::trace!($s $(, $x)*);
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(all(feature = "log", not(feature = "defmt")))]
+            ::log::trace!($s $(, $crate::macros::LogFmt(&$x))*);
+            #[cfg(not(any(feature = "defmt", feature = "log")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -28,7 +34,9 @@ macro_rules! debug {
         {
             #[cfg(feature = "defmt")]
             ::defmt::debug!($s $(, $x)*);
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(all(feature = "log", not(feature = "defmt")))]
+            ::log::debug!($s $(, $crate::macros::LogFmt(&$x))*);
+            #[cfg(not(any(feature = "defmt", feature = "log")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -41,7 +49,9 @@ macro_rules! info {
         {
             #[cfg(feature = "defmt")]
             ::defmt::info!($s $(, $x)*);
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(all(feature = "log", not(feature = "defmt")))]
+            ::log::info!($s $(, $crate::macros::LogFmt(&$x))*);
+            #[cfg(not(any(feature = "defmt", feature = "log")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -53,7 +63,9 @@ macro_rules! warn {
         {
             #[cfg(feature = "defmt")]
             ::defmt::warn!($s $(, $x)*);
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(all(feature = "log", not(feature = "defmt")))]
+            ::log::warn!($s $(, $crate::macros::LogFmt(&$x))*);
+            #[cfg(not(any(feature = "defmt", feature = "log")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -65,7 +77,9 @@ macro_rules! error {
         {
             #[cfg(feature = "defmt")]
             ::defmt::error!($s $(, $x)*);
-            #[cfg(not(feature = "defmt"))]
+            #[cfg(all(feature = "log", not(feature = "defmt")))]
+            ::log::error!($s $(, $crate::macros::LogFmt(&$x))*);
+            #[cfg(not(any(feature = "defmt", feature = "log")))]
             let _ = ($( & $x ),*);
         }
     };
@@ -84,3 +98,36 @@ macro_rules! unwrap {
         }
     }};
 }
+
+/// Formats log arguments written for defmt with `log`: `{}` prints the
+/// `Debug` representation, the hex formats are passed through.
+#[cfg(all(feature = "log", not(feature = "defmt")))]
+pub(crate) struct LogFmt<'a, T: ?Sized>(pub &'a T);
+
+#[cfg(all(feature = "log", not(feature = "defmt")))]
+impl<T: core::fmt::Debug + ?Sized> core::fmt::Display for LogFmt<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.0, f)
+    }
+}
+
+#[cfg(all(feature = "log", not(feature = "defmt")))]
+impl<T: core::fmt::Debug + ?Sized> core::fmt::Debug for LogFmt<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.0, f)
+    }
+}
+
+#[cfg(all(feature = "log", not(feature = "defmt")))]
+impl<T: core::fmt::LowerHex + ?Sized> core::fmt::LowerHex for LogFmt<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::LowerHex::fmt(self.0, f)
+    }
+}
+
+#[cfg(all(feature = "log", not(feature = "defmt")))]
+impl<T: core::fmt::UpperHex + ?Sized> core::fmt::UpperHex for LogFmt<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::UpperHex::fmt(self.0, f)
+    }
+}
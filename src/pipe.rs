@@ -1,16 +1,76 @@
-use embassy_futures::select::{select, Either};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::Timer;
+use arrayvec::ArrayVec;
+use embassy_futures::select::{select, select_array, Either};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    mutex::{Mutex, MutexGuard},
+};
+use embassy_time::{Duration, Timer};
 
 use crate::{
-    descriptor::{DescriptorIterator, DeviceDescriptor},
-    device_addr::DeviceDisconnectMask,
+    descriptor::{
+        bos::{Bos, BOS_HEADER_SIZE},
+        ConfigurationAttributes, ConfigurationDescriptor, Descriptor, DescriptorIterator,
+        DescriptorType, DeviceDescriptor, DeviceQualifier, ParsingError,
+    },
+    device_addr::{AttachedDevice, DeviceDisconnectMask, MAX_PATH_LEN},
     errors::UsbHostError,
     request::{self, Request, StandardDeviceRequest},
-    types::{self, DataTog, DevInfo, EndpointType, InterruptChannel, PortInfo, UsbSpeed},
+    types::{
+        self, BulkChannel, DataTog, DevInfo, DeviceInterfaces, DeviceStatus, EndpointAddress,
+        EndpointStatus, EndpointType, InterruptChannel, IsoChannel, PortInfo, UsbSpeed,
+    },
     DeviceAddressManager, DeviceHandle, HostDriver, TRANSFER_TIMEOUT,
 };
 
+/// Feature selector of SET_FEATURE / CLEAR_FEATURE for the device
+const DEVICE_REMOTE_WAKEUP: u16 = 1;
+
+/// Largest number of pipes (hardware channels) a [`USBHostPipe`] runs transfers on
+pub const MAX_PIPES: usize = 4;
+
+/// Default number of NAKs retried in a single stage
+const DEFAULT_NAK_LIMIT: u32 = 4000;
+
+/// Time the TT needs after a start split before the complete split can pick
+/// up the result: its think time (in full-speed bit times) plus the classic
+/// speed transaction of `len` data bytes on the downstream bus
+fn split_delay(think_time: u8, speed: UsbSpeed, len: usize) -> Duration {
+    // Token, data and handshake packets with worst case bit stuffing
+    let transaction_bits = (len as u64 + 12) * 8 * 7 / 6;
+    let transaction_bits = match speed {
+        UsbSpeed::LowSpeed => transaction_bits * 8,
+        _ => transaction_bits,
+    };
+    // 12 full-speed bit times per microsecond
+    Duration::from_micros((think_time as u64 + transaction_bits).div_ceil(12))
+}
+
+/// Transaction translator a low or full-speed device is reached through
+#[derive(Clone, Copy)]
+struct SplitTarget {
+    /// Address of the high-speed hub with the TT
+    hub_addr: u8,
+    /// Port of the device on that hub
+    port: u8,
+    /// TT think time in full-speed bit times
+    think_time: u8,
+    /// Speed of the device on the classic bus, sent in the split token
+    speed: UsbSpeed,
+}
+
+impl SplitTarget {
+    /// TT of `dev_info`, `None` if the device is reached without splits
+    fn of(dev_info: &DevInfo) -> Option<Self> {
+        let (hub_addr, port) = dev_info.transaction_translator()?;
+        Some(SplitTarget {
+            hub_addr,
+            port,
+            think_time: dev_info.tt_think_time(),
+            speed: dev_info.speed(),
+        })
+    }
+}
+
 // not Send anyways
 #[allow(async_fn_in_trait)]
 pub trait Pipe {
@@ -49,27 +109,141 @@ pub trait Pipe {
         wait_for_reply: bool,
         buf: Option<&[u8]>,
     ) -> Result<(), UsbHostError>;
+
+    /// Sends a PING to a high-speed bulk / control OUT endpoint, returning
+    /// `Ok(())` on ACK, `Err(NAK)` or `Err(NYET)` if the endpoint has no room
+    /// for a packet yet. Hardware without PING support can leave the default,
+    /// which reports the endpoint as ready.
+    async fn ping(&mut self, endpoint: u8) -> Result<(), UsbHostError> {
+        let _ = endpoint;
+        Ok(())
+    }
+}
+
+/// Size of the buffer returned by [`USBHostPipe::descriptor_buffer`]
+pub const DESCRIPTOR_BUFFER_SIZE: usize = 255;
+
+/// Largest bulk max packet size, high-speed
+pub(crate) const MAX_BULK_PACKET_SIZE: usize = 512;
+
+/// Error of [`USBHostPipe::bulk_write_stream`]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BulkStreamError<E> {
+    Usb(UsbHostError),
+    /// The reader failed, the data read before was sent
+    Read(E),
+}
+
+impl<E> From<UsbHostError> for BulkStreamError<E> {
+    fn from(e: UsbHostError) -> Self {
+        BulkStreamError::Usb(e)
+    }
+}
+
+/// Largest configuration descriptor kept by [`ConfigurationCache`], the size of
+/// the buffer the drivers read it into
+const CONFIGURATION_CACHE_SIZE: usize = DESCRIPTOR_BUFFER_SIZE;
+
+/// The last complete configuration descriptor read, so that the drivers offered
+/// a composite device in turn do not read it again
+struct ConfigurationCache {
+    /// Device address, 0 when empty
+    address: u8,
+    index: u8,
+    len: usize,
+    data: [u8; CONFIGURATION_CACHE_SIZE],
+    /// Device address and header of the last configuration descriptor read,
+    /// also kept for descriptors too long for `data`
+    header: Option<(u8, ConfigurationDescriptor)>,
+}
+
+impl ConfigurationCache {
+    const fn new() -> Self {
+        Self {
+            address: 0,
+            index: 0,
+            len: 0,
+            data: [0; CONFIGURATION_CACHE_SIZE],
+            header: None,
+        }
+    }
+
+    fn get(&self, address: u8, index: u8) -> Option<&[u8]> {
+        (self.address != 0 && self.address == address && self.index == index)
+            .then(|| &self.data[..self.len])
+    }
+
+    /// Keeps `data` if it is the complete descriptor, i.e. wTotalLength long,
+    /// and its header in any case
+    fn insert(&mut self, address: u8, index: u8, data: &[u8]) {
+        self.insert_header(address, data);
+        if data.len() < 4
+            || data.len() > CONFIGURATION_CACHE_SIZE
+            || u16::from_le_bytes([data[2], data[3]]) as usize != data.len()
+        {
+            return;
+        }
+        self.address = address;
+        self.index = index;
+        self.len = data.len();
+        self.data[..data.len()].copy_from_slice(data);
+    }
+
+    /// Keeps the configuration descriptor header `data` starts with
+    fn insert_header(&mut self, address: u8, data: &[u8]) {
+        if let Some(Ok(Descriptor::Configuration(desc))) = DescriptorIterator::new(data).next() {
+            self.header = Some((address, desc.clone()));
+        }
+    }
+
+    /// Header of the cached configuration of `address` if its
+    /// bConfigurationValue is `value`
+    fn configuration(&self, address: u8, value: u8) -> Option<ConfigurationDescriptor> {
+        match &self.header {
+            Some((cached, desc)) if *cached == address && desc.value == value => Some(desc.clone()),
+            _ => None,
+        }
+    }
+
+    fn invalidate(&mut self, mask: &DeviceDisconnectMask) {
+        if mask.contains(self.address) {
+            self.address = 0;
+        }
+        if self
+            .header
+            .as_ref()
+            .is_some_and(|(address, _)| mask.contains(*address))
+        {
+            self.header = None;
+        }
+    }
 }
 
-struct USBHostPipeInner<D: HostDriver, const NR_DEVICES: usize> {
+struct USBHostPipeInner<D: HostDriver> {
     pipe: D::Pipe,
-    address_alloc: DeviceAddressManager<NR_DEVICES>,
+    /// Per stage timeout of transfers without an explicit timeout
+    default_timeout: Duration,
+    /// NAKs retried before a transfer fails with [`UsbHostError::NakTimeout`]
+    nak_limit: u32,
 }
 
 /// wrapper around the underlying pipe implementation with support for split transactions
-impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
+impl<D: HostDriver> USBHostPipeInner<D> {
     async fn split_setup(
         &mut self,
-        tt_addr: u8,
-        tt_port: u8,
+        tt: SplitTarget,
         address: u8,
-        speed: UsbSpeed,
         req: &Request,
     ) -> Result<(), UsbHostError> {
+        let delay = split_delay(tt.think_time, tt.speed, core::mem::size_of::<Request>());
+        // NAKs of the SSPLIT and NYETs of the CSPLIT, a TT that never
+        // completes the SETUP can't retry forever
+        let mut naks = 0;
         loop {
-            self.pipe.set_addr(tt_addr);
+            self.pipe.set_addr(tt.hub_addr);
             self.pipe
-                .split(false, tt_port, EndpointType::Control, speed)
+                .split(false, tt.port, EndpointType::Control, tt.speed)
                 .await?;
 
             self.pipe.set_addr(address);
@@ -79,6 +253,10 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
             match setup_fut.await {
                 Ok(()) => break,
                 Err(UsbHostError::NAK) => {
+                    naks += 1;
+                    if naks >= self.nak_limit {
+                        return Err(UsbHostError::NakTimeout);
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -88,15 +266,20 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         }
 
         loop {
-            self.pipe.set_addr(tt_addr);
+            Timer::after(delay).await;
+            self.pipe.set_addr(tt.hub_addr);
             self.pipe
-                .split(true, tt_port, EndpointType::Control, speed)
+                .split(true, tt.port, EndpointType::Control, tt.speed)
                 .await?;
             self.pipe.set_addr(address);
             let setup_fut = self.pipe.setup(None);
             match setup_fut.await {
                 Ok(()) => return Ok(()),
                 Err(UsbHostError::NYET) => {
+                    naks += 1;
+                    if naks >= self.nak_limit {
+                        return Err(UsbHostError::NakTimeout);
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -111,14 +294,17 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         dev_info: &DevInfo,
         address: u8,
         req: &Request,
+        timeout: Duration,
     ) -> Result<(), UsbHostError> {
-        let timeout_fut = Timer::after(TRANSFER_TIMEOUT);
+        let timeout_fut = Timer::after(timeout);
         #[cfg(not(target_endian = "little"))]
         compile_error!("Only little endian supported");
-        if let Some((tt_addr, tt_port)) = dev_info.transaction_translator() {
-            return self
-                .split_setup(tt_addr, tt_port, address, dev_info.speed(), req)
-                .await;
+        if let Some(tt) = SplitTarget::of(dev_info) {
+            let fut = self.split_setup(tt, address, req);
+            return match select(timeout_fut, fut).await {
+                Either::First(_) => Err(UsbHostError::TransferTimeout),
+                Either::Second(r) => r,
+            };
         }
         self.pipe.set_addr(address);
         let setup_fut = self.pipe.setup(Some(unsafe {
@@ -130,6 +316,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn data_in_with_retry(
         &mut self,
         dev_info: &DevInfo,
@@ -138,14 +325,28 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         endpoint_type: EndpointType,
         tog: DataTog,
         buf: &mut [u8],
+        timeout: Duration,
     ) -> Result<usize, UsbHostError> {
+        let mut naks = 0;
         loop {
             match self
-                .data_in(dev_info, address, endpoint, endpoint_type, tog, buf)
+                .data_in(
+                    dev_info,
+                    address,
+                    endpoint,
+                    endpoint_type,
+                    tog,
+                    buf,
+                    timeout,
+                )
                 .await
             {
                 Ok(size) => return Ok(size),
                 Err(UsbHostError::NAK) => {
+                    naks += 1;
+                    if naks >= self.nak_limit {
+                        return Err(UsbHostError::NakTimeout);
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -157,27 +358,28 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
 
     async fn split_data_in(
         &mut self,
-        tt_addr: u8,
-        tt_port: u8,
+        tt: SplitTarget,
         address: u8,
         endpoint: u8,
         endpoint_type: EndpointType,
-        speed: UsbSpeed,
         tog: DataTog,
         buf: &mut [u8],
     ) -> Result<usize, UsbHostError> {
         let wait_for_reply = match endpoint_type {
-            EndpointType::Control => true,
+            EndpointType::Control | EndpointType::Bulk => true,
             EndpointType::Interrupt => false,
-            _ => todo!(),
+            // TODO: isochronous split transactions
+            EndpointType::Isochronous => return Err(UsbHostError::Unsupported),
         };
+        let delay = split_delay(tt.think_time, tt.speed, buf.len());
 
         for _ in 0..3 {
+            let mut naks = 0;
             loop {
-                self.pipe.set_addr(tt_addr);
+                self.pipe.set_addr(tt.hub_addr);
                 // TODO: this is a huge problem, fix
                 self.pipe
-                    .split(false, tt_port, endpoint_type, speed)
+                    .split(false, tt.port, endpoint_type, tt.speed)
                     .await?;
                 self.pipe.set_addr(address);
                 let in_fut = self
@@ -189,6 +391,10 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
                         break;
                     }
                     Err(UsbHostError::NAK) => {
+                        naks += 1;
+                        if naks >= self.nak_limit {
+                            return Err(UsbHostError::NakTimeout);
+                        }
                         continue;
                     }
                     Err(e) => {
@@ -201,8 +407,11 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
 
             // Do the csplit, retry on NYET
             loop {
-                self.pipe.set_addr(tt_addr);
-                self.pipe.split(true, tt_port, endpoint_type, speed).await?;
+                Timer::after(delay).await;
+                self.pipe.set_addr(tt.hub_addr);
+                self.pipe
+                    .split(true, tt.port, endpoint_type, tt.speed)
+                    .await?;
                 self.pipe.set_addr(address);
                 let in_fut = self.pipe.data_in(endpoint, tog, true, false, buf);
                 match in_fut.await {
@@ -218,7 +427,6 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
                         // if endpoint_type == EndpointType::Interrupt {
                         // Maybe do something speical? consider the spec draw these differently
                         // }
-                        Timer::after_micros(20).await;
                         csplit_count += 1;
                         if csplit_count >= 5 {
                             break;
@@ -236,6 +444,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         Err(UsbHostError::STALL)
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn data_in(
         &mut self,
         dev_info: &DevInfo,
@@ -244,19 +453,11 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         endpoint_type: EndpointType,
         tog: DataTog,
         buf: &mut [u8],
+        timeout: Duration,
     ) -> Result<usize, UsbHostError> {
-        let timeout_fut = Timer::after(TRANSFER_TIMEOUT);
-        if let Some((tt_addr, tt_port)) = dev_info.transaction_translator() {
-            let fut = self.split_data_in(
-                tt_addr,
-                tt_port,
-                address,
-                endpoint,
-                endpoint_type,
-                dev_info.speed(),
-                tog,
-                buf,
-            );
+        let timeout_fut = Timer::after(timeout);
+        if let Some(tt) = SplitTarget::of(dev_info) {
+            let fut = self.split_data_in(tt, address, endpoint, endpoint_type, tog, buf);
             match select(timeout_fut, fut).await {
                 Either::First(_) => Err(UsbHostError::TransferTimeout),
                 Either::Second(r) => r,
@@ -270,6 +471,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn data_out_with_retry(
         &mut self,
         dev_info: &DevInfo,
@@ -278,14 +480,28 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         endpoint_type: EndpointType,
         tog: DataTog,
         buf: &[u8],
+        timeout: Duration,
     ) -> Result<(), UsbHostError> {
+        let mut naks = 0;
         loop {
             match self
-                .data_out(dev_info, address, endpoint, endpoint_type, tog, buf)
+                .data_out(
+                    dev_info,
+                    address,
+                    endpoint,
+                    endpoint_type,
+                    tog,
+                    buf,
+                    timeout,
+                )
                 .await
             {
                 Ok(()) => return Ok(()),
                 Err(UsbHostError::NAK) => {
+                    naks += 1;
+                    if naks >= self.nak_limit {
+                        return Err(UsbHostError::NakTimeout);
+                    }
                     continue;
                 }
                 Err(e) => {
@@ -295,38 +511,84 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         }
     }
 
+    /// PING protocol for high-speed OUT endpoints: the data is only sent once
+    /// the endpoint ACKs a PING, instead of being sent again on every NAK
+    async fn ping_data_out(
+        &mut self,
+        endpoint: u8,
+        tog: DataTog,
+        buf: &[u8],
+    ) -> Result<(), UsbHostError> {
+        let mut naks = 0;
+        loop {
+            match self.pipe.ping(endpoint).await {
+                Ok(()) => {}
+                Err(UsbHostError::NAK | UsbHostError::NYET) => {
+                    naks += 1;
+                    if naks >= self.nak_limit {
+                        return Err(UsbHostError::NakTimeout);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            match self.pipe.data_out(endpoint, tog, true, Some(buf)).await {
+                // NYET: the data was accepted, but the endpoint has no room for the next packet
+                Ok(()) | Err(UsbHostError::NYET) => return Ok(()),
+                // The PING raced with the endpoint filling up, PING again
+                Err(UsbHostError::NAK) => {
+                    naks += 1;
+                    if naks >= self.nak_limit {
+                        return Err(UsbHostError::NakTimeout);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// SSPLIT/CSPLIT OUT transaction through the hub's TT. Not bounded in time
+    /// by itself, only call it through [`Self::data_out`] which applies the timeout.
     async fn split_data_out(
         &mut self,
-        tt_addr: u8,
-        tt_port: u8,
+        tt: SplitTarget,
         address: u8,
         endpoint: u8,
         endpoint_type: EndpointType,
-        speed: UsbSpeed,
         tog: DataTog,
         buf: &[u8],
     ) -> Result<(), UsbHostError> {
         let wait_for_reply = match endpoint_type {
-            EndpointType::Control => true,
+            EndpointType::Control | EndpointType::Bulk => true,
             EndpointType::Interrupt => false,
-            _ => todo!(),
+            // TODO: isochronous split transactions
+            EndpointType::Isochronous => return Err(UsbHostError::Unsupported),
         };
+        let delay = split_delay(tt.think_time, tt.speed, buf.len());
+        // NAKs of the SSPLIT and the CSPLIT both restart the SSPLIT, count
+        // them together so a device that never accepts the data can't retry forever
+        let mut naks = 0;
 
-        for _ in 0..3 {
+        let mut attempts = 0;
+        while attempts < 3 {
             loop {
-                self.pipe.set_addr(tt_addr);
+                self.pipe.set_addr(tt.hub_addr);
                 // TODO: this is a huge problem, fix
                 self.pipe
-                    .split(false, tt_port, endpoint_type, speed)
+                    .split(false, tt.port, endpoint_type, tt.speed)
                     .await?;
                 self.pipe.set_addr(address);
-                let in_fut = self.pipe.data_out(endpoint, tog, wait_for_reply, Some(buf));
+                let out_fut = self.pipe.data_out(endpoint, tog, wait_for_reply, Some(buf));
 
-                match in_fut.await {
-                    Ok(_) => {
+                match out_fut.await {
+                    Ok(()) => {
                         break;
                     }
                     Err(UsbHostError::NAK) => {
+                        naks += 1;
+                        if naks >= self.nak_limit {
+                            return Err(UsbHostError::NakTimeout);
+                        }
                         continue;
                     }
                     Err(e) => {
@@ -339,20 +601,31 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
 
             // Do the csplit, retry on NYET
             loop {
-                self.pipe.set_addr(tt_addr);
-                self.pipe.split(true, tt_port, endpoint_type, speed).await?;
+                Timer::after(delay).await;
+                self.pipe.set_addr(tt.hub_addr);
+                self.pipe
+                    .split(true, tt.port, endpoint_type, tt.speed)
+                    .await?;
                 self.pipe.set_addr(address);
-                let in_fut = self.pipe.data_out(endpoint, tog, true, None);
-                match in_fut.await {
-                    Ok(size) => return Ok(size),
+                let out_fut = self.pipe.data_out(endpoint, tog, true, None);
+                match out_fut.await {
+                    Ok(()) => return Ok(()),
                     Err(UsbHostError::NYET) => {
-                        Timer::after_micros(20).await;
                         csplit_count += 1;
                         if csplit_count >= 5 {
+                            attempts += 1;
                             break;
                         }
                         continue;
                     }
+                    Err(UsbHostError::NAK) => {
+                        // The TT got a NAK from the device, the data has to be sent again
+                        naks += 1;
+                        if naks >= self.nak_limit {
+                            return Err(UsbHostError::NakTimeout);
+                        }
+                        break;
+                    }
                     Err(e) => {
                         return Err(e);
                     }
@@ -363,6 +636,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         Err(UsbHostError::STALL)
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn data_out(
         &mut self,
         dev_info: &DevInfo,
@@ -371,19 +645,19 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
         endpoint_type: EndpointType,
         tog: DataTog,
         buf: &[u8],
+        timeout: Duration,
     ) -> Result<(), UsbHostError> {
-        let timeout_fut = Timer::after(TRANSFER_TIMEOUT);
-        if let Some((tt_addr, tt_port)) = dev_info.transaction_translator() {
-            let fut = self.split_data_out(
-                tt_addr,
-                tt_port,
-                address,
-                endpoint,
-                endpoint_type,
-                dev_info.speed(),
-                tog,
-                buf,
-            );
+        let timeout_fut = Timer::after(timeout);
+        if let Some(tt) = SplitTarget::of(dev_info) {
+            let fut = self.split_data_out(tt, address, endpoint, endpoint_type, tog, buf);
+            match select(timeout_fut, fut).await {
+                Either::First(_) => Err(UsbHostError::TransferTimeout),
+                Either::Second(r) => r,
+            }
+        } else if dev_info.speed() == UsbSpeed::HighSpeed
+            && matches!(endpoint_type, EndpointType::Bulk | EndpointType::Control)
+        {
+            let fut = self.ping_data_out(endpoint, tog, buf);
             match select(timeout_fut, fut).await {
                 Either::First(_) => Err(UsbHostError::TransferTimeout),
                 Either::Second(r) => r,
@@ -398,17 +672,128 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipeInner<D, NR_DEVICES> {
     }
 }
 
+/// Device addresses and cached configurations, shared by all pipes
+struct Devices<const NR_DEVICES: usize> {
+    address_alloc: DeviceAddressManager<NR_DEVICES>,
+    configuration_cache: ConfigurationCache,
+    /// Device address and configuration set for the drivers of a
+    /// [`Composite`](crate::driver::composite::Composite), see
+    /// [`USBHostPipe::begin_composed`]
+    composed: Option<(u8, u8)>,
+}
+
+impl<const NR_DEVICES: usize> Devices<NR_DEVICES> {
+    /// Forgets everything kept about the detached devices in `mask`
+    fn invalidate(&mut self, mask: &DeviceDisconnectMask) {
+        self.configuration_cache.invalidate(mask);
+        if self
+            .composed
+            .is_some_and(|(address, _)| mask.contains(address))
+        {
+            self.composed = None;
+        }
+    }
+}
+
+/// Transfers run on a pool of pipes, one per hardware channel of the
+/// controller (see [`HostDriver::start_pipes`]). A transfer holds its pipe from
+/// the first to the last transaction.
+///
+/// Control transfers, address assignment included, always run on the first
+/// pipe, so two control transfers never interleave on a device's endpoint 0.
+/// Interrupt, bulk and isochronous transfers take any free pipe. Their data
+/// toggles are kept in the endpoint's channel ([`InterruptChannel`],
+/// [`BulkChannel`]), not in the pipe, so consecutive transfers on different
+/// pipes continue the endpoint's toggle sequence.
+///
+/// With a single pipe a control transfer with NAK retries (up to the stage
+/// timeout per stage) delays every other transfer, hub status polling
+/// included. Pollers that would rather skip an interval than wait can use
+/// [`USBHostPipe::try_interrupt_transfer`].
 pub struct USBHostPipe<D: HostDriver, const NR_DEVICES: usize> {
-    inner: Mutex<CriticalSectionRawMutex, USBHostPipeInner<D, NR_DEVICES>>,
+    pipes: ArrayVec<Mutex<CriticalSectionRawMutex, USBHostPipeInner<D>>, MAX_PIPES>,
+    devices: Mutex<CriticalSectionRawMutex, Devices<NR_DEVICES>>,
+    descriptor_buffer: Mutex<CriticalSectionRawMutex, [u8; DESCRIPTOR_BUFFER_SIZE]>,
 }
 
 impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
     pub fn new(pipe: D::Pipe) -> Self {
+        Self::with_pipes([pipe].into_iter().collect())
+    }
+
+    /// Host pipe running transfers concurrently on `pipes`, see
+    /// [`HostDriver::start_pipes`]. Panics if `pipes` is empty.
+    pub fn with_pipes(pipes: ArrayVec<D::Pipe, MAX_PIPES>) -> Self {
+        assert!(!pipes.is_empty(), "at least one pipe is required");
         Self {
-            inner: Mutex::new(USBHostPipeInner {
-                pipe,
+            pipes: pipes
+                .into_iter()
+                .map(|pipe| {
+                    Mutex::new(USBHostPipeInner {
+                        pipe,
+                        default_timeout: TRANSFER_TIMEOUT,
+                        nak_limit: DEFAULT_NAK_LIMIT,
+                    })
+                })
+                .collect(),
+            devices: Mutex::new(Devices {
                 address_alloc: DeviceAddressManager::new(),
+                configuration_cache: ConfigurationCache::new(),
+                composed: None,
             }),
+            descriptor_buffer: Mutex::new([0; DESCRIPTOR_BUFFER_SIZE]),
+        }
+    }
+
+    /// The first pipe, which runs all control transfers
+    async fn control_pipe(&self) -> MutexGuard<'_, CriticalSectionRawMutex, USBHostPipeInner<D>> {
+        self.pipes[0].lock().await
+    }
+
+    /// The first free pipe, waits for one if all are busy
+    async fn any_pipe(&self) -> MutexGuard<'_, CriticalSectionRawMutex, USBHostPipeInner<D>> {
+        if let Some(inner) = self.try_any_pipe() {
+            return inner;
+        }
+        let locks: [_; MAX_PIPES] = core::array::from_fn(|i| async move {
+            match self.pipes.get(i) {
+                Some(pipe) => pipe.lock().await,
+                None => core::future::pending().await,
+            }
+        });
+        select_array(locks).await.0
+    }
+
+    /// The first free pipe, `None` if all are busy
+    fn try_any_pipe(&self) -> Option<MutexGuard<'_, CriticalSectionRawMutex, USBHostPipeInner<D>>> {
+        self.pipes.iter().find_map(|pipe| pipe.try_lock().ok())
+    }
+
+    /// Buffer for reading configuration descriptors during attach, shared by
+    /// all drivers instead of each keeping a 255 byte array in its attach
+    /// future. With the hub, dispatcher and composite driver futures nested in
+    /// the host's task this saves 255 bytes of stack per driver attached in
+    /// that task. Attaching is serialized on the buffer, drop the guard before
+    /// waiting on another driver.
+    pub async fn descriptor_buffer(
+        &self,
+    ) -> MutexGuard<'_, CriticalSectionRawMutex, [u8; DESCRIPTOR_BUFFER_SIZE]> {
+        self.descriptor_buffer.lock().await
+    }
+
+    /// Timeout of each transfer stage, used unless a transfer gives its own.
+    /// Defaults to 500ms.
+    pub async fn set_default_timeout(&self, timeout: Duration) {
+        for pipe in self.pipes.iter() {
+            pipe.lock().await.default_timeout = timeout;
+        }
+    }
+
+    /// Number of consecutive NAKs retried before a transfer stage fails with
+    /// [`UsbHostError::NakTimeout`]. Defaults to 4000.
+    pub async fn set_nak_limit(&self, nak_limit: u32) {
+        for pipe in self.pipes.iter() {
+            pipe.lock().await.nak_limit = nak_limit;
         }
     }
 
@@ -418,10 +803,14 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
         //TODO: take reference
         devinfo: DevInfo,
     ) -> Result<DeviceHandle, UsbHostError> {
-        let mut inner = self.inner.lock().await;
-        let handle = inner
+        let mut inner = self.control_pipe().await;
+        let timeout = inner.default_timeout;
+        let handle = self
+            .devices
+            .lock()
+            .await
             .address_alloc
-            .alloc_device_address(max_packet_size, devinfo);
+            .alloc_device_address(max_packet_size, devinfo)?;
 
         if let Err(e) = (async || {
             let request = Request {
@@ -439,7 +828,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
             };
 
             // Setup stage
-            inner.setup(&devinfo, 0, &request).await?;
+            inner.setup(&devinfo, 0, &request, timeout).await?;
             // Status stage (no data)
             inner
                 .data_in_with_retry(
@@ -449,6 +838,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
                     EndpointType::Control,
                     DataTog::DATA1,
                     &mut [],
+                    timeout,
                 )
                 .await?;
 
@@ -456,21 +846,22 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
         })()
         .await
         {
-            inner.address_alloc.free_address(handle);
+            self.devices.lock().await.address_alloc.free_address(handle);
             return Err(e);
         }
 
         Ok(handle)
     }
 
-    // TODO: maybe take a address, technically we can do this after enumeration
-    async fn get_device_descriptor(
-        &self,
+    /// GET_DESCRIPTOR(Device) control read at the default address, reading
+    /// `buf.len()` bytes in packets of at most `max_packet_size` bytes.
+    async fn read_device_descriptor(
+        inner: &mut USBHostPipeInner<D>,
         dev_info: &DevInfo,
+        max_packet_size: usize,
         buf: &mut [u8],
-    ) -> Result<DeviceDescriptor, UsbHostError> {
-        debug_assert!(buf.len() >= 18);
-        let mut inner = self.inner.lock().await;
+    ) -> Result<usize, UsbHostError> {
+        let timeout = inner.default_timeout;
         // Setup Stage
         let request = Request {
             request_type: {
@@ -484,46 +875,84 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
             request: StandardDeviceRequest::GetDescriptor as u8,
             value: (1 << 8) | 0, // DescriptorType: 1(Device), Index 0
             index: 0,
-            length: 64,
+            length: buf.len() as u16,
         };
         // default address upon initial connection
-        inner.setup(dev_info, 0, &request).await?;
+        inner.setup(dev_info, 0, &request, timeout).await?;
         trace!("setup finished");
 
         let mut tog = DataTog::DATA1;
         // Data stage
         let mut bytes_read = 0usize;
-        let in_result = inner
-            .data_in_with_retry(dev_info, 0, 0, EndpointType::Control, tog, buf)
-            .await?;
-        tog.next();
-        bytes_read += in_result;
-
-        while bytes_read < core::mem::size_of::<DeviceDescriptor>() {
-            let chopped_off_buf = &buf[bytes_read..];
-            // SAFETY:
-            // If the return Ok(desc); statement within the match block was executed,
-            // the borrow is no longer in effect. Therefore, the unsafe transmute
-            // is safe because there are no other outstanding immutable borrows of
-            // the memory region being modified.
+        for chunk in buf.chunks_mut(max_packet_size) {
+            let chunk_len = chunk.len();
             let in_result = inner
-                .data_in_with_retry(dev_info, 0, 0, EndpointType::Control, tog, unsafe {
-                    core::slice::from_raw_parts_mut(
-                        chopped_off_buf.as_ptr() as *mut u8,
-                        chopped_off_buf.len(),
-                    )
-                })
+                .data_in_with_retry(dev_info, 0, 0, EndpointType::Control, tog, chunk, timeout)
                 .await?;
             tog.next();
             bytes_read += in_result;
+            // short packet ends the data stage
+            if in_result < chunk_len {
+                break;
+            }
         }
 
         // Status stage
         inner
-            .data_out_with_retry(dev_info, 0, 0, EndpointType::Control, DataTog::DATA1, &[])
+            .data_out_with_retry(
+                dev_info,
+                0,
+                0,
+                EndpointType::Control,
+                DataTog::DATA1,
+                &[],
+                timeout,
+            )
             .await?;
 
-        debug_assert!(bytes_read == core::mem::size_of::<DeviceDescriptor>());
+        Ok(bytes_read)
+    }
+
+    /// Reads the device descriptor in two phases: the first 8 bytes to learn
+    /// `bMaxPacketSize0`, then the full descriptor in packets of that size.
+    /// Low speed devices with an 8 byte EP0 cannot answer a longer first read.
+    // TODO: maybe take a address, technically we can do this after enumeration
+    async fn get_device_descriptor(
+        &self,
+        dev_info: &DevInfo,
+        buf: &mut [u8],
+    ) -> Result<DeviceDescriptor, UsbHostError> {
+        let desc_len = core::mem::size_of::<DeviceDescriptor>();
+        if buf.len() < desc_len {
+            return Err(UsbHostError::BufferOverflow);
+        }
+        let mut inner = self.control_pipe().await;
+
+        // 8 bytes is the smallest EP0 max packet size, always a single packet
+        let bytes_read =
+            Self::read_device_descriptor(&mut inner, dev_info, 8, &mut buf[..8]).await?;
+        let max_packet_size = match DescriptorIterator::new(&buf[..bytes_read]).next() {
+            Some(Err(UsbHostError::ParsingError(ParsingError::IncompleteDeviceDescriptor {
+                max_packet_size,
+            }))) => max_packet_size,
+            _ => return Err(UsbHostError::InvalidResponse),
+        };
+        if !matches!(max_packet_size, 8 | 16 | 32 | 64) {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        trace!("EP0 max packet size {}", max_packet_size);
+
+        let bytes_read = Self::read_device_descriptor(
+            &mut inner,
+            dev_info,
+            max_packet_size as usize,
+            &mut buf[..desc_len],
+        )
+        .await?;
+        if bytes_read != desc_len {
+            return Err(UsbHostError::InvalidResponse);
+        }
+
         let mut desc_iter = DescriptorIterator::new(&buf[..bytes_read]);
 
         desc_iter
@@ -532,12 +961,93 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
             .and_then(|desc| desc.device().cloned().ok_or(UsbHostError::InvalidResponse))
     }
 
+    /// CLEAR_FEATURE(ENDPOINT_HALT) on `endpoint_address`.
+    ///
+    /// The device resets the endpoint's data toggle to DATA0, the channel used
+    /// with the endpoint has to be reset as well, see `InterruptChannel::reset_tog`
+    /// and `BulkChannel::reset_tog`.
+    pub async fn clear_halt(
+        &self,
+        device_handle: DeviceHandle,
+        endpoint_address: EndpointAddress,
+    ) -> Result<(), UsbHostError> {
+        self.control_transfer(
+            device_handle,
+            &Request::clear_feature(
+                request::RequestTypeRecipient::Endpoint,
+                request::RequestTypeType::Standard,
+                0, // ENDPOINT_HALT
+                u8::from(endpoint_address) as u16,
+                0,
+            ),
+            &mut [],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Interrupt transfer of a single packet, a STALLed endpoint is cleared
+    /// with [`USBHostPipe::clear_halt`] and the transfer retried once.
+    ///
+    /// The direction is the channel's endpoint direction. IN transfers read
+    /// into `buf` and return the packet length. OUT transfers send `buf` as one
+    /// packet, at most the endpoint's max packet size, and return 0. The
+    /// channel's data toggle only advances once the packet is acknowledged, a
+    /// NAKed or failed transfer is retried with the same toggle.
     pub async fn interrupt_transfer(
         &self,
         interrupt_channel: &mut InterruptChannel,
         buf: &mut [u8],
     ) -> Result<usize, UsbHostError> {
-        let mut inner = self.inner.lock().await;
+        match self.interrupt_transfer_once(interrupt_channel, buf).await {
+            Err(UsbHostError::STALL) => {
+                warn!(
+                    "endpoint {} of device {} stalled, clearing halt",
+                    interrupt_channel.endpoint_address.number,
+                    interrupt_channel.device_handle.address()
+                );
+                self.clear_halt(
+                    interrupt_channel.device_handle,
+                    interrupt_channel.endpoint_address,
+                )
+                .await?;
+                interrupt_channel.reset_tog();
+                self.interrupt_transfer_once(interrupt_channel, buf).await
+            }
+            res => res,
+        }
+    }
+
+    /// [`USBHostPipe::interrupt_transfer`] that only runs when the pipe is free,
+    /// returns `None` while another transfer holds it. A STALL is returned as
+    /// is, recover with [`USBHostPipe::clear_halt`].
+    ///
+    /// Lets a poller skip an interval instead of queueing behind a long control
+    /// transfer.
+    pub async fn try_interrupt_transfer(
+        &self,
+        interrupt_channel: &mut InterruptChannel,
+        buf: &mut [u8],
+    ) -> Option<Result<usize, UsbHostError>> {
+        let mut inner = self.try_any_pipe()?;
+        Some(Self::interrupt_transfer_locked(&mut inner, interrupt_channel, buf).await)
+    }
+
+    async fn interrupt_transfer_once(
+        &self,
+        interrupt_channel: &mut InterruptChannel,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        let mut inner = self.any_pipe().await;
+        Self::interrupt_transfer_locked(&mut inner, interrupt_channel, buf).await
+    }
+
+    async fn interrupt_transfer_locked(
+        inner: &mut USBHostPipeInner<D>,
+        interrupt_channel: &mut InterruptChannel,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        let timeout = inner.default_timeout;
         let endpoint = interrupt_channel.endpoint_address.number;
         let tog = interrupt_channel.tog;
         let buf = buf;
@@ -556,6 +1066,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
                         EndpointType::Interrupt,
                         tog,
                         buf,
+                        timeout,
                     )
                     .await
             }
@@ -567,89 +1078,406 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
                     EndpointType::Interrupt,
                     tog,
                     buf,
+                    timeout,
                 )
                 .await
+                // Nothing was read, see interrupt_transfer
                 .map(|_| 0),
         }?;
         interrupt_channel.tog.next();
         Ok(res)
     }
 
-    pub async fn control_transfer(
+    /// Single isochronous IN transaction of up to the endpoint's max packet
+    /// size, call it once per [`IsoChannel::period`].
+    ///
+    /// Isochronous transactions are not acknowledged, a lost packet is not
+    /// retried and data toggles are not tracked (only one transaction per
+    /// microframe is made, always DATA0). Devices behind a transaction
+    /// translator are not supported yet.
+    pub async fn iso_in(
         &self,
-        device_handle: DeviceHandle,
-        request: &Request,
-        mut buffer: &mut [u8],
+        iso_channel: &mut IsoChannel,
+        buf: &mut [u8],
     ) -> Result<usize, UsbHostError> {
-        use request::RequestTypeDirection;
-        let mut inner = self.inner.lock().await;
-        let dir = request.request_type.data_direction();
-        let mut bytes_received = 0usize;
-
-        debug_assert!(buffer.len() >= request.length as usize);
+        if iso_channel.endpoint_address.direction != types::EndpointDirection::In {
+            return Err(UsbHostError::InvalidState);
+        }
+        let dev_info = iso_channel.device_handle.dev_info();
+        if dev_info.transaction_translator().is_some() {
+            // TODO: isochronous split transactions
+            return Err(UsbHostError::Unsupported);
+        }
+        let len = core::cmp::min(buf.len(), iso_channel.max_packet_size as usize);
 
-        // Setup stage
-        inner
-            .setup(&device_handle.dev_info(), device_handle.address(), request)
-            .await?;
+        let mut inner = self.any_pipe().await;
+        let timeout_fut = Timer::after(inner.default_timeout);
+        inner.pipe.set_addr(iso_channel.device_handle.address());
+        let in_fut = inner.pipe.data_in(
+            iso_channel.endpoint_address.number,
+            DataTog::DATA0,
+            true,
+            false,
+            &mut buf[..len],
+        );
+        match select(timeout_fut, in_fut).await {
+            Either::First(_) => Err(UsbHostError::TransferTimeout),
+            Either::Second(r) => r,
+        }
+    }
 
-        // (Optional) data stage
-        if request.length > 0 {
-            match dir {
-                RequestTypeDirection::HostToDevice => {
-                    let mut tog = DataTog::DATA1;
-                    while !buffer.is_empty() {
-                        let transfer_len =
-                            core::cmp::min(buffer.len(), device_handle.max_packet_size() as usize);
-                        inner
-                            .data_out_with_retry(
-                                &device_handle.dev_info(),
-                                device_handle.address(),
-                                0,
-                                EndpointType::Control,
-                                tog,
-                                &buffer[..transfer_len],
-                            )
-                            .await?;
-                        tog.next();
-                        buffer = &mut buffer[transfer_len..];
-                    }
-                }
-                RequestTypeDirection::DeviceToHost => {
-                    let mut tog = DataTog::DATA1;
-                    loop {
-                        let len = inner
-                            .data_in_with_retry(
-                                &device_handle.dev_info(),
-                                device_handle.address(),
-                                0,
-                                EndpointType::Control,
-                                tog,
-                                &mut buffer[bytes_received..],
-                            )
-                            .await?;
-                        tog.next();
-                        bytes_received += len;
-                        if len < device_handle.max_packet_size() as usize {
-                            break;
-                        }
-                    }
-                }
-            }
+    /// Bulk transfer on the endpoint described by `bulk_channel`.
+    ///
+    /// IN transfers read packets until a short packet is received or `buf` is full.
+    /// OUT transfers split `buf` into `max_packet_size` packets, an empty `buf`
+    /// sends a single zero-length packet.
+    /// NAKs are retried, each packet is subject to the transfer timeout.
+    /// A STALLed endpoint is cleared with [`USBHostPipe::clear_halt`] and the
+    /// transfer resumed once, after the packets that already went through.
+    pub async fn bulk_transfer(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        match bulk_channel.endpoint_address.direction {
+            types::EndpointDirection::In => self.bulk_in(bulk_channel, buf).await,
+            types::EndpointDirection::Out => self.bulk_out(bulk_channel, buf).await,
         }
+    }
 
-        // Status stage
-        match dir {
-            RequestTypeDirection::HostToDevice => {
-                inner
-                    .data_in_with_retry(
-                        &device_handle.dev_info(),
-                        device_handle.address(),
-                        0,
-                        EndpointType::Control,
-                        DataTog::DATA1,
-                        &mut [],
-                    )
+    async fn bulk_in(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        let mut bytes_received = 0;
+        match self
+            .bulk_in_from(bulk_channel, buf, &mut bytes_received)
+            .await
+        {
+            Err(UsbHostError::STALL) => {
+                self.clear_bulk_halt(bulk_channel).await?;
+                self.bulk_in_from(bulk_channel, buf, &mut bytes_received)
+                    .await?;
+            }
+            res => res?,
+        }
+        Ok(bytes_received)
+    }
+
+    /// OUT half of [`USBHostPipe::bulk_transfer`], for callers only holding a shared buffer
+    pub(crate) async fn bulk_out(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        buf: &[u8],
+    ) -> Result<usize, UsbHostError> {
+        let mut bytes_sent = 0;
+        match self.bulk_out_from(bulk_channel, buf, &mut bytes_sent).await {
+            Err(UsbHostError::STALL) => {
+                self.clear_bulk_halt(bulk_channel).await?;
+                self.bulk_out_from(bulk_channel, buf, &mut bytes_sent)
+                    .await?;
+            }
+            res => res?,
+        }
+        Ok(bytes_sent)
+    }
+
+    async fn clear_bulk_halt(&self, bulk_channel: &mut BulkChannel) -> Result<(), UsbHostError> {
+        warn!(
+            "endpoint {} of device {} stalled, clearing halt",
+            bulk_channel.endpoint_address.number,
+            bulk_channel.device_handle.address()
+        );
+        self.clear_halt(bulk_channel.device_handle, bulk_channel.endpoint_address)
+            .await?;
+        bulk_channel.reset_tog();
+        Ok(())
+    }
+
+    /// [`USBHostPipe::bulk_transfer`] IN without the STALL recovery, for class
+    /// drivers that handle a STALL themselves
+    pub(crate) async fn bulk_in_once(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        let mut bytes_received = 0;
+        self.bulk_in_from(bulk_channel, buf, &mut bytes_received)
+            .await
+            .map(|_| bytes_received)
+    }
+
+    /// Reads into `buf` from `bytes_received` on, counting the received bytes
+    /// even if a later packet fails
+    async fn bulk_in_from(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        buf: &mut [u8],
+        bytes_received: &mut usize,
+    ) -> Result<(), UsbHostError> {
+        let mut inner = self.any_pipe().await;
+        let timeout = inner.default_timeout;
+        let dev_info = bulk_channel.device_handle.dev_info();
+        let address = bulk_channel.device_handle.address();
+        let endpoint = bulk_channel.endpoint_address.number;
+        let max_packet_size = bulk_channel.max_packet_size as usize;
+        // The pipe may have last talked to another device
+        inner.pipe.set_addr(address);
+
+        while *bytes_received < buf.len() {
+            let len = inner
+                .data_in_with_retry(
+                    &dev_info,
+                    address,
+                    endpoint,
+                    EndpointType::Bulk,
+                    bulk_channel.tog,
+                    &mut buf[*bytes_received..],
+                    timeout,
+                )
+                .await?;
+            bulk_channel.tog.next();
+            *bytes_received += len;
+            if len < max_packet_size {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends everything `reader` returns until it reaches EOF to an OUT
+    /// endpoint, one max packet size chunk at a time, returning the number of
+    /// bytes sent.
+    ///
+    /// Short reads are collected into full packets, so only the last packet
+    /// may be short. With `zero_length_packet` a zero-length packet ends
+    /// transfers that are a multiple of the max packet size, for classes that
+    /// need the end of a transfer marked.
+    pub async fn bulk_write_stream<R: embedded_io_async::Read>(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        reader: &mut R,
+        zero_length_packet: bool,
+    ) -> Result<usize, BulkStreamError<R::Error>> {
+        if bulk_channel.endpoint_address.direction != types::EndpointDirection::Out {
+            return Err(UsbHostError::InvalidState.into());
+        }
+        let max_packet_size = bulk_channel.max_packet_size as usize;
+        if max_packet_size == 0 || max_packet_size > MAX_BULK_PACKET_SIZE {
+            return Err(UsbHostError::Unsupported.into());
+        }
+
+        let mut packet = [0u8; MAX_BULK_PACKET_SIZE];
+        let mut bytes_sent = 0;
+        loop {
+            let mut len = 0;
+            while len < max_packet_size {
+                match reader.read(&mut packet[len..max_packet_size]).await {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(e) => return Err(BulkStreamError::Read(e)),
+                }
+            }
+            if len == 0 && !zero_length_packet {
+                // EOF right after a full packet
+                return Ok(bytes_sent);
+            }
+            self.bulk_out(bulk_channel, &packet[..len]).await?;
+            bytes_sent += len;
+            if len < max_packet_size {
+                // A short (or zero-length) packet ends the transfer
+                return Ok(bytes_sent);
+            }
+        }
+    }
+
+    /// [`USBHostPipe::bulk_out`] without the STALL recovery
+    pub(crate) async fn bulk_out_once(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        buf: &[u8],
+    ) -> Result<usize, UsbHostError> {
+        let mut bytes_sent = 0;
+        self.bulk_out_from(bulk_channel, buf, &mut bytes_sent)
+            .await
+            .map(|_| bytes_sent)
+    }
+
+    /// Sends `buf` from `bytes_sent` on, counting the acknowledged bytes even
+    /// if a later packet fails
+    async fn bulk_out_from(
+        &self,
+        bulk_channel: &mut BulkChannel,
+        buf: &[u8],
+        bytes_sent: &mut usize,
+    ) -> Result<(), UsbHostError> {
+        let mut inner = self.any_pipe().await;
+        let timeout = inner.default_timeout;
+        let dev_info = bulk_channel.device_handle.dev_info();
+        let address = bulk_channel.device_handle.address();
+        let endpoint = bulk_channel.endpoint_address.number;
+        let max_packet_size = bulk_channel.max_packet_size as usize;
+        inner.pipe.set_addr(address);
+
+        loop {
+            let transfer_len = core::cmp::min(buf.len() - *bytes_sent, max_packet_size);
+            inner
+                .data_out_with_retry(
+                    &dev_info,
+                    address,
+                    endpoint,
+                    EndpointType::Bulk,
+                    bulk_channel.tog,
+                    &buf[*bytes_sent..*bytes_sent + transfer_len],
+                    timeout,
+                )
+                .await?;
+            bulk_channel.tog.next();
+            *bytes_sent += transfer_len;
+            if *bytes_sent >= buf.len() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts building a control transfer to `device_handle`, a standard request
+    /// to the device unless changed
+    pub fn control(&self, device_handle: DeviceHandle) -> ControlTransfer<'_, D, NR_DEVICES> {
+        ControlTransfer {
+            pipe: self,
+            device_handle,
+            request_type: request::RequestTypeType::Standard,
+            recipient: request::RequestTypeRecipient::Device,
+            request: 0,
+            value: 0,
+            index: 0,
+            timeout: None,
+        }
+    }
+
+    pub async fn control_transfer(
+        &self,
+        device_handle: DeviceHandle,
+        request: &Request,
+        buffer: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        self.control_transfer_inner(device_handle, request, DataStage::of(request, buffer), None)
+            .await
+    }
+
+    /// [`USBHostPipe::control_transfer`] with `timeout` applied to each stage
+    /// instead of the default timeout
+    pub async fn control_transfer_with_timeout(
+        &self,
+        device_handle: DeviceHandle,
+        request: &Request,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, UsbHostError> {
+        self.control_transfer_inner(
+            device_handle,
+            request,
+            DataStage::of(request, buffer),
+            Some(timeout),
+        )
+        .await
+    }
+
+    async fn control_transfer_inner(
+        &self,
+        device_handle: DeviceHandle,
+        request: &Request,
+        data: DataStage<'_>,
+        timeout: Option<Duration>,
+    ) -> Result<usize, UsbHostError> {
+        use request::RequestTypeDirection;
+        // The data stage reads or writes wLength bytes of the buffer
+        if data.len() < request.length as usize {
+            return Err(UsbHostError::BufferOverflow);
+        }
+
+        let mut inner = self.control_pipe().await;
+        let timeout = timeout.unwrap_or(inner.default_timeout);
+        let dir = request.request_type.data_direction();
+        let mut bytes_received = 0usize;
+
+        // Setup stage
+        inner
+            .setup(
+                &device_handle.dev_info(),
+                device_handle.address(),
+                request,
+                timeout,
+            )
+            .await?;
+
+        // (Optional) data stage
+        if request.length > 0 {
+            match data {
+                DataStage::Out(buffer) => {
+                    let mut tog = DataTog::DATA1;
+                    for chunk in buffer[..request.length as usize]
+                        .chunks(device_handle.max_packet_size() as usize)
+                    {
+                        inner
+                            .data_out_with_retry(
+                                &device_handle.dev_info(),
+                                device_handle.address(),
+                                0,
+                                EndpointType::Control,
+                                tog,
+                                chunk,
+                                timeout,
+                            )
+                            .await?;
+                        tog.next();
+                    }
+                }
+                DataStage::In(buffer) => {
+                    let length = request.length as usize;
+                    let mut tog = DataTog::DATA1;
+                    loop {
+                        let len = inner
+                            .data_in_with_retry(
+                                &device_handle.dev_info(),
+                                device_handle.address(),
+                                0,
+                                EndpointType::Control,
+                                tog,
+                                &mut buffer[bytes_received..length],
+                                timeout,
+                            )
+                            .await?;
+                        tog.next();
+                        bytes_received += len;
+                        // A short packet ends the data stage, as does reaching wLength: the
+                        // device sends no zero length packet when the data is an exact
+                        // multiple of the max packet size
+                        if len < device_handle.max_packet_size() as usize
+                            || bytes_received >= length
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Status stage
+        match dir {
+            RequestTypeDirection::HostToDevice => {
+                inner
+                    .data_in_with_retry(
+                        &device_handle.dev_info(),
+                        device_handle.address(),
+                        0,
+                        EndpointType::Control,
+                        DataTog::DATA1,
+                        &mut [],
+                        timeout,
+                    )
                     .await?;
             }
             RequestTypeDirection::DeviceToHost => {
@@ -661,6 +1489,7 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
                         EndpointType::Control,
                         DataTog::DATA1,
                         &[],
+                        timeout,
                     )
                     .await?;
             }
@@ -669,6 +1498,534 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
         Ok(bytes_received)
     }
 
+    /// Reads a raw string descriptor into `buf` and returns its UTF-16LE payload
+    async fn read_string_descriptor<'b>(
+        &self,
+        device_handle: DeviceHandle,
+        index: u8,
+        lang_id: u16,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], UsbHostError> {
+        let len = self
+            .control_transfer(
+                device_handle,
+                &Request::get_string_descriptor(index, lang_id, buf.len() as u16),
+                buf,
+            )
+            .await?;
+
+        // bLength, bDescriptorType, followed by the UTF-16LE payload
+        if len < 2 || buf[1] != DescriptorType::String as u8 || (buf[0] as usize) < 2 {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        let end = core::cmp::min(buf[0] as usize, len);
+        Ok(&buf[2..end])
+    }
+
+    /// Reads string descriptor `index` in language `lang_id` and decodes it into `buf`.
+    ///
+    /// Unpaired surrogates are replaced with U+FFFD.
+    /// Returns `BufferOverflow` if the decoded string does not fit in `buf`.
+    pub async fn get_string_descriptor<'b>(
+        &self,
+        device_handle: DeviceHandle,
+        index: u8,
+        lang_id: u16,
+        buf: &'b mut [u8],
+    ) -> Result<&'b str, UsbHostError> {
+        let mut raw = [0u8; 255];
+        let payload = self
+            .read_string_descriptor(device_handle, index, lang_id, &mut raw)
+            .await?;
+
+        let units = payload
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        let mut len = 0usize;
+        for c in char::decode_utf16(units) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            if len + c.len_utf8() > buf.len() {
+                return Err(UsbHostError::BufferOverflow);
+            }
+            len += c.encode_utf8(&mut buf[len..]).len();
+        }
+
+        core::str::from_utf8(&buf[..len]).map_err(|_| UsbHostError::InvalidResponse)
+    }
+
+    /// Reads string descriptor 0 and returns the language IDs supported by the device.
+    ///
+    /// Returns `BufferOverflow` if `buf` cannot hold all language IDs.
+    pub async fn get_string_langids<'b>(
+        &self,
+        device_handle: DeviceHandle,
+        buf: &'b mut [u16],
+    ) -> Result<&'b [u16], UsbHostError> {
+        let mut raw = [0u8; 255];
+        let payload = self
+            .read_string_descriptor(device_handle, 0, 0, &mut raw)
+            .await?;
+
+        let count = payload.len() / 2;
+        if count > buf.len() {
+            return Err(UsbHostError::BufferOverflow);
+        }
+        for (langid, unit) in buf.iter_mut().zip(payload.chunks_exact(2)) {
+            *langid = u16::from_le_bytes([unit[0], unit[1]]);
+        }
+        Ok(&buf[..count])
+    }
+
+    /// Reads the device qualifier of a high-speed capable device.
+    ///
+    /// Devices that only support full or low speed STALL the request, this is
+    /// returned as [`UsbHostError::Unsupported`].
+    pub async fn get_device_qualifier(
+        &self,
+        device_handle: DeviceHandle,
+    ) -> Result<DeviceQualifier, UsbHostError> {
+        let mut buf = [0u8; core::mem::size_of::<DeviceQualifier>()];
+        let len = match self
+            .control_transfer(device_handle, &Request::get_device_qualifier(), &mut buf)
+            .await
+        {
+            Ok(len) => len,
+            Err(UsbHostError::STALL) => return Err(UsbHostError::Unsupported),
+            Err(e) => return Err(e),
+        };
+        match DescriptorIterator::new(&buf[..len]).next() {
+            Some(Ok(Descriptor::DeviceQualifier(qualifier))) => Ok(qualifier.clone()),
+            Some(Err(e)) => Err(e),
+            _ => Err(UsbHostError::InvalidResponse),
+        }
+    }
+
+    /// Reads the Binary Object Store into `buf`.
+    ///
+    /// Devices that don't have one (before USB 2.1) STALL the request, this is
+    /// returned as [`UsbHostError::Unsupported`]. Returns `BufferOverflow` if
+    /// `buf` cannot hold the whole BOS.
+    pub async fn get_bos<'b>(
+        &self,
+        device_handle: DeviceHandle,
+        buf: &'b mut [u8],
+    ) -> Result<Bos<'b>, UsbHostError> {
+        let mut header = [0u8; BOS_HEADER_SIZE];
+        let len = match self
+            .control_transfer(
+                device_handle,
+                &Request::get_bos(BOS_HEADER_SIZE as u16),
+                &mut header,
+            )
+            .await
+        {
+            Ok(len) => len,
+            Err(UsbHostError::STALL) => return Err(UsbHostError::Unsupported),
+            Err(e) => return Err(e),
+        };
+        if len != BOS_HEADER_SIZE || header[1] != DescriptorType::Bos as u8 {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let buf = buf
+            .get_mut(..total_length as usize)
+            .ok_or(UsbHostError::BufferOverflow)?;
+
+        let len = self
+            .control_transfer(device_handle, &Request::get_bos(total_length), buf)
+            .await?;
+        match DescriptorIterator::new(&buf[..len]).next() {
+            Some(Ok(Descriptor::Bos(bos))) => Ok(bos),
+            Some(Err(e)) => Err(e),
+            _ => Err(UsbHostError::InvalidResponse),
+        }
+    }
+
+    /// Writes `descriptor` as descriptor `index` of `descriptor_type`, `language_id`
+    /// is 0 unless it is a string descriptor. Devices without support STALL.
+    pub async fn set_descriptor(
+        &self,
+        device_handle: DeviceHandle,
+        descriptor_type: u8,
+        index: u8,
+        language_id: u16,
+        descriptor: &[u8],
+    ) -> Result<(), UsbHostError> {
+        let length = u16::try_from(descriptor.len()).map_err(|_| UsbHostError::BufferOverflow)?;
+        self.control_transfer_inner(
+            device_handle,
+            &Request::set_descriptor(descriptor_type, index, language_id, length),
+            DataStage::Out(descriptor),
+            None,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// SET_CONFIGURATION, refused with [`UsbHostError::InsufficientPower`] if
+    /// the configuration's bMaxPower exceeds the current the device's port
+    /// supplies: 500mA on the root port and self-powered hubs, 100mA on
+    /// bus-powered hubs.
+    ///
+    /// bMaxPower is taken from the configuration descriptor read last, as the
+    /// drivers do right before configuring the device. Other configurations
+    /// are set without the check.
+    pub async fn set_configuration(
+        &self,
+        device_handle: DeviceHandle,
+        configuration: u8,
+    ) -> Result<(), UsbHostError> {
+        let devices = self.devices.lock().await;
+        if devices.composed == Some((device_handle.address(), configuration)) {
+            trace!(
+                "device {} already in configuration {}",
+                device_handle.address(),
+                configuration
+            );
+            return Ok(());
+        }
+        let cached = devices
+            .configuration_cache
+            .configuration(device_handle.address(), configuration);
+        drop(devices);
+        if let Some(desc) = cached {
+            let required = desc.max_power as u16 * 2;
+            let available = self
+                .devices
+                .lock()
+                .await
+                .address_alloc
+                .available_current(device_handle.dev_info().port());
+            if required > available {
+                warn!(
+                    "configuration {} of device {} draws {}mA, port supplies {}mA",
+                    configuration,
+                    device_handle.address(),
+                    required,
+                    available
+                );
+                return Err(UsbHostError::InsufficientPower);
+            }
+        }
+        self.control_transfer(
+            device_handle,
+            &Request::set_configuration(configuration),
+            &mut [],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Puts the device in its first configuration for the drivers of a
+    /// [`Composite`](crate::driver::composite::Composite). Until
+    /// [`USBHostPipe::end_composed`] their SET_CONFIGURATION of that
+    /// configuration is skipped, it would reset the interfaces the drivers
+    /// attached before set up.
+    ///
+    /// Returns false without configuring the device if it is composed already,
+    /// by an outer `Composite`.
+    pub(crate) async fn begin_composed(
+        &self,
+        device_handle: DeviceHandle,
+    ) -> Result<bool, UsbHostError> {
+        if self
+            .devices
+            .lock()
+            .await
+            .composed
+            .is_some_and(|(address, _)| address == device_handle.address())
+        {
+            return Ok(false);
+        }
+        let mut buf = [0u8; core::mem::size_of::<ConfigurationDescriptor>()];
+        let len = self
+            .read_configuration_descriptor(device_handle, 0, &mut buf)
+            .await?;
+        let Some(Ok(Descriptor::Configuration(desc))) = DescriptorIterator::new(&buf[..len]).next()
+        else {
+            return Err(UsbHostError::InvalidResponse);
+        };
+        let configuration = desc.value;
+        self.set_configuration(device_handle, configuration).await?;
+        self.devices.lock().await.composed = Some((device_handle.address(), configuration));
+        Ok(true)
+    }
+
+    /// Ends [`USBHostPipe::begin_composed`], once all drivers attached
+    pub(crate) async fn end_composed(&self, device_handle: DeviceHandle) {
+        let mut devices = self.devices.lock().await;
+        if devices
+            .composed
+            .is_some_and(|(address, _)| address == device_handle.address())
+        {
+            devices.composed = None;
+        }
+    }
+
+    /// Current configuration value, 0 if the device is not configured
+    pub async fn get_configuration(&self, device_handle: DeviceHandle) -> Result<u8, UsbHostError> {
+        let mut buf = [0u8; 1];
+        let len = self
+            .control_transfer(device_handle, &Request::get_configuration(), &mut buf)
+            .await?;
+        if len != 1 {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        Ok(buf[0])
+    }
+
+    /// Selects `alternate_setting` of `interface`
+    pub async fn set_interface(
+        &self,
+        device_handle: DeviceHandle,
+        interface: u8,
+        alternate_setting: u8,
+    ) -> Result<(), UsbHostError> {
+        self.control_transfer(
+            device_handle,
+            &Request::set_interface(interface, alternate_setting),
+            &mut [],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Alternate setting currently selected on `interface`
+    pub async fn get_interface(
+        &self,
+        device_handle: DeviceHandle,
+        interface: u8,
+    ) -> Result<u8, UsbHostError> {
+        let mut buf = [0u8; 1];
+        let len = self
+            .control_transfer(device_handle, &Request::get_interface(interface), &mut buf)
+            .await?;
+        if len != 1 {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        Ok(buf[0])
+    }
+
+    /// Reads the configuration descriptor at `index` into `buf`, answered from the
+    /// cache when it was read completely before
+    pub(crate) async fn read_configuration_descriptor(
+        &self,
+        device_handle: DeviceHandle,
+        index: u8,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        {
+            let devices = self.devices.lock().await;
+            if let Some(cached) = devices
+                .configuration_cache
+                .get(device_handle.address(), index)
+                .filter(|cached| cached.len() <= buf.len())
+            {
+                trace!(
+                    "configuration {} of device {} cached",
+                    index,
+                    device_handle.address()
+                );
+                buf[..cached.len()].copy_from_slice(cached);
+                return Ok(cached.len());
+            }
+        }
+
+        let len = self
+            .control_transfer(
+                device_handle,
+                &Request::get_configuration_descriptor(index, buf.len() as u16),
+                buf,
+            )
+            .await?;
+        self.devices.lock().await.configuration_cache.insert(
+            device_handle.address(),
+            index,
+            &buf[..len],
+        );
+        Ok(len)
+    }
+
+    /// Interfaces of the device's first configuration, alternate setting 0 only.
+    /// Uses [`USBHostPipe::descriptor_buffer`], don't hold it while calling this.
+    pub async fn device_interfaces(
+        &self,
+        device_handle: DeviceHandle,
+    ) -> Result<DeviceInterfaces, UsbHostError> {
+        let mut buf = self.descriptor_buffer().await;
+        let len = self
+            .read_configuration_descriptor(device_handle, 0, &mut buf[..])
+            .await?;
+        let mut interfaces = DeviceInterfaces::default();
+        for desc in DescriptorIterator::with_config_length(&buf[..len]).validating() {
+            if let Descriptor::Interface(interface) = desc? {
+                if interface.b_alternate_setting == 0 {
+                    interfaces.push(interface.into());
+                }
+            }
+        }
+        Ok(interfaces)
+    }
+
+    /// Power and remote wakeup state of the device
+    pub async fn get_device_status(
+        &self,
+        device_handle: DeviceHandle,
+    ) -> Result<DeviceStatus, UsbHostError> {
+        self.get_status(device_handle, request::RequestTypeRecipient::Device, 0)
+            .await
+            .map(DeviceStatus::from)
+    }
+
+    /// Whether `endpoint_address` is halted
+    pub async fn get_endpoint_status(
+        &self,
+        device_handle: DeviceHandle,
+        endpoint_address: EndpointAddress,
+    ) -> Result<EndpointStatus, UsbHostError> {
+        self.get_status(
+            device_handle,
+            request::RequestTypeRecipient::Endpoint,
+            u8::from(endpoint_address) as u16,
+        )
+        .await
+        .map(EndpointStatus::from)
+    }
+
+    /// Enables or disables the device's remote wakeup with SET_FEATURE / CLEAR_FEATURE
+    /// (DEVICE_REMOTE_WAKEUP). Returns [`UsbHostError::Unsupported`] when the active
+    /// configuration does not advertise remote wakeup, and
+    /// [`UsbHostError::InvalidState`] when the device is not configured.
+    pub async fn set_remote_wakeup(
+        &self,
+        device_handle: DeviceHandle,
+        enable: bool,
+    ) -> Result<(), UsbHostError> {
+        let attributes = self.active_configuration_attributes(device_handle).await?;
+        if !attributes.remote_wakeup() {
+            return Err(UsbHostError::Unsupported);
+        }
+
+        let request = if enable {
+            Request::set_feature(
+                request::RequestTypeRecipient::Device,
+                request::RequestTypeType::Standard,
+                DEVICE_REMOTE_WAKEUP,
+                0,
+                0,
+            )
+        } else {
+            Request::clear_feature(
+                request::RequestTypeRecipient::Device,
+                request::RequestTypeType::Standard,
+                DEVICE_REMOTE_WAKEUP,
+                0,
+                0,
+            )
+        };
+        self.control_transfer(device_handle, &request, &mut [])
+            .await
+            .map(|_| ())
+    }
+
+    /// Attributes of the active configuration
+    async fn active_configuration_attributes(
+        &self,
+        device_handle: DeviceHandle,
+    ) -> Result<ConfigurationAttributes, UsbHostError> {
+        let configuration = self.get_configuration(device_handle).await?;
+        if configuration == 0 {
+            return Err(UsbHostError::InvalidState);
+        }
+        self.configuration_header(device_handle, configuration)
+            .await
+            .map(|desc| desc.attributes)
+    }
+
+    /// Configuration descriptor (without the descriptors following it) with
+    /// bConfigurationValue `configuration`, from the cache or by reading the
+    /// header of each of the device's configurations until the value matches
+    async fn configuration_header(
+        &self,
+        device_handle: DeviceHandle,
+        configuration: u8,
+    ) -> Result<ConfigurationDescriptor, UsbHostError> {
+        if let Some(desc) = self
+            .devices
+            .lock()
+            .await
+            .configuration_cache
+            .configuration(device_handle.address(), configuration)
+        {
+            return Ok(desc);
+        }
+
+        let mut buf = [0u8; core::mem::size_of::<DeviceDescriptor>()];
+        let len = self
+            .control_transfer(
+                device_handle,
+                &Request::get_descriptor(
+                    DescriptorType::Device as u8,
+                    request::RequestTypeType::Standard,
+                    0,
+                    0,
+                    buf.len() as u16,
+                ),
+                &mut buf,
+            )
+            .await?;
+        let num_configurations = match DescriptorIterator::new(&buf[..len]).next() {
+            Some(Ok(Descriptor::Device(desc))) => desc.num_configurations,
+            _ => return Err(UsbHostError::InvalidResponse),
+        };
+
+        let mut buf = [0u8; core::mem::size_of::<ConfigurationDescriptor>()];
+        for index in 0..num_configurations {
+            let len = self
+                .control_transfer(
+                    device_handle,
+                    &Request::get_configuration_descriptor(index, buf.len() as u16),
+                    &mut buf,
+                )
+                .await?;
+            let Some(Ok(Descriptor::Configuration(desc))) =
+                DescriptorIterator::new(&buf[..len]).next()
+            else {
+                return Err(UsbHostError::InvalidResponse);
+            };
+            if desc.value == configuration {
+                let desc = desc.clone();
+                self.devices
+                    .lock()
+                    .await
+                    .configuration_cache
+                    .insert_header(device_handle.address(), &buf[..len]);
+                return Ok(desc);
+            }
+        }
+        Err(UsbHostError::InvalidResponse)
+    }
+
+    async fn get_status(
+        &self,
+        device_handle: DeviceHandle,
+        recipient: request::RequestTypeRecipient,
+        index: u16,
+    ) -> Result<u16, UsbHostError> {
+        let mut buf = [0u8; 2];
+        let len = self
+            .control_transfer(
+                device_handle,
+                &Request::get_status(recipient, request::RequestTypeType::Standard, 0, index, 2),
+                &mut buf,
+            )
+            .await?;
+        if len < 2 {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        Ok(u16::from_le_bytes(buf))
+    }
+
     pub async fn dev_attach(
         &self,
         dev_info: DevInfo,
@@ -686,13 +2043,418 @@ impl<D: HostDriver, const NR_DEVICES: usize> USBHostPipe<D, NR_DEVICES> {
         Ok((d, handle))
     }
 
+    /// Snapshot of all devices that currently have an address, hubs included
+    pub async fn devices(&self) -> ArrayVec<AttachedDevice, NR_DEVICES> {
+        let devices = self.devices.lock().await;
+        devices.address_alloc.devices().collect()
+    }
+
+    /// Ports of the hubs between the root port and the device, the same
+    /// physical port gives the same path across reboots
+    pub async fn device_path(&self, device_handle: DeviceHandle) -> ArrayVec<u8, MAX_PATH_LEN> {
+        let devices = self.devices.lock().await;
+        device_handle.path(&devices.address_alloc)
+    }
+
+    /// Records the current each downstream port of `hub` supplies, checked by
+    /// [`USBHostPipe::set_configuration`] for the devices attached to it
+    pub(crate) async fn set_hub_port_current(&self, hub: DeviceHandle, current_ma: u16) {
+        self.devices
+            .lock()
+            .await
+            .address_alloc
+            .set_port_current(hub.address(), current_ma);
+    }
+
+    /// Handle of the device attached to `port`, `None` if the port has no addressed device
+    pub(crate) async fn device_at(&self, port: PortInfo) -> Option<DeviceHandle> {
+        let devices = self.devices.lock().await;
+        devices.address_alloc.device_at(port)
+    }
+
     pub async fn root_detach(&self) -> DeviceDisconnectMask {
-        let mut inner = self.inner.lock().await;
-        inner.address_alloc.free_all_addresses()
+        let mut devices = self.devices.lock().await;
+        let mask = devices.address_alloc.free_all_addresses();
+        devices.invalidate(&mask);
+        mask
     }
 
     pub async fn dev_detach(&self, port_info: PortInfo) -> DeviceDisconnectMask {
-        let mut inner = self.inner.lock().await;
-        inner.address_alloc.free_subtree(port_info)
+        let mut devices = self.devices.lock().await;
+        let mask = devices.address_alloc.free_subtree(port_info);
+        devices.invalidate(&mask);
+        mask
+    }
+}
+
+/// Buffer of a control transfer's data stage, read into for IN requests and
+/// sent from for OUT requests
+enum DataStage<'b> {
+    In(&'b mut [u8]),
+    Out(&'b [u8]),
+}
+
+impl<'b> DataStage<'b> {
+    /// `buffer` in the direction of `request`
+    fn of(request: &Request, buffer: &'b mut [u8]) -> Self {
+        match request.request_type.data_direction() {
+            request::RequestTypeDirection::DeviceToHost => DataStage::In(buffer),
+            request::RequestTypeDirection::HostToDevice => DataStage::Out(buffer),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            DataStage::In(buffer) => buffer.len(),
+            DataStage::Out(buffer) => buffer.len(),
+        }
+    }
+}
+
+/// Control transfer built step by step, see [`USBHostPipe::control`].
+///
+/// bmRequestType's direction and wLength follow from the data stage, the buffer
+/// passed to [`ControlTransfer::read`] or [`ControlTransfer::write`], so they
+/// can't disagree with it.
+#[must_use]
+pub struct ControlTransfer<'p, D: HostDriver, const NR_DEVICES: usize> {
+    pipe: &'p USBHostPipe<D, NR_DEVICES>,
+    device_handle: DeviceHandle,
+    request_type: request::RequestTypeType,
+    recipient: request::RequestTypeRecipient,
+    request: u8,
+    value: u16,
+    index: u16,
+    timeout: Option<Duration>,
+}
+
+impl<D: HostDriver, const NR_DEVICES: usize> ControlTransfer<'_, D, NR_DEVICES> {
+    pub fn standard(mut self) -> Self {
+        self.request_type = request::RequestTypeType::Standard;
+        self
+    }
+
+    pub fn class(mut self) -> Self {
+        self.request_type = request::RequestTypeType::Class;
+        self
+    }
+
+    pub fn vendor(mut self) -> Self {
+        self.request_type = request::RequestTypeType::Vendor;
+        self
+    }
+
+    /// Addressed to the device, wIndex 0
+    pub fn device(mut self) -> Self {
+        self.recipient = request::RequestTypeRecipient::Device;
+        self.index = 0;
+        self
+    }
+
+    /// Addressed to `interface`, wIndex is the interface number
+    pub fn interface(mut self, interface: u8) -> Self {
+        self.recipient = request::RequestTypeRecipient::Interface;
+        self.index = interface as u16;
+        self
+    }
+
+    /// Addressed to `endpoint`, wIndex is the endpoint address
+    pub fn endpoint(mut self, endpoint: EndpointAddress) -> Self {
+        self.recipient = request::RequestTypeRecipient::Endpoint;
+        self.index = u8::from(endpoint) as u16;
+        self
+    }
+
+    /// Addressed to "other", e.g. a hub port given with [`ControlTransfer::index`]
+    pub fn other(mut self) -> Self {
+        self.recipient = request::RequestTypeRecipient::Other;
+        self
+    }
+
+    /// bRequest and wValue
+    pub fn request(mut self, request: u8, value: u16) -> Self {
+        self.request = request;
+        self.value = value;
+        self
+    }
+
+    /// wIndex, for requests that put more than the recipient in it (e.g. the
+    /// entity ID of audio and video class requests in the high byte)
+    pub fn index(mut self, index: u16) -> Self {
+        self.index = index;
+        self
+    }
+
+    /// GET_DESCRIPTOR of `descriptor_type` and `descriptor_index`, the language
+    /// ID of string descriptors is set with [`ControlTransfer::index`]
+    pub fn get_descriptor(self, descriptor_type: u8, descriptor_index: u8) -> Self {
+        self.request(
+            StandardDeviceRequest::GetDescriptor as u8,
+            ((descriptor_type as u16) << 8) | descriptor_index as u16,
+        )
+    }
+
+    /// Stage timeout instead of the pipe's default timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    async fn execute(self, data: DataStage<'_>) -> Result<usize, UsbHostError> {
+        let length = u16::try_from(data.len()).map_err(|_| UsbHostError::BufferOverflow)?;
+        let direction = match data {
+            DataStage::In(_) => request::RequestTypeDirection::DeviceToHost,
+            DataStage::Out(_) => request::RequestTypeDirection::HostToDevice,
+        };
+        let mut request_type = request::RequestType::default();
+        request_type.set_data_direction(direction);
+        request_type.set_type(self.request_type);
+        request_type.set_recipient(self.recipient);
+        let request = Request::new(request_type, self.request, self.value, self.index, length);
+        self.pipe
+            .control_transfer_inner(self.device_handle, &request, data, self.timeout)
+            .await
+    }
+
+    /// Reads up to `buf.len()` bytes in the data stage, returns the number of
+    /// bytes read. `buf` must not be empty, requests without data stage are
+    /// sent with [`ControlTransfer::no_data`].
+    pub async fn read(self, buf: &mut [u8]) -> Result<usize, UsbHostError> {
+        if buf.is_empty() {
+            return Err(UsbHostError::InvalidState);
+        }
+        self.execute(DataStage::In(buf)).await
+    }
+
+    /// Sends `buf` in the data stage
+    pub async fn write(self, buf: &[u8]) -> Result<(), UsbHostError> {
+        self.execute(DataStage::Out(buf)).await.map(|_| ())
+    }
+
+    /// Request without data stage
+    pub async fn no_data(self) -> Result<(), UsbHostError> {
+        self.write(&[]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MockDriver, MockPipe, Transaction};
+    use embassy_futures::block_on;
+    use std::vec::Vec;
+
+    fn host_pipe() -> (USBHostPipe<MockDriver, 4>, MockPipe) {
+        let mock = MockPipe::default();
+        (USBHostPipe::new(mock.clone()), mock)
+    }
+
+    async fn alloc_device(pipe: &USBHostPipe<MockDriver, 4>, max_packet_size: u16) -> DeviceHandle {
+        let mut devices = pipe.devices.lock().await;
+        devices
+            .address_alloc
+            .alloc_device_address(max_packet_size, DevInfo::root_device(UsbSpeed::FullSpeed))
+            .unwrap()
+    }
+
+    #[test]
+    fn control_out_data_stage_is_chunked_up_to_length() {
+        let (pipe, mock) = host_pipe();
+        block_on(async {
+            let device = alloc_device(&pipe, 8).await;
+            let mut request_type = request::RequestType::default();
+            request_type.set_data_direction(request::RequestTypeDirection::HostToDevice);
+            request_type.set_type(request::RequestTypeType::Vendor);
+            let request = Request::new(request_type, 0x01, 0, 0, 20);
+            // Longer than wLength, the bytes past it must not be sent
+            let mut buffer: Vec<u8> = (0..32).collect();
+
+            let received = pipe.control_transfer(device, &request, &mut buffer).await;
+            assert!(matches!(received, Ok(0)));
+
+            let log = mock.take_log();
+            let addr = device.address();
+            assert!(matches!(log[0], Transaction::Setup { addr: a, .. } if a == addr));
+            let out = |data1, range: core::ops::Range<u8>| Transaction::Out {
+                addr,
+                endpoint: 0,
+                data1,
+                data: range.collect(),
+            };
+            assert_eq!(
+                log[1..],
+                [
+                    out(true, 0..8),
+                    out(false, 8..16),
+                    out(true, 16..20),
+                    Transaction::In {
+                        addr,
+                        endpoint: 0,
+                        data1: true
+                    },
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn device_descriptor_of_8_byte_ep0() {
+        let (pipe, mock) = host_pipe();
+        #[rustfmt::skip]
+        let descriptor = [
+            18, 1, 0x00, 0x02, 0, 0, 0, 8, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 1,
+        ];
+        mock.push_in(Ok(&descriptor[..8]));
+        for packet in descriptor.chunks(8) {
+            mock.push_in(Ok(packet));
+        }
+
+        let dev_info = DevInfo::root_device(UsbSpeed::LowSpeed);
+        let mut buf = [0; 64];
+        let desc = block_on(pipe.get_device_descriptor(&dev_info, &mut buf)).unwrap();
+        assert_eq!(desc.max_packet_size, 8);
+        assert_eq!({ desc.id_vendor }, 0x1234);
+        assert_eq!({ desc.id_product }, 0x5678);
+
+        let setup = |length: u8| Transaction::Setup {
+            addr: 0,
+            data: [0x80, 6, 0, 1, 0, 0, length, 0],
+        };
+        let data_in = |data1| Transaction::In {
+            addr: 0,
+            endpoint: 0,
+            data1,
+        };
+        let status = Transaction::Out {
+            addr: 0,
+            endpoint: 0,
+            data1: true,
+            data: Vec::new(),
+        };
+        assert_eq!(
+            mock.take_log(),
+            [
+                // wLength 8 first, a single packet
+                setup(8),
+                data_in(true),
+                status.clone(),
+                // Then the whole descriptor in packets of 8 bytes
+                setup(18),
+                data_in(true),
+                data_in(false),
+                data_in(true),
+                status,
+            ]
+        );
+    }
+
+    #[test]
+    fn interrupt_out_advances_toggle_on_ack() {
+        let (pipe, mock) = host_pipe();
+        block_on(async {
+            let device = alloc_device(&pipe, 8).await;
+            let endpoint = EndpointAddress {
+                number: 2,
+                direction: types::EndpointDirection::Out,
+            };
+            let mut channel = InterruptChannel::new(device, endpoint);
+            // LED report, Num Lock
+            let mut report = [0x01];
+
+            mock.push_out(Ok(()));
+            mock.push_out(Err(UsbHostError::NAK));
+            mock.push_out(Ok(()));
+            let first = pipe.interrupt_transfer(&mut channel, &mut report).await;
+            assert!(matches!(first, Ok(0)));
+            assert!(channel.tog() == DataTog::DATA1);
+            // A NAKed report keeps its toggle for the next attempt
+            let nak = pipe.interrupt_transfer(&mut channel, &mut report).await;
+            assert!(matches!(nak, Err(UsbHostError::NAK)));
+            assert!(channel.tog() == DataTog::DATA1);
+            let retry = pipe.interrupt_transfer(&mut channel, &mut report).await;
+            assert!(matches!(retry, Ok(0)));
+            assert!(channel.tog() == DataTog::DATA0);
+
+            let report = |data1| Transaction::Out {
+                addr: device.address(),
+                endpoint: 2,
+                data1,
+                data: vec![0x01],
+            };
+            assert_eq!(mock.take_log(), [report(false), report(true), report(true)]);
+        });
+    }
+
+    #[test]
+    fn composed_device_is_configured_once() {
+        let (pipe, mock) = host_pipe();
+        block_on(async {
+            let device = alloc_device(&pipe, 8).await;
+            // Configuration 1, 100mA
+            mock.push_in(Ok(&[9, 2, 32, 0, 1, 1, 0, 0x80, 50]));
+            assert!(matches!(pipe.begin_composed(device).await, Ok(true)));
+            assert!(matches!(pipe.begin_composed(device).await, Ok(false)));
+            let set_configuration = Transaction::Setup {
+                addr: device.address(),
+                data: [0x00, 9, 1, 0, 0, 0, 0, 0],
+            };
+            let log = mock.take_log();
+            assert_eq!(log.iter().filter(|t| **t == set_configuration).count(), 1);
+
+            // The drivers' SET_CONFIGURATION is skipped while composed
+            assert!(pipe.set_configuration(device, 1).await.is_ok());
+            assert!(mock.take_log().is_empty());
+            pipe.end_composed(device).await;
+            assert!(pipe.set_configuration(device, 1).await.is_ok());
+            assert_eq!(mock.take_log()[0], set_configuration);
+        });
+    }
+
+    #[test]
+    fn bulk_out_resumes_after_clearing_stall() {
+        let (pipe, mock) = host_pipe();
+        block_on(async {
+            let device = alloc_device(&pipe, 8).await;
+            let endpoint = EndpointAddress {
+                number: 2,
+                direction: types::EndpointDirection::Out,
+            };
+            let mut channel = BulkChannel::new(device, endpoint, 8).unwrap();
+            let mut buffer: Vec<u8> = (0..20).collect();
+
+            // The second packet is STALLed
+            mock.push_out(Ok(()));
+            mock.push_out(Err(UsbHostError::STALL));
+            let sent = pipe.bulk_transfer(&mut channel, &mut buffer).await;
+            assert!(matches!(sent, Ok(20)));
+
+            let addr = device.address();
+            let out = |data1, range: core::ops::Range<u8>| Transaction::Out {
+                addr,
+                endpoint: 2,
+                data1,
+                data: range.collect(),
+            };
+            assert_eq!(
+                mock.take_log(),
+                [
+                    out(false, 0..8),
+                    out(true, 8..16),
+                    // CLEAR_FEATURE(ENDPOINT_HALT) of endpoint 2 OUT
+                    Transaction::Setup {
+                        addr,
+                        data: [0x02, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+                    },
+                    Transaction::In {
+                        addr,
+                        endpoint: 0,
+                        data1: true
+                    },
+                    // Resumed at the STALLed packet, starting over at DATA0
+                    out(false, 8..16),
+                    out(true, 16..20),
+                ]
+            );
+        });
     }
 }
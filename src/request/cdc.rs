@@ -0,0 +1,46 @@
+use super::{Request, RequestType, RequestTypeDirection, RequestTypeRecipient, RequestTypeType};
+
+/// CDC PSTN subclass requests used by the abstract control model
+#[repr(u8)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CdcRequest {
+    SendEncapsulatedCommand = 0x00,
+    GetEncapsulatedResponse = 0x01,
+    SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
+    SetControlLineState = 0x22,
+    SendBreak = 0x23,
+}
+
+impl Request {
+    fn cdc_class_out(request: CdcRequest, value: u16, interface: u8, length: u16) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(RequestTypeDirection::HostToDevice);
+                t.set_type(RequestTypeType::Class);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: request as u8,
+            value,
+            index: interface as u16,
+            length,
+        }
+    }
+
+    /// SET_LINE_CODING, followed by the 7 byte line coding structure
+    pub fn cdc_set_line_coding(interface: u8) -> Request {
+        Self::cdc_class_out(CdcRequest::SetLineCoding, 0, interface, 7)
+    }
+
+    pub fn cdc_set_control_line_state(interface: u8, dtr: bool, rts: bool) -> Request {
+        Self::cdc_class_out(
+            CdcRequest::SetControlLineState,
+            (dtr as u16) | ((rts as u16) << 1),
+            interface,
+            0,
+        )
+    }
+}
@@ -0,0 +1,94 @@
+use usb_dfu_target::consts::DfuRequest;
+
+use super::{Request, RequestType, RequestTypeDirection, RequestTypeRecipient, RequestTypeType};
+
+impl Request {
+    fn dfu_class(
+        request: DfuRequest,
+        direction: RequestTypeDirection,
+        value: u16,
+        interface: u8,
+        length: u16,
+    ) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(direction);
+                t.set_type(RequestTypeType::Class);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: request as u8,
+            value,
+            index: interface as u16,
+            length,
+        }
+    }
+
+    /// DFU_DNLOAD of block `block_num`, the `length` byte block follows in the data stage
+    pub fn dfu_dnload(interface: u8, block_num: u16, length: u16) -> Request {
+        Self::dfu_class(
+            DfuRequest::Dnload,
+            RequestTypeDirection::HostToDevice,
+            block_num,
+            interface,
+            length,
+        )
+    }
+
+    /// DFU_UPLOAD of block `block_num`, the device returns up to `length` bytes,
+    /// a shorter block ends the upload
+    pub fn dfu_upload(interface: u8, block_num: u16, length: u16) -> Request {
+        Self::dfu_class(
+            DfuRequest::Upload,
+            RequestTypeDirection::DeviceToHost,
+            block_num,
+            interface,
+            length,
+        )
+    }
+
+    /// DFU_GETSTATE, the device returns its one byte state
+    pub fn dfu_get_state(interface: u8) -> Request {
+        Self::dfu_class(
+            DfuRequest::GetState,
+            RequestTypeDirection::DeviceToHost,
+            0,
+            interface,
+            1,
+        )
+    }
+
+    /// DFU_GETSTATUS, the device returns the 6 byte status
+    pub fn dfu_get_status(interface: u8) -> Request {
+        Self::dfu_class(
+            DfuRequest::GetStatus,
+            RequestTypeDirection::DeviceToHost,
+            0,
+            interface,
+            6,
+        )
+    }
+
+    /// DFU_CLRSTATUS, leaves dfuERROR
+    pub fn dfu_clear_status(interface: u8) -> Request {
+        Self::dfu_class(
+            DfuRequest::ClrStatus,
+            RequestTypeDirection::HostToDevice,
+            0,
+            interface,
+            0,
+        )
+    }
+
+    /// DFU_ABORT, returns the device to dfuIDLE
+    pub fn dfu_abort(interface: u8) -> Request {
+        Self::dfu_class(
+            DfuRequest::Abort,
+            RequestTypeDirection::HostToDevice,
+            0,
+            interface,
+            0,
+        )
+    }
+}
@@ -0,0 +1,103 @@
+use crate::descriptor::hid::HID_REPORT_DESCRIPTOR_TYPE;
+
+use super::{
+    Request, RequestType, RequestTypeDirection, RequestTypeRecipient, RequestTypeType,
+    StandardDeviceRequest,
+};
+
+/// HID class specific requests
+#[repr(u8)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HidRequest {
+    GetReport = 0x01,
+    GetIdle = 0x02,
+    GetProtocol = 0x03,
+    SetReport = 0x09,
+    SetIdle = 0x0A,
+    SetProtocol = 0x0B,
+}
+
+/// Protocol selected with SET_PROTOCOL on boot interfaces
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum HidProtocol {
+    Boot = 0,
+    Report = 1,
+}
+
+/// Report type carried in the high byte of GET_REPORT/SET_REPORT `wValue`
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub enum HidReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+impl Request {
+    fn hid_class_out(request: HidRequest, value: u16, interface: u8, length: u16) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(RequestTypeDirection::HostToDevice);
+                t.set_type(RequestTypeType::Class);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: request as u8,
+            value,
+            index: interface as u16,
+            length,
+        }
+    }
+
+    /// GET_DESCRIPTOR of the report descriptor of `interface`
+    pub fn hid_get_report_descriptor(interface: u8, length: u16) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(RequestTypeDirection::DeviceToHost);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: StandardDeviceRequest::GetDescriptor as u8,
+            value: (HID_REPORT_DESCRIPTOR_TYPE as u16) << 8,
+            index: interface as u16,
+            length,
+        }
+    }
+
+    /// SET_IDLE, `duration` in 4ms units, 0 reports only on change
+    pub fn hid_set_idle(interface: u8, duration: u8, report_id: u8) -> Request {
+        Self::hid_class_out(
+            HidRequest::SetIdle,
+            ((duration as u16) << 8) | report_id as u16,
+            interface,
+            0,
+        )
+    }
+
+    pub fn hid_set_protocol(interface: u8, protocol: HidProtocol) -> Request {
+        Self::hid_class_out(HidRequest::SetProtocol, protocol as u16, interface, 0)
+    }
+
+    /// SET_REPORT, the `length` byte report follows in the data stage
+    pub fn hid_set_report(
+        interface: u8,
+        report_type: HidReportType,
+        report_id: u8,
+        length: u16,
+    ) -> Request {
+        Self::hid_class_out(
+            HidRequest::SetReport,
+            ((report_type as u16) << 8) | report_id as u16,
+            interface,
+            length,
+        )
+    }
+}
@@ -1,6 +1,11 @@
-use crate::descriptor::DescriptorType;
+use crate::descriptor::{DescriptorType, DeviceQualifier};
 
+pub mod cdc;
+pub mod dfu;
+pub mod hid;
 pub mod hub;
+pub mod msc;
+pub mod uvc;
 
 // Note, this does not have packed, becuase it happens to be aligned.
 // so there is the static assert there.
@@ -18,6 +23,39 @@ pub struct Request {
 static_assertions::const_assert_eq!(core::mem::size_of::<Request>(), 8);
 
 impl Request {
+    /// Arbitrary control request, for requests without a typed constructor
+    pub fn new(
+        request_type: RequestType,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Request {
+        Request {
+            request_type,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    /// Vendor specific control request
+    pub fn vendor(
+        direction: RequestTypeDirection,
+        recipient: RequestTypeRecipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Request {
+        let mut request_type = RequestType::default();
+        request_type.set_data_direction(direction);
+        request_type.set_type(RequestTypeType::Vendor);
+        request_type.set_recipient(recipient);
+        Request::new(request_type, request, value, index, length)
+    }
+
     pub fn set_configuration(configuration: u8) -> Request {
         Request {
             request_type: {
@@ -33,6 +71,51 @@ impl Request {
         }
     }
 
+    pub fn get_configuration() -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(RequestTypeDirection::DeviceToHost);
+                t.set_recipient(RequestTypeRecipient::Device);
+                t
+            },
+            request: StandardDeviceRequest::GetConfiguration as u8,
+            value: 0,
+            index: 0,
+            length: 1,
+        }
+    }
+
+    pub fn set_interface(interface: u8, alternate_setting: u8) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(RequestTypeDirection::HostToDevice);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: StandardDeviceRequest::SetInterface as u8,
+            value: alternate_setting as u16,
+            index: interface as u16,
+            length: 0,
+        }
+    }
+
+    pub fn get_interface(interface: u8) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(RequestTypeDirection::DeviceToHost);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: StandardDeviceRequest::GetInterface as u8,
+            value: 0,
+            index: interface as u16,
+            length: 1,
+        }
+    }
+
     pub fn get_descriptor(
         descriptor_type: u8,
         request_type_type: RequestTypeType,
@@ -104,6 +187,16 @@ impl Request {
         }
     }
 
+    pub fn get_string_descriptor(index: u8, language_id: u16, length: u16) -> Request {
+        Self::get_descriptor(
+            DescriptorType::String as u8,
+            RequestTypeType::Standard,
+            index,
+            language_id,
+            length,
+        )
+    }
+
     pub fn get_configuration_descriptor(index: u8, length: u16) -> Request {
         Self::get_descriptor(
             DescriptorType::Configuration as u8,
@@ -113,6 +206,49 @@ impl Request {
             length,
         )
     }
+
+    /// GET_DESCRIPTOR(DEVICE_QUALIFIER), STALLed by devices that are not high-speed capable
+    pub fn get_device_qualifier() -> Request {
+        Self::get_descriptor(
+            DescriptorType::DeviceQualifier as u8,
+            RequestTypeType::Standard,
+            0,
+            0,
+            core::mem::size_of::<DeviceQualifier>() as u16,
+        )
+    }
+
+    /// GET_DESCRIPTOR(BOS), STALLed by devices before USB 2.1
+    pub fn get_bos(length: u16) -> Request {
+        Self::get_descriptor(
+            DescriptorType::Bos as u8,
+            RequestTypeType::Standard,
+            0,
+            0,
+            length,
+        )
+    }
+
+    /// SET_DESCRIPTOR, the `length` descriptor bytes follow in the data stage
+    pub fn set_descriptor(
+        descriptor_type: u8,
+        index: u8,
+        language_id: u16,
+        length: u16,
+    ) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(RequestTypeDirection::HostToDevice);
+                t.set_recipient(RequestTypeRecipient::Device);
+                t
+            },
+            request: StandardDeviceRequest::SetDescriptor as u8,
+            value: ((descriptor_type as u16) << 8) | (index as u16),
+            index: language_id,
+            length,
+        }
+    }
 }
 
 #[repr(u8)]
@@ -210,4 +346,6 @@ pub enum StandardDeviceRequest {
     SetDescriptor = 0x7,
     GetConfiguration = 0x8,
     SetConfiguration = 0x9,
+    GetInterface = 0xA,
+    SetInterface = 0xB,
 }
@@ -0,0 +1,53 @@
+use super::{Request, RequestType, RequestTypeDirection, RequestTypeRecipient, RequestTypeType};
+
+/// Bulk-Only Transport class specific requests
+#[repr(u8)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MscRequest {
+    GetMaxLun = 0xFE,
+    BulkOnlyMassStorageReset = 0xFF,
+}
+
+impl Request {
+    fn msc_class(
+        request: MscRequest,
+        direction: RequestTypeDirection,
+        interface: u8,
+        length: u16,
+    ) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(direction);
+                t.set_type(RequestTypeType::Class);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: request as u8,
+            value: 0,
+            index: interface as u16,
+            length,
+        }
+    }
+
+    /// GET_MAX_LUN, the device returns a single byte
+    pub fn msc_get_max_lun(interface: u8) -> Request {
+        Self::msc_class(
+            MscRequest::GetMaxLun,
+            RequestTypeDirection::DeviceToHost,
+            interface,
+            1,
+        )
+    }
+
+    /// Bulk-Only Mass Storage Reset, first step of the reset recovery
+    pub fn msc_bulk_only_reset(interface: u8) -> Request {
+        Self::msc_class(
+            MscRequest::BulkOnlyMassStorageReset,
+            RequestTypeDirection::HostToDevice,
+            interface,
+            0,
+        )
+    }
+}
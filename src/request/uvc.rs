@@ -0,0 +1,54 @@
+use super::{Request, RequestType, RequestTypeDirection, RequestTypeRecipient, RequestTypeType};
+
+/// UVC class specific requests
+#[repr(u8)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UvcRequest {
+    SetCur = 0x01,
+    GetCur = 0x81,
+    GetMin = 0x82,
+    GetMax = 0x83,
+    GetRes = 0x84,
+    GetLen = 0x85,
+    GetInfo = 0x86,
+    GetDef = 0x87,
+}
+
+/// Control selectors of the video streaming interface
+#[repr(u8)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UvcStreamingControl {
+    Probe = 0x01,
+    Commit = 0x02,
+}
+
+impl Request {
+    /// UVC request on `control` of the video streaming `interface`, the
+    /// direction follows from the request
+    pub fn uvc_streaming(
+        request: UvcRequest,
+        control: UvcStreamingControl,
+        interface: u8,
+        length: u16,
+    ) -> Request {
+        Request {
+            request_type: {
+                let mut t = RequestType::default();
+                t.set_data_direction(if request as u8 & 0x80 != 0 {
+                    RequestTypeDirection::DeviceToHost
+                } else {
+                    RequestTypeDirection::HostToDevice
+                });
+                t.set_type(RequestTypeType::Class);
+                t.set_recipient(RequestTypeRecipient::Interface);
+                t
+            },
+            request: request as u8,
+            value: (control as u16) << 8,
+            index: interface as u16,
+            length,
+        }
+    }
+}
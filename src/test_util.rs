@@ -0,0 +1,176 @@
+//! Mock controller for the unit tests: a pipe answering from a script and
+//! recording every transaction, and a std time driver.
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc, sync::OnceLock, vec::Vec};
+
+use crate::{
+    errors::UsbHostError,
+    types::{DataTog, EndpointType, UsbSpeed},
+    Bus, Event, HostDriver, Pipe,
+};
+
+struct StdTimeDriver;
+
+impl embassy_time_driver::Driver for StdTimeDriver {
+    fn now(&self) -> u64 {
+        static START: OnceLock<std::time::Instant> = OnceLock::new();
+        let elapsed = START.get_or_init(std::time::Instant::now).elapsed();
+        (elapsed.as_micros() * embassy_time_driver::TICK_HZ as u128 / 1_000_000) as u64
+    }
+
+    fn schedule_wake(&self, _at: u64, waker: &core::task::Waker) {
+        // `block_on` polls in a loop anyway, the timer checks the time itself
+        waker.wake_by_ref();
+    }
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: StdTimeDriver = StdTimeDriver);
+
+/// A transaction seen by [`MockPipe`], toggles as `data1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transaction {
+    Setup {
+        addr: u8,
+        data: [u8; 8],
+    },
+    In {
+        addr: u8,
+        endpoint: u8,
+        data1: bool,
+    },
+    Out {
+        addr: u8,
+        endpoint: u8,
+        data1: bool,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Default)]
+struct MockState {
+    addr: u8,
+    log: Vec<Transaction>,
+    /// Answers to the IN transactions, in order. IN transactions past the end
+    /// of the script get a zero length packet.
+    in_script: VecDeque<Result<Vec<u8>, UsbHostError>>,
+    /// Handshakes of the OUT transactions, ACK past the end of the script
+    out_script: VecDeque<Result<(), UsbHostError>>,
+}
+
+/// Pipe of [`MockDriver`], clones share the script and the log
+#[derive(Clone, Default)]
+pub struct MockPipe(Rc<RefCell<MockState>>);
+
+impl MockPipe {
+    /// Queues the answer to the next unanswered IN transaction
+    pub fn push_in(&self, response: Result<&[u8], UsbHostError>) {
+        self.0
+            .borrow_mut()
+            .in_script
+            .push_back(response.map(<[u8]>::to_vec));
+    }
+
+    /// Queues the handshake of the next unanswered OUT transaction
+    pub fn push_out(&self, response: Result<(), UsbHostError>) {
+        self.0.borrow_mut().out_script.push_back(response);
+    }
+
+    /// Transactions so far, clearing the log
+    pub fn take_log(&self) -> Vec<Transaction> {
+        core::mem::take(&mut self.0.borrow_mut().log)
+    }
+}
+
+impl Pipe for MockPipe {
+    fn set_addr(&mut self, addr: u8) {
+        self.0.borrow_mut().addr = addr;
+    }
+
+    async fn setup(&mut self, buf: Option<&[u8; 8]>) -> Result<(), UsbHostError> {
+        let mut state = self.0.borrow_mut();
+        let addr = state.addr;
+        state.log.push(Transaction::Setup {
+            addr,
+            data: buf.copied().unwrap_or_default(),
+        });
+        Ok(())
+    }
+
+    async fn split(
+        &mut self,
+        _complete: bool,
+        _port: u8,
+        _ep_type: EndpointType,
+        _speed: UsbSpeed,
+    ) -> Result<(), UsbHostError> {
+        Ok(())
+    }
+
+    async fn data_in(
+        &mut self,
+        endpoint: u8,
+        tog: DataTog,
+        _wait_for_reply: bool,
+        _send_ack: bool,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        let mut state = self.0.borrow_mut();
+        let addr = state.addr;
+        state.log.push(Transaction::In {
+            addr,
+            endpoint,
+            data1: tog == DataTog::DATA1,
+        });
+        let data = state.in_script.pop_front().unwrap_or(Ok(Vec::new()))?;
+        assert!(
+            data.len() <= buf.len(),
+            "scripted packet overflows the buffer"
+        );
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    async fn data_out(
+        &mut self,
+        endpoint: u8,
+        tog: DataTog,
+        _wait_for_reply: bool,
+        buf: Option<&[u8]>,
+    ) -> Result<(), UsbHostError> {
+        let mut state = self.0.borrow_mut();
+        let addr = state.addr;
+        state.log.push(Transaction::Out {
+            addr,
+            endpoint,
+            data1: tog == DataTog::DATA1,
+            data: buf.unwrap_or_default().to_vec(),
+        });
+        state.out_script.pop_front().unwrap_or(Ok(()))
+    }
+}
+
+/// Root port with a device of the given speed attached
+pub struct MockBus(pub Option<UsbSpeed>);
+
+impl Bus for MockBus {
+    async fn reset(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        core::future::pending().await
+    }
+
+    async fn speed(&mut self) -> Option<UsbSpeed> {
+        self.0
+    }
+}
+
+pub struct MockDriver;
+
+impl HostDriver for MockDriver {
+    type Bus = MockBus;
+    type Pipe = MockPipe;
+
+    fn start(self) -> (MockBus, MockPipe) {
+        (MockBus(Some(UsbSpeed::FullSpeed)), MockPipe::default())
+    }
+}
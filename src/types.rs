@@ -1,4 +1,12 @@
-use crate::{descriptor::EndpointDescriptor, DeviceHandle};
+use arrayvec::ArrayVec;
+use embassy_time::Duration;
+
+use crate::{
+    descriptor::{EndpointDescriptor, InterfaceDescriptor},
+    errors::UsbHostError,
+    pipe::USBHostPipe,
+    DeviceHandle, HostDriver,
+};
 
 /// Represents a 16-bit binary-coded-decimal value
 ///
@@ -105,7 +113,7 @@ impl TryFrom<u8> for Pid {
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum UsbSpeed {
     LowSpeed,
     FullSpeed,
@@ -136,16 +144,128 @@ pub struct EndpointAddress {
 
 impl From<&EndpointDescriptor> for EndpointAddress {
     fn from(value: &EndpointDescriptor) -> Self {
-        let number = value.b_endpoint_address & 0xF;
-        let direction = if value.b_endpoint_address & 0x80 == 0 {
-            EndpointDirection::Out
-        } else {
-            EndpointDirection::In
-        };
-        EndpointAddress { number, direction }
+        value.address()
+    }
+}
+
+/// bEndpointAddress, as used in wIndex of endpoint requests
+impl From<EndpointAddress> for u8 {
+    fn from(value: EndpointAddress) -> Self {
+        match value.direction {
+            EndpointDirection::In => value.number | 0x80,
+            EndpointDirection::Out => value.number,
+        }
+    }
+}
+
+/// Response of GET_STATUS for the device
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct DeviceStatus {
+    pub self_powered: bool,
+    pub remote_wakeup_enabled: bool,
+}
+
+impl From<u16> for DeviceStatus {
+    fn from(value: u16) -> Self {
+        DeviceStatus {
+            self_powered: value & 0x1 != 0,
+            remote_wakeup_enabled: value & 0x2 != 0,
+        }
+    }
+}
+
+/// Response of GET_STATUS for an endpoint
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct EndpointStatus {
+    pub halted: bool,
+}
+
+impl From<u16> for EndpointStatus {
+    fn from(value: u16) -> Self {
+        EndpointStatus {
+            halted: value & 0x1 != 0,
+        }
+    }
+}
+
+/// Interfaces kept in [`DeviceInterfaces`], further interfaces are dropped
+pub const MAX_DEVICE_INTERFACES: usize = 8;
+
+/// Class, subclass and protocol of an interface, enough to pick a driver for it
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct InterfaceClass {
+    /// bInterfaceNumber
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+impl From<&InterfaceDescriptor> for InterfaceClass {
+    fn from(desc: &InterfaceDescriptor) -> Self {
+        InterfaceClass {
+            number: desc.b_interface_number,
+            class: desc.b_interface_class,
+            subclass: desc.b_interface_sub_class,
+            protocol: desc.b_interface_protocol,
+        }
+    }
+}
+
+/// Interfaces of a device's first configuration, alternate setting 0 only,
+/// see [`USBHostPipe::device_interfaces`]
+#[derive(Clone, Default)]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct DeviceInterfaces {
+    interfaces: ArrayVec<InterfaceClass, MAX_DEVICE_INTERFACES>,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DeviceInterfaces {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", self.interfaces.as_slice());
     }
 }
 
+impl DeviceInterfaces {
+    pub(crate) fn push(&mut self, interface: InterfaceClass) {
+        if self.interfaces.try_push(interface).is_err() {
+            warn!(
+                "more than {} interfaces, interface {} dropped",
+                MAX_DEVICE_INTERFACES, interface.number
+            );
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InterfaceClass> + '_ {
+        self.interfaces.iter()
+    }
+
+    /// First interface of `class`
+    pub fn find(&self, class: u8) -> Option<&InterfaceClass> {
+        self.interfaces
+            .iter()
+            .find(|interface| interface.class == class)
+    }
+
+    pub fn len(&self) -> usize {
+        self.interfaces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interfaces.is_empty()
+    }
+}
+
+/// Polling interval of interrupt channels created without an endpoint descriptor
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 #[derive(Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
@@ -153,17 +273,274 @@ pub struct InterruptChannel {
     pub(crate) device_handle: DeviceHandle,
     pub(crate) endpoint_address: EndpointAddress,
     pub(crate) tog: DataTog,
+    /// Polling interval in µs, from bInterval. Not a `Duration`, which
+    /// doesn't implement `defmt::Format`
+    interval_us: u64,
 }
 
 impl InterruptChannel {
+    /// Channel polled every [`DEFAULT_POLL_INTERVAL`], use
+    /// [`InterruptChannel::from_descriptor`] to poll at the endpoint's bInterval
     pub fn new(device_handle: DeviceHandle, endpoint_address: EndpointAddress) -> Self {
         Self {
             device_handle,
             endpoint_address,
             tog: DataTog::DATA0,
+            interval_us: DEFAULT_POLL_INTERVAL.as_micros(),
+        }
+    }
+
+    /// Endpoint described by `desc`, `None` if it is not an interrupt endpoint
+    pub fn from_descriptor(device_handle: DeviceHandle, desc: &EndpointDescriptor) -> Option<Self> {
+        (desc.transfer_type() == EndpointType::Interrupt).then(|| Self {
+            interval_us: desc.interval(device_handle.speed()).as_micros(),
+            ..Self::new(device_handle, desc.address())
+        })
+    }
+
+    /// Time between two polls of the endpoint
+    pub fn interval(&self) -> Duration {
+        Duration::from_micros(self.interval_us)
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval_us = interval.as_micros();
+    }
+
+    /// Restarts the data toggle at DATA0, e.g. after the endpoint halt is cleared
+    pub fn reset_tog(&mut self) {
+        self.tog = DataTog::DATA0;
+    }
+
+    /// Data toggle of the next transaction
+    pub fn tog(&self) -> DataTog {
+        self.tog
+    }
+
+    pub fn set_tog(&mut self, tog: DataTog) {
+        self.tog = tog;
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct BulkChannel {
+    pub(crate) device_handle: DeviceHandle,
+    pub(crate) endpoint_address: EndpointAddress,
+    /// wMaxPacketSize of the bulk endpoint, used to split transfers into packets
+    pub(crate) max_packet_size: u16,
+    pub(crate) tog: DataTog,
+}
+
+impl BulkChannel {
+    /// Fails with [`UsbHostError::InvalidResponse`] for a `max_packet_size` of
+    /// 0, transfers are split into packets of that size
+    pub fn new(
+        device_handle: DeviceHandle,
+        endpoint_address: EndpointAddress,
+        max_packet_size: u16,
+    ) -> Result<Self, UsbHostError> {
+        if max_packet_size == 0 {
+            return Err(UsbHostError::InvalidResponse);
+        }
+        Ok(Self {
+            device_handle,
+            endpoint_address,
+            max_packet_size,
+            tog: DataTog::DATA0,
+        })
+    }
+
+    /// Endpoint described by `desc`, `None` if it is not a bulk endpoint, see
+    /// [`BulkChannel::new`] for the errors
+    pub fn from_descriptor(
+        device_handle: DeviceHandle,
+        desc: &EndpointDescriptor,
+    ) -> Result<Option<Self>, UsbHostError> {
+        if desc.transfer_type() != EndpointType::Bulk {
+            return Ok(None);
+        }
+        Self::new(device_handle, desc.address(), desc.max_packet_size()).map(Some)
+    }
+
+    /// Direction and number of the endpoint
+    pub fn endpoint_address(&self) -> EndpointAddress {
+        self.endpoint_address
+    }
+
+    /// Restarts the data toggle at DATA0, e.g. after the endpoint halt is cleared
+    pub fn reset_tog(&mut self) {
+        self.tog = DataTog::DATA0;
+    }
+
+    /// Data toggle of the next transaction
+    pub fn tog(&self) -> DataTog {
+        self.tog
+    }
+
+    pub fn set_tog(&mut self, tog: DataTog) {
+        self.tog = tog;
+    }
+}
+
+/// Isochronous endpoint, see [`USBHostPipe::iso_in`](crate::pipe::USBHostPipe::iso_in)
+#[derive(Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct IsoChannel {
+    pub(crate) device_handle: DeviceHandle,
+    pub(crate) endpoint_address: EndpointAddress,
+    /// Bytes of a single transaction
+    pub(crate) max_packet_size: u16,
+    /// bInterval of the endpoint
+    pub(crate) interval: u8,
+}
+
+impl IsoChannel {
+    pub fn new(
+        device_handle: DeviceHandle,
+        endpoint_address: EndpointAddress,
+        max_packet_size: u16,
+        interval: u8,
+    ) -> Self {
+        Self {
+            device_handle,
+            endpoint_address,
+            max_packet_size,
+            interval,
+        }
+    }
+
+    /// Endpoint described by `desc`, `None` if it is not isochronous
+    pub fn from_descriptor(device_handle: DeviceHandle, desc: &EndpointDescriptor) -> Option<Self> {
+        (desc.transfer_type() == EndpointType::Isochronous).then(|| {
+            Self::new(
+                device_handle,
+                desc.address(),
+                desc.max_packet_size(),
+                desc.b_interval,
+            )
+        })
+    }
+
+    pub fn max_packet_size(&self) -> u16 {
+        self.max_packet_size
+    }
+
+    /// Service interval of the endpoint, 2^(bInterval-1) frames or microframes
+    pub fn period(&self) -> Duration {
+        let exponent = self.interval.clamp(1, 16) as u32 - 1;
+        let unit = match self.device_handle.speed() {
+            UsbSpeed::HighSpeed => 125,
+            UsbSpeed::FullSpeed | UsbSpeed::LowSpeed => 1000,
+        };
+        Duration::from_micros(unit << exponent)
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+enum EndpointChannel {
+    Interrupt(InterruptChannel),
+    Bulk(BulkChannel),
+}
+
+/// Interrupt or bulk endpoint of a device, keeping its data toggle across transfers
+///
+/// Drivers using several endpoints keep one `Endpoint` per endpoint and call
+/// [`Endpoint::transfer`], the transfer type and direction are taken from the
+/// endpoint.
+#[derive(Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(not(feature = "defmt"), derive(Debug))]
+pub struct Endpoint {
+    channel: EndpointChannel,
+    max_packet_size: u16,
+}
+
+impl Endpoint {
+    /// Returns `None` for control and isochronous endpoints, see
+    /// [`BulkChannel::new`] for the errors
+    pub fn new(
+        device_handle: DeviceHandle,
+        endpoint_address: EndpointAddress,
+        endpoint_type: EndpointType,
+        max_packet_size: u16,
+    ) -> Result<Option<Self>, UsbHostError> {
+        let channel = match endpoint_type {
+            EndpointType::Interrupt => {
+                EndpointChannel::Interrupt(InterruptChannel::new(device_handle, endpoint_address))
+            }
+            EndpointType::Bulk => EndpointChannel::Bulk(BulkChannel::new(
+                device_handle,
+                endpoint_address,
+                max_packet_size,
+            )?),
+            EndpointType::Control | EndpointType::Isochronous => return Ok(None),
+        };
+        Ok(Some(Self {
+            channel,
+            max_packet_size,
+        }))
+    }
+
+    /// Endpoint described by `desc`, `None` for control and isochronous endpoints
+    pub fn from_descriptor(
+        device_handle: DeviceHandle,
+        desc: &EndpointDescriptor,
+    ) -> Result<Option<Self>, UsbHostError> {
+        Self::new(
+            device_handle,
+            desc.address(),
+            desc.transfer_type(),
+            desc.max_packet_size(),
+        )
+    }
+
+    pub fn address(&self) -> EndpointAddress {
+        match &self.channel {
+            EndpointChannel::Interrupt(channel) => channel.endpoint_address,
+            EndpointChannel::Bulk(channel) => channel.endpoint_address,
+        }
+    }
+
+    pub fn endpoint_type(&self) -> EndpointType {
+        match self.channel {
+            EndpointChannel::Interrupt(_) => EndpointType::Interrupt,
+            EndpointChannel::Bulk(_) => EndpointType::Bulk,
+        }
+    }
+
+    pub fn max_packet_size(&self) -> u16 {
+        self.max_packet_size
+    }
+
+    /// Restarts the data toggle at DATA0, e.g. after the endpoint halt is cleared
+    pub fn reset_tog(&mut self) {
+        match &mut self.channel {
+            EndpointChannel::Interrupt(channel) => channel.reset_tog(),
+            EndpointChannel::Bulk(channel) => channel.reset_tog(),
+        }
+    }
+
+    /// Transfers `buf` in the endpoint's direction, see
+    /// [`USBHostPipe::interrupt_transfer`] and [`USBHostPipe::bulk_transfer`]
+    pub async fn transfer<D: HostDriver, const NR_DEVICES: usize>(
+        &mut self,
+        pipe: &USBHostPipe<D, NR_DEVICES>,
+        buf: &mut [u8],
+    ) -> Result<usize, UsbHostError> {
+        match &mut self.channel {
+            EndpointChannel::Interrupt(channel) => pipe.interrupt_transfer(channel, buf).await,
+            EndpointChannel::Bulk(channel) => pipe.bulk_transfer(channel, buf).await,
         }
     }
 }
+
+/// Data toggle of an interrupt or bulk endpoint. Control transfers ignore the
+/// channel toggles, their data stage always starts with DATA1.
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
@@ -173,6 +550,15 @@ pub enum DataTog {
 }
 
 impl DataTog {
+    /// `false` for DATA0, `true` for DATA1
+    pub fn set(&mut self, data1: bool) {
+        *self = if data1 {
+            DataTog::DATA1
+        } else {
+            DataTog::DATA0
+        };
+    }
+
     pub fn next(&mut self) {
         *self = match self {
             DataTog::DATA0 => DataTog::DATA1,
@@ -181,7 +567,7 @@ impl DataTog {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
 pub struct PortInfo {
@@ -198,6 +584,11 @@ impl PortInfo {
         }
     }
 
+    /// Port of the device on the root port
+    pub(crate) fn root() -> Self {
+        PortInfo::new(0x80, 0)
+    }
+
     // hub constructs PortInfo on detach
     pub(crate) fn new(valid_parent_addr: u8, port_on_parent: u8) -> Self {
         PortInfo {
@@ -223,14 +614,19 @@ impl PortInfo {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(not(feature = "defmt"), derive(Debug))]
 pub struct DevInfo {
     port: PortInfo,
     // tt addr, port
     // TODO: clean up
+    /// Address of the high-speed hub translating for this device and the hub
+    /// port the device is behind. Single TT hubs share one TT between their
+    /// ports, multi TT hubs select the port's TT from the port number.
     transaction_translator: Option<(u8, u8)>,
+    /// TT think time of the translating hub in full-speed bit times
+    tt_think_time: u8,
     speed: UsbSpeed,
 }
 
@@ -239,14 +635,16 @@ impl DevInfo {
         DevInfo {
             port: PortInfo::invalid(),
             transaction_translator: None,
+            tt_think_time: 0,
             speed: UsbSpeed::LowSpeed,
         }
     }
 
     pub fn root_device(speed: UsbSpeed) -> Self {
         DevInfo {
-            port: PortInfo::new(0x80, 0),
+            port: PortInfo::root(),
             transaction_translator: None,
+            tt_think_time: 0,
             speed,
         }
     }
@@ -255,12 +653,14 @@ impl DevInfo {
         addr: u8,
         port: u8,
         transaction_translator: Option<(u8, u8)>,
+        tt_think_time: u8,
         speed: UsbSpeed,
     ) -> Self {
         assert!((addr & 0x7F) != 0);
         DevInfo {
             port: PortInfo::new(0x80 | addr, port),
             transaction_translator,
+            tt_think_time,
             speed,
         }
     }
@@ -273,6 +673,12 @@ impl DevInfo {
         self.transaction_translator
     }
 
+    /// Think time of the transaction translator in full-speed bit times,
+    /// 0 without one
+    pub fn tt_think_time(&self) -> u8 {
+        self.tt_think_time
+    }
+
     pub fn speed(&self) -> UsbSpeed {
         self.speed
     }